@@ -0,0 +1,438 @@
+use crate::input_output::response::ResponseRule;
+use crate::input_output::tx_format::LineEnding;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Group for commands that don't name one
+pub const DEFAULT_GROUP: &str = "General";
+
+/// One thing a custom command does
+pub enum Step {
+    /// Send a line and read its response, then wait `delay` before the next step.
+    /// Without a `delay`, `step_delay_ms` from the config is used.
+    Send {
+        text: String,
+        delay: Option<Duration>,
+    },
+    /// Wait until a received line matches the regex, without sending anything
+    WaitFor { pattern: Regex, timeout: Duration },
+    /// Do nothing for a while
+    Sleep(Duration),
+    /// Run another custom command, with arguments that may use our own placeholders
+    Call { name: String, args: Vec<String> },
+    /// Run a Rhai script, with `args` in its `args` variable. Without any, the script gets
+    /// the arguments of the command.
+    Script { path: PathBuf, args: Vec<String> },
+}
+
+/// An argument of a custom command. Its value replaces `{name}` and `{N}` (1-based) in sent text.
+pub struct Arg {
+    pub name: String,
+    /// Used when the argument is not given. Arguments without a default are required.
+    pub default: Option<String>,
+}
+
+/// A custom command from `ExtraCommands.toml`
+pub struct CustomCommand {
+    /// Upper-cased name the user types in
+    pub name: String,
+    /// Upper-cased names that run the same command
+    pub aliases: Vec<String>,
+    pub description: Option<String>,
+    /// Commands are listed by group, then by name
+    pub group: String,
+    /// Arguments typed after the name, e.g. `SETADDR 7 fast`
+    pub args: Vec<Arg>,
+    /// What to do, in order
+    pub steps: Vec<Step>,
+    /// Overrides `tx_line_ending` from the config for this command only
+    pub line_ending: Option<LineEnding>,
+    /// Overrides parts of [write.response] from the config for this command only
+    pub response: Option<ResponseRule>,
+}
+
+/// All custom commands, looked up by name or alias
+#[derive(Default)]
+pub struct CustomCommands {
+    /// Keyed by upper-cased name, so iteration order is stable
+    commands: BTreeMap<String, CustomCommand>,
+    /// Upper-cased alias to upper-cased name
+    aliases: HashMap<String, String>,
+}
+
+impl CustomCommands {
+    /// Add a command, replacing any earlier one of the same name
+    pub fn insert(&mut self, custom_command: CustomCommand) {
+        for alias in &custom_command.aliases {
+            self.aliases
+                .insert(alias.clone(), custom_command.name.clone());
+        }
+        self.commands
+            .insert(custom_command.name.clone(), custom_command);
+    }
+
+    /// Look up by name or alias, case-insensitive
+    pub fn get(&self, name: &str) -> Option<&CustomCommand> {
+        let name = name.trim().to_uppercase();
+        match self.commands.get(&name) {
+            Some(custom_command) => Some(custom_command),
+            None => self
+                .aliases
+                .get(&name)
+                .and_then(|name| self.commands.get(name)),
+        }
+    }
+
+    /// Find the custom command for a typed line, and the arguments typed after its name.
+    /// Only if it takes that many arguments, so any other line is sent as it was typed.
+    pub fn find(&self, input: &str) -> Option<(&CustomCommand, Vec<String>)> {
+        self.resolve(input)
+            .filter(|(custom_command, args)| custom_command.accepts(args.len()))
+    }
+
+    /// Like `find`, for input that can only be a custom command, e.g. `--run`, so a wrong
+    /// number of arguments is reported by `bind_args()` instead.
+    /// A name containing spaces, e.g. from `command_array`, has to match the whole input.
+    pub fn resolve(&self, input: &str) -> Option<(&CustomCommand, Vec<String>)> {
+        if let Some(custom_command) = self.get(input) {
+            return Some((custom_command, Vec::<String>::new()));
+        }
+
+        let mut words = split_args(input).into_iter();
+        let name = words.next()?;
+        self.get(&name)
+            .map(|custom_command| (custom_command, words.collect()))
+    }
+
+    /// Commands sorted by group, then by name
+    pub fn sorted(&self) -> Vec<&CustomCommand> {
+        let mut sorted: Vec<&CustomCommand> = self.commands.values().collect();
+        sorted.sort_by(|a, b| a.group.cmp(&b.group).then_with(|| a.name.cmp(&b.name)));
+        sorted
+    }
+
+    /// Names and aliases, for completion
+    pub fn names(&self) -> Vec<&String> {
+        self.commands.keys().chain(self.aliases.keys()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Check that every `call` step names a known command, and that no command ends up
+    /// calling itself. Returns a description of the first problem found.
+    pub fn validate_calls(&self) -> Result<(), String> {
+        for custom_command in self.commands.values() {
+            for step in &custom_command.steps {
+                if let Step::Call { name, .. } = step {
+                    if self.get(name).is_none() {
+                        return Err(format!(
+                            "'{}' calls unknown command '{}'",
+                            custom_command.name, name
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut checked = HashSet::<String>::new();
+        for name in self.commands.keys() {
+            let mut path = vec![name.clone()];
+            self.find_cycle(&mut path, &mut checked)?;
+        }
+        Ok(())
+    }
+
+    /// Depth-first walk of `call` steps, with `path` holding the commands being called.
+    /// Commands in `checked` are known not to lead back to themselves, and are skipped.
+    fn find_cycle(
+        &self,
+        path: &mut Vec<String>,
+        checked: &mut HashSet<String>,
+    ) -> Result<(), String> {
+        let name = path.last().unwrap().clone();
+        if checked.contains(&name) {
+            return Ok(());
+        }
+        for step in &self.commands[&name].steps {
+            if let Step::Call { name, .. } = step {
+                let callee = self.get(name).unwrap().name.clone();
+                if path.contains(&callee) {
+                    path.push(callee);
+                    return Err(format!("Commands call each other: {}", path.join(" -> ")));
+                }
+                path.push(callee);
+                self.find_cycle(path, checked)?;
+                path.pop();
+            }
+        }
+        checked.insert(name);
+        Ok(())
+    }
+}
+
+impl CustomCommand {
+    /// e.g. `SETADDR <addr> [speed=slow]`
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.clone();
+        for arg in &self.args {
+            match &arg.default {
+                Some(default) => usage.push_str(&format!(" [{}={}]", arg.name, default)),
+                None => usage.push_str(&format!(" <{}>", arg.name)),
+            }
+        }
+        usage
+    }
+
+    /// Whether `count` arguments are enough, and not too many
+    pub fn accepts(&self, count: usize) -> bool {
+        let required = self.args.iter().filter(|arg| arg.default.is_none()).count();
+        count >= required && count <= self.args.len()
+    }
+
+    /// Match the typed arguments to our declared ones, filling in defaults
+    pub fn bind_args(&self, given: &[String]) -> Result<Vec<String>, String> {
+        if !self.accepts(given.len()) {
+            return Err(format!(
+                "'{}' takes {} argument(s), got {}. Usage: {}",
+                self.name,
+                self.args.len(),
+                given.len(),
+                self.usage()
+            ));
+        }
+
+        let mut values = Vec::<String>::new();
+        for (index, arg) in self.args.iter().enumerate() {
+            match given.get(index) {
+                Some(value) => values.push(value.clone()),
+                None => values.push(arg.default.clone().unwrap_or_default()),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Replace `{name}` and `{N}` placeholders in `text` with bound argument values.
+    /// Braces that don't name one of our arguments are left alone.
+    pub fn expand(&self, text: &str, values: &[String]) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+
+        while let Some(open) = rest.find('{') {
+            result.push_str(&rest[..open]);
+            let after_open = &rest[open + 1..];
+            let value = after_open
+                .find('}')
+                .and_then(|close| self.arg_index(&after_open[..close]).map(|i| (i, close)));
+            match value {
+                Some((index, close)) => {
+                    result.push_str(&values[index]);
+                    rest = &after_open[close + 1..];
+                }
+                None => {
+                    result.push('{');
+                    rest = after_open;
+                }
+            }
+        }
+
+        result.push_str(rest);
+        result
+    }
+
+    /// Index into `args` for a placeholder key, either a 1-based number or a name
+    fn arg_index(&self, key: &str) -> Option<usize> {
+        match key.parse::<usize>() {
+            Ok(number) if number >= 1 && number <= self.args.len() => Some(number - 1),
+            Ok(_) => None,
+            Err(_) => self.args.iter().position(|arg| arg.name == key),
+        }
+    }
+
+    /// Numbered placeholders past the last argument are almost certainly a mistake
+    pub fn validate_args(&self) -> Result<(), String> {
+        for step in &self.steps {
+            let texts: Vec<&String> = match step {
+                Step::Send { text, .. } => vec![text],
+                Step::Call { args, .. } | Step::Script { args, .. } => args.iter().collect(),
+                _ => continue,
+            };
+            for text in texts {
+                for key in text.split('{').skip(1).filter_map(|s| s.split('}').next()) {
+                    if let Ok(number) = key.parse::<usize>() {
+                        if number == 0 || number > self.args.len() {
+                            return Err(format!(
+                                "'{}' uses {{{}}} but only has {} argument(s)",
+                                self.name,
+                                number,
+                                self.args.len()
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything `HELP <name>` shows
+    pub fn help(&self) -> String {
+        let mut help = format!("Usage: {}", self.usage());
+        if let Some(description) = &self.description {
+            help.push_str(&format!("\n  {}", description));
+        }
+        help.push_str(&format!("\n  Group: {}", self.group));
+        if !self.aliases.is_empty() {
+            help.push_str(&format!("\n  Aliases: {}", self.aliases.join(", ")));
+        }
+        for (index, step) in self.steps.iter().enumerate() {
+            help.push_str(&format!("\n  {}. {}", index + 1, step));
+        }
+        help
+    }
+}
+
+#[cfg(test)]
+impl CustomCommand {
+    /// A command without arguments or overrides, in the default group
+    pub fn with_steps(name: &str, steps: Vec<Step>) -> Self {
+        CustomCommand {
+            name: name.to_string(),
+            aliases: Vec::new(),
+            description: None,
+            group: DEFAULT_GROUP.to_string(),
+            args: Vec::new(),
+            steps,
+            line_ending: None,
+            response: None,
+        }
+    }
+}
+
+/// Split on whitespace, keeping `"quoted words"` together
+pub fn split_args(input: &str) -> Vec<String> {
+    let mut args = Vec::<String>::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+
+    for c in input.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(current.clone());
+                    current.clear();
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+
+    args
+}
+
+impl Step {
+    /// Send `text` with the default delay afterwards
+    pub fn send(text: String) -> Step {
+        Step::Send { text, delay: None }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Send { text, delay: None } => write!(f, "'{}'", text),
+            Step::Send {
+                text,
+                delay: Some(delay),
+            } => write!(f, "'{}', then wait {} ms", text, delay.as_millis()),
+            Step::WaitFor { pattern, timeout } => write!(
+                f,
+                "wait for /{}/ (up to {} ms)",
+                pattern,
+                timeout.as_millis()
+            ),
+            Step::Sleep(duration) => write!(f, "sleep {} ms", duration.as_millis()),
+            Step::Call { name, args } if args.is_empty() => write!(f, "call {}", name),
+            Step::Call { name, args } => write!(f, "call {} {}", name, args.join(" ")),
+            Step::Script { path, args } if args.is_empty() => {
+                write!(f, "script {}", path.display())
+            }
+            Step::Script { path, args } => {
+                write!(f, "script {} {}", path.display(), args.join(" "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn arg(name: &str, default: Option<&str>) -> Arg {
+        Arg {
+            name: name.to_string(),
+            default: default.map(str::to_string),
+        }
+    }
+
+    fn setaddr() -> CustomCommand {
+        CustomCommand {
+            args: vec![arg("addr", None), arg("speed", Some("slow"))],
+            ..CustomCommand::with_steps("SETADDR", Vec::new())
+        }
+    }
+
+    #[test]
+    fn should_expand_arguments_with_defaults() {
+        let mut commands = CustomCommands::default();
+        commands.insert(setaddr());
+        let (setaddr, given) = commands.find("SETADDR 7").unwrap();
+        assert_eq!("SETADDR <addr> [speed=slow]", setaddr.usage());
+
+        let values = setaddr.bind_args(&given).unwrap();
+        assert_eq!(vec!["7", "slow"], values);
+        assert_eq!(
+            "ADDR 7 slow {\"json\": 1}",
+            setaddr.expand("ADDR {addr} {2} {\"json\": 1}", &values)
+        );
+
+        assert!(setaddr.bind_args(&[]).is_err());
+        assert_eq!(vec!["7", "two words"], split_args("7 \"two words\""));
+    }
+
+    #[test]
+    fn should_only_find_commands_whose_arguments_fit() {
+        let mut commands = CustomCommands::default();
+        commands.insert(CustomCommand::with_steps("STATUS", Vec::new()));
+        commands.insert(setaddr());
+
+        assert_eq!("STATUS", commands.find("status").unwrap().0.name);
+        assert!(commands.find("STATUS foo").is_none());
+        assert_eq!(
+            vec!["7", "fast"],
+            commands.find("setaddr 7 fast").unwrap().1
+        );
+        assert!(commands.find("SETADDR").is_none());
+        assert!(commands.find("SETADDR 7 fast now").is_none());
+
+        let (setaddr, given) = commands.resolve("SETADDR").unwrap();
+        assert!(setaddr.bind_args(&given).is_err());
+    }
+}
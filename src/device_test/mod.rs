@@ -0,0 +1,237 @@
+pub mod report;
+pub mod runner;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// A test file: `[[case]]` tables, each with `[[case.step]]` tables
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestFileToml {
+    #[serde(default, rename = "case")]
+    cases: Vec<CaseToml>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CaseToml {
+    name: String,
+    /// Default for steps that don't set their own
+    #[serde(default)]
+    retries: u32,
+    #[serde(rename = "step")]
+    steps: Vec<StepToml>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepToml {
+    send: Option<String>,
+    run: Option<String>,
+    expect: Option<String>,
+    timeout_ms: Option<u64>,
+    retries: Option<u32>,
+}
+
+/// The test cases of one file
+pub struct TestSuite {
+    /// The file name without its extension
+    pub name: String,
+    pub cases: Vec<TestCase>,
+}
+
+/// Steps run in order. A case fails at its first failing step.
+pub struct TestCase {
+    pub name: String,
+    pub steps: Vec<TestStep>,
+}
+
+pub enum Action {
+    /// Send a line, like typing it in the write REPL
+    Send(String),
+    /// Run a custom command, with any arguments
+    Run(String),
+    /// Only wait for `expect`
+    Wait,
+}
+
+pub struct TestStep {
+    pub action: Action,
+    /// The step passes once a received line matches. Without it, the response has to
+    /// complete according to [write.response].
+    pub expect: Option<Regex>,
+    /// Overrides `timeout_ms` of [write.response]
+    pub timeout: Option<Duration>,
+    /// Attempts after the first one fails
+    pub retries: u32,
+}
+
+/// Results of one test file
+#[derive(Serialize)]
+pub struct SuiteResult {
+    pub name: String,
+    pub cases: Vec<CaseResult>,
+}
+
+#[derive(Serialize)]
+pub struct CaseResult {
+    pub name: String,
+    pub passed: bool,
+    pub duration_ms: f64,
+    /// Steps after a failing one are not run, and not listed
+    pub steps: Vec<StepResult>,
+}
+
+#[derive(Serialize)]
+pub struct StepResult {
+    /// What the step does, e.g. `send 'AT' expect /OK/`
+    pub step: String,
+    pub passed: bool,
+    pub attempts: u32,
+    /// Of all attempts together
+    pub duration_ms: f64,
+    /// Why the last attempt failed
+    pub message: Option<String>,
+    /// Lines received by the last attempt
+    pub response: Vec<String>,
+}
+
+impl TestSuite {
+    /// Panics on an invalid file, like the config and custom command files
+    pub fn load(path: &Path) -> TestSuite {
+        let file_data = fs::read_to_string(path)
+            .unwrap_or_else(|err| panic!("Cannot open: '{}': {}", path.display(), err));
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        TestSuite::parse(name, &file_data)
+            .unwrap_or_else(|err| panic!("Invalid test file '{}': {}", path.display(), err))
+    }
+
+    fn parse(name: String, file_data: &str) -> Result<TestSuite, String> {
+        let file_toml: TestFileToml = toml::from_str(file_data).map_err(|err| err.to_string())?;
+        let mut cases = Vec::<TestCase>::new();
+
+        for case_toml in file_toml.cases {
+            let CaseToml {
+                name,
+                retries,
+                steps: step_tomls,
+            } = case_toml;
+            let mut steps = Vec::<TestStep>::new();
+            for (index, step_toml) in step_tomls.into_iter().enumerate() {
+                let step = TestStep::from_toml(step_toml, retries)
+                    .map_err(|err| format!("case '{}', step {}: {}", name, index + 1, err))?;
+                steps.push(step);
+            }
+            cases.push(TestCase { name, steps });
+        }
+
+        Ok(TestSuite { name, cases })
+    }
+}
+
+impl TestStep {
+    fn from_toml(step_toml: StepToml, default_retries: u32) -> Result<TestStep, String> {
+        let expect = match &step_toml.expect {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|err| err.to_string())?),
+            None => None,
+        };
+        let action = match (step_toml.send, step_toml.run, &expect) {
+            (Some(text), None, _) => Action::Send(text),
+            (None, Some(input), None) => Action::Run(input),
+            (None, Some(_), Some(_)) => {
+                return Err(String::from("`run` steps cannot have `expect`"));
+            }
+            (None, None, Some(_)) => Action::Wait,
+            (None, None, None) => {
+                return Err(String::from("needs `send`, `run` or `expect`"));
+            }
+            (Some(_), Some(_), _) => return Err(String::from("has both `send` and `run`")),
+        };
+
+        Ok(TestStep {
+            action,
+            expect,
+            timeout: step_toml.timeout_ms.map(Duration::from_millis),
+            retries: step_toml.retries.unwrap_or(default_retries),
+        })
+    }
+
+    /// e.g. `send 'AT+GMR' expect /version/`
+    pub fn describe(&self) -> String {
+        let mut description = match &self.action {
+            Action::Send(text) => format!("send '{}'", text),
+            Action::Run(input) => format!("run {}", input),
+            Action::Wait => String::from("wait"),
+        };
+        if let Some(expect) = &self.expect {
+            description.push_str(&format!(" expect /{}/", expect));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_test_cases() {
+        let suite = TestSuite::parse(
+            String::from("smoke"),
+            r#"
+            [[case]]
+            name = "firmware"
+            retries = 2
+
+            [[case.step]]
+            send = "AT+GMR"
+            expect = "version \\d+"
+            timeout_ms = 2000
+
+            [[case.step]]
+            run = "SETADDR 7"
+            retries = 0
+
+            [[case.step]]
+            expect = "READY"
+            "#,
+        )
+        .unwrap();
+
+        let steps = &suite.cases[0].steps;
+        assert_eq!(
+            vec![
+                "send 'AT+GMR' expect /version \\d+/",
+                "run SETADDR 7",
+                "wait expect /READY/"
+            ],
+            steps.iter().map(TestStep::describe).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![2, 0, 2],
+            steps.iter().map(|step| step.retries).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(Duration::from_millis(2000)), steps[0].timeout);
+    }
+
+    #[test]
+    fn should_reject_invalid_steps() {
+        let error = TestSuite::parse(
+            String::from("smoke"),
+            "[[case]]\nname = \"empty\"\n[[case.step]]\ntimeout_ms = 5\n",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "case 'empty', step 1: needs `send`, `run` or `expect`",
+            error
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use crate::device_test::{CaseResult, SuiteResult};
+
+use std::fs;
+
+/// Whether every case of every suite passed
+pub fn all_passed(results: &[SuiteResult]) -> bool {
+    results
+        .iter()
+        .all(|suite| suite.cases.iter().all(|case| case.passed))
+}
+
+/// Counts and the names of failed cases, for the end of a test run
+pub fn summary(results: &[SuiteResult]) -> String {
+    let cases: Vec<(&SuiteResult, &CaseResult)> = results
+        .iter()
+        .flat_map(|suite| suite.cases.iter().map(move |case| (suite, case)))
+        .collect();
+    let failed: Vec<&(&SuiteResult, &CaseResult)> =
+        cases.iter().filter(|(_, case)| !case.passed).collect();
+
+    let mut summary = format!(
+        "{} passed, {} failed, {} total",
+        cases.len() - failed.len(),
+        failed.len(),
+        cases.len()
+    );
+    for (suite, case) in failed {
+        let message = case
+            .steps
+            .iter()
+            .find(|step| !step.passed)
+            .and_then(|step| step.message.as_deref())
+            .unwrap_or("");
+        summary.push_str(&format!(
+            "\n  FAILED {} / {}: {}",
+            suite.name, case.name, message
+        ));
+    }
+    summary
+}
+
+pub fn junit_xml(results: &[SuiteResult]) -> String {
+    let total_cases: usize = results.iter().map(|suite| suite.cases.len()).sum();
+    let total_failures: usize = results.iter().map(failures).sum();
+    let total_ms: f64 = results.iter().map(duration_ms).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"serial-port-reader-writer\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        total_cases,
+        total_failures,
+        total_ms / 1000.0
+    ));
+
+    for suite in results {
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            escape_xml(&suite.name),
+            suite.cases.len(),
+            failures(suite),
+            duration_ms(suite) / 1000.0
+        ));
+        for case in &suite.cases {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\">\n",
+                escape_xml(&suite.name),
+                escape_xml(&case.name),
+                case.duration_ms / 1000.0
+            ));
+            if let Some(step) = case.steps.iter().find(|step| !step.passed) {
+                let message = step.message.as_deref().unwrap_or("");
+                xml.push_str(&format!(
+                    "      <failure message=\"{}\">{}</failure>\n",
+                    escape_xml(message),
+                    escape_xml(&step.response.join("\n"))
+                ));
+            }
+            let steps: Vec<String> = case
+                .steps
+                .iter()
+                .map(|step| {
+                    format!(
+                        "{} {} ({:.1} ms, {} attempt(s))",
+                        if step.passed { "PASS" } else { "FAIL" },
+                        step.step,
+                        step.duration_ms,
+                        step.attempts
+                    )
+                })
+                .collect();
+            xml.push_str(&format!(
+                "      <system-out>{}</system-out>\n",
+                escape_xml(&steps.join("\n"))
+            ));
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+pub fn json(results: &[SuiteResult]) -> String {
+    serde_json::to_string_pretty(results).expect("Cannot serialize test results")
+}
+
+/// Write the report files that were asked for
+pub fn write_reports(results: &[SuiteResult], junit_path: Option<&str>, json_path: Option<&str>) {
+    if let Some(path) = junit_path {
+        fs::write(path, junit_xml(results))
+            .unwrap_or_else(|err| panic!("Cannot write: '{}': {}", path, err));
+        println!("JUnit report written to: '{}'", path);
+    }
+    if let Some(path) = json_path {
+        fs::write(path, json(results))
+            .unwrap_or_else(|err| panic!("Cannot write: '{}': {}", path, err));
+        println!("JSON report written to: '{}'", path);
+    }
+}
+
+fn failures(suite: &SuiteResult) -> usize {
+    suite.cases.iter().filter(|case| !case.passed).count()
+}
+
+fn duration_ms(suite: &SuiteResult) -> f64 {
+    suite.cases.iter().map(|case| case.duration_ms).sum()
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Not allowed in XML 1.0, even escaped
+            c if (c as u32) < 0x20 && c != '\n' && c != '\r' && c != '\t' => {
+                escaped.push_str(&format!("\\x{:02X}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_test::StepResult;
+    use pretty_assertions::assert_eq;
+
+    fn results() -> Vec<SuiteResult> {
+        vec![SuiteResult {
+            name: String::from("smoke"),
+            cases: vec![
+                CaseResult {
+                    name: String::from("boot"),
+                    passed: true,
+                    duration_ms: 1500.0,
+                    steps: vec![],
+                },
+                CaseResult {
+                    name: String::from("version <2>"),
+                    passed: false,
+                    duration_ms: 500.0,
+                    steps: vec![StepResult {
+                        step: String::from("send 'AT' expect /OK/"),
+                        passed: false,
+                        attempts: 2,
+                        duration_ms: 500.0,
+                        message: Some(String::from("expected /OK/, timed out")),
+                        response: vec![String::from("ERROR \x02")],
+                    }],
+                },
+            ],
+        }]
+    }
+
+    #[test]
+    fn should_write_junit_xml() {
+        let xml = junit_xml(&results());
+
+        assert!(
+            xml.contains("<testsuite name=\"smoke\" tests=\"2\" failures=\"1\" time=\"2.000\">")
+        );
+        assert!(xml
+            .contains("<testcase classname=\"smoke\" name=\"version &lt;2&gt;\" time=\"0.500\">"));
+        assert!(xml.contains("<failure message=\"expected /OK/, timed out\">ERROR \\x02</failure>"));
+        assert!(xml.contains("FAIL send &apos;AT&apos; expect /OK/ (500.0 ms, 2 attempt(s))"));
+    }
+
+    #[test]
+    fn should_summarize_failures() {
+        let results = results();
+        assert_eq!(false, all_passed(&results));
+        assert_eq!(
+            "1 passed, 1 failed, 2 total\n  FAILED smoke / version <2>: expected /OK/, timed out",
+            summary(&results)
+        );
+    }
+}
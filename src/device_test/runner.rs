@@ -0,0 +1,278 @@
+use crate::custom_commands::CustomCommands;
+use crate::device_test::{
+    Action, CaseResult, StepResult, SuiteResult, TestCase, TestStep, TestSuite,
+};
+use crate::input_output::command_steps::{StepRunner, StepTarget, TxSettings};
+use crate::input_output::response::{EndReason, Response, ResponseRule};
+use crate::input_output::shutdown;
+use crate::input_output::write_serial::Outcome;
+use crate::parse_config::WriteConfig;
+
+use std::time::Instant;
+
+/// A `StepTarget` that also hands back the lines received, for the reports
+pub trait TestTarget: StepTarget {
+    /// Send a line and collect its response, or say why nothing was sent
+    fn exchange(&mut self, text: &str, tx_settings: &TxSettings) -> Result<Response, String>;
+    /// Print received lines until `rule` says to stop, without sending anything
+    fn receive(&mut self, rule: &ResponseRule, label: &str) -> Response;
+}
+
+/// Runs the cases of test suites against a `TestTarget`, printing each step as it passes
+/// or fails
+pub struct TestRunner<'a> {
+    custom_commands: &'a CustomCommands,
+    write_config: &'a WriteConfig,
+}
+
+impl<'a> TestRunner<'a> {
+    pub fn new(custom_commands: &'a CustomCommands, write_config: &'a WriteConfig) -> Self {
+        TestRunner {
+            custom_commands,
+            write_config,
+        }
+    }
+
+    /// Run the cases of each suite. Once SIGINT or SIGTERM is received, the remaining
+    /// cases are left out, so the results so far can still be reported.
+    pub fn run<T: TestTarget>(&self, target: &mut T, suites: &[TestSuite]) -> Vec<SuiteResult> {
+        let mut results = Vec::<SuiteResult>::new();
+        for suite in suites {
+            if shutdown::requested() {
+                break;
+            }
+            let mut cases = Vec::<CaseResult>::new();
+            for case in &suite.cases {
+                if shutdown::requested() {
+                    println!("--- Interrupted, the remaining cases are not run ---");
+                    break;
+                }
+                cases.push(self.run_case(target, case));
+            }
+            results.push(SuiteResult {
+                name: suite.name.clone(),
+                cases,
+            });
+        }
+        results
+    }
+
+    fn run_case<T: TestTarget>(&self, target: &mut T, case: &TestCase) -> CaseResult {
+        println!("\n=== {} ===", case.name);
+        let start_time = Instant::now();
+        let mut steps = Vec::<StepResult>::new();
+
+        for step in &case.steps {
+            let result = self.run_step(target, step);
+            let passed = result.passed;
+            match &result.message {
+                Some(message) => println!("FAIL {}: {}", result.step, message),
+                None => println!("PASS {} ({:.1} ms)", result.step, result.duration_ms),
+            }
+            steps.push(result);
+            if !passed || shutdown::requested() {
+                break;
+            }
+        }
+
+        CaseResult {
+            name: case.name.clone(),
+            passed: steps.len() == case.steps.len() && steps.iter().all(|step| step.passed),
+            duration_ms: millis(start_time),
+            steps,
+        }
+    }
+
+    /// Attempt a step until it passes or runs out of retries
+    fn run_step<T: TestTarget>(&self, target: &mut T, step: &TestStep) -> StepResult {
+        let start_time = Instant::now();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let result = self.attempt_step(target, step);
+            if result.is_ok() || attempts > step.retries || shutdown::requested() {
+                let (message, response) = match result {
+                    Ok(response) => (None, response),
+                    Err((message, response)) => (Some(message), response),
+                };
+                return StepResult {
+                    step: step.describe(),
+                    passed: message.is_none(),
+                    attempts,
+                    duration_ms: millis(start_time),
+                    message,
+                    response,
+                };
+            }
+            println!("--- Retrying: {} ---", step.describe());
+        }
+    }
+
+    /// The lines received, or why the step failed along with them
+    fn attempt_step<T: TestTarget>(
+        &self,
+        target: &mut T,
+        step: &TestStep,
+    ) -> Result<Vec<String>, (String, Vec<String>)> {
+        let mut tx_settings = TxSettings::new(self.write_config, None);
+        let timeout = step.timeout.or(tx_settings.response.timeout);
+        if let Some(expect) = &step.expect {
+            tx_settings.response = ResponseRule {
+                until_regex: Some(expect.clone()),
+                timeout,
+                ..ResponseRule::default()
+            };
+        } else {
+            tx_settings.response.timeout = timeout;
+        }
+
+        let response = match &step.action {
+            Action::Send(text) => target
+                .exchange(text, &tx_settings)
+                .map_err(|error| (format!("nothing sent: {}", error), Vec::new()))?,
+            Action::Wait => target.receive(&tx_settings.response, &step.describe()),
+            Action::Run(input) => {
+                let (custom_command, args) = self
+                    .custom_commands
+                    .resolve(input)
+                    .ok_or_else(|| (format!("no custom command '{}'", input), Vec::new()))?;
+                let mut step_runner =
+                    StepRunner::new(self.custom_commands, self.write_config, Vec::new());
+                return match step_runner.run(target, custom_command, &args) {
+                    Ok(Outcome::Completed) => Ok(Vec::new()),
+                    Ok(outcome) | Err(outcome) => Err((
+                        format!("custom command ended with {:?}", outcome),
+                        Vec::new(),
+                    )),
+                };
+            }
+        };
+
+        let passed = match &step.expect {
+            Some(_) => response.ended_by == EndReason::Matched,
+            None => !matches!(
+                response.ended_by,
+                EndReason::Timeout | EndReason::Disconnected
+            ),
+        };
+        if passed {
+            return Ok(response.lines);
+        }
+        let message = match (&step.expect, response.lines.is_empty()) {
+            (Some(expect), true) => format!("expected /{}/, nothing received", expect),
+            (Some(expect), false) => format!("expected /{}/, {}", expect, response.ended_by),
+            (None, true) => String::from("no response"),
+            (None, false) => format!("response incomplete, {}", response.ended_by),
+        };
+        Err((message, response.lines))
+    }
+}
+
+/// Milliseconds since `start_time`, with a fraction
+fn millis(start_time: Instant) -> f64 {
+    start_time.elapsed().as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use regex::Regex;
+    use std::collections::VecDeque;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Records what was sent, and answers each line with the next response
+    struct Device {
+        sent: Vec<String>,
+        responses: VecDeque<Response>,
+    }
+
+    impl StepTarget for Device {
+        fn send_and_read(&mut self, text: &str, _tx_settings: &TxSettings) -> Outcome {
+            self.sent.push(text.to_string());
+            Outcome::Completed
+        }
+
+        fn wait_for(&mut self, _pattern: &Regex, _timeout: Duration) -> bool {
+            true
+        }
+
+        fn run_script(
+            &mut self,
+            _path: &Path,
+            _args: &[String],
+            _running: &[String],
+        ) -> Result<(), Outcome> {
+            Ok(())
+        }
+    }
+
+    impl TestTarget for Device {
+        fn exchange(&mut self, text: &str, _tx_settings: &TxSettings) -> Result<Response, String> {
+            self.sent.push(text.to_string());
+            Ok(self.responses.pop_front().unwrap())
+        }
+
+        fn receive(&mut self, _rule: &ResponseRule, _label: &str) -> Response {
+            self.responses.pop_front().unwrap()
+        }
+    }
+
+    fn response(lines: &[&str], ended_by: EndReason) -> Response {
+        Response {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            last_line_at: None,
+            ended_by,
+        }
+    }
+
+    fn run(file_data: &str, responses: Vec<Response>) -> (Vec<SuiteResult>, Vec<String>) {
+        let suite = TestSuite::parse(String::from("smoke"), file_data).unwrap();
+        let custom_commands = CustomCommands::default();
+        let write_config = WriteConfig::with_response(ResponseRule::default());
+        let mut device = Device {
+            sent: Vec::new(),
+            responses: responses.into(),
+        };
+        let results = TestRunner::new(&custom_commands, &write_config).run(&mut device, &[suite]);
+        (results, device.sent)
+    }
+
+    #[test]
+    fn should_retry_a_step_until_it_passes() {
+        let (results, sent) = run(
+            "[[case]]\nname = \"gmr\"\nretries = 1\n\
+             [[case.step]]\nsend = \"AT+GMR\"\nexpect = \"version\"\n",
+            vec![
+                response(&[], EndReason::Timeout),
+                response(&["version 3"], EndReason::Matched),
+            ],
+        );
+
+        let case = &results[0].cases[0];
+        assert!(case.passed);
+        assert_eq!(2, case.steps[0].attempts);
+        assert_eq!(vec!["version 3"], case.steps[0].response);
+        assert_eq!(vec!["AT+GMR", "AT+GMR"], sent);
+    }
+
+    #[test]
+    fn should_stop_a_case_at_its_first_failing_step() {
+        let (results, sent) = run(
+            "[[case]]\nname = \"boot\"\n\
+             [[case.step]]\nsend = \"AT+RST\"\nexpect = \"READY\"\n\
+             [[case.step]]\nsend = \"AT+GMR\"\n",
+            vec![response(&["ERROR"], EndReason::Timeout)],
+        );
+
+        let case = &results[0].cases[0];
+        assert!(!case.passed);
+        assert_eq!(1, case.steps.len());
+        assert_eq!(
+            Some(String::from("expected /READY/, timed out")),
+            case.steps[0].message
+        );
+        assert_eq!(vec!["AT+RST"], sent);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::input_output::read_serial::ReadSerial;
 use crate::input_output::write_serial::WriteSerial;
+use crate::script::runner::ScriptRunner;
 
 pub struct Factory {}
 
@@ -9,7 +10,10 @@ impl Factory {
     }
 
     pub fn create_write_serial(config_file_path: &str) -> WriteSerial {
-        let read_serial = Box::new(Factory::create_read_serial(config_file_path));
-        WriteSerial::new(config_file_path, read_serial)
+        WriteSerial::new(config_file_path)
+    }
+
+    pub fn create_script_runner(config_file_path: &str) -> ScriptRunner {
+        ScriptRunner::new(config_file_path)
     }
 }
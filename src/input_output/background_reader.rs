@@ -0,0 +1,341 @@
+use serialport::SerialPort;
+
+use std::io::ErrorKind;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the background thread blocks on a single read
+const POLL_INTERVAL_MS: u64 = 20;
+/// Bytes without a newline are treated as a line once nothing arrived for this long,
+/// so prompts like `> ` still show up.
+const PARTIAL_LINE_FLUSH_MS: u64 = 100;
+
+/// Prints a line received outside of a response, with the last command sent, if any
+pub type Printer = Box<dyn FnMut(Option<&str>, &RxLine) + Send>;
+
+/// A received line, and the bytes it came from
+#[derive(Clone, Debug, PartialEq)]
+pub struct RxLine {
+    /// Decoded, without the line ending
+    pub text: String,
+    /// Everything received since the line before, including this line's ending and any
+    /// empty lines, which are not passed on by themselves
+    pub bytes: Vec<u8>,
+}
+
+impl AsRef<str> for RxLine {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
+struct Shared {
+    /// The last command transmitted, so output can be attributed to it
+    last_command: Option<String>,
+    /// Set while the REPL waits for a response to the last command
+    response: Option<Sender<RxLine>>,
+    /// When the first byte arrived since `begin_response()`
+    first_byte: Option<Instant>,
+    /// Set while the scheduler sends, so a response only begins once it is done
+    scheduled: bool,
+    /// When anything was last received
+    last_byte: Option<Instant>,
+}
+
+/// Lets the scheduler take turns with the REPL, and name the command that output arriving
+/// next belongs to, from another thread
+#[derive(Clone)]
+pub struct CommandLabel(Arc<Mutex<Shared>>);
+
+impl CommandLabel {
+    /// Take the port for the scheduler until `release()`. Returns false while a response
+    /// is expected, which scheduled output must not end up in.
+    pub fn claim(&self) -> bool {
+        let mut shared = self.0.lock().unwrap();
+        if shared.response.is_some() {
+            return false;
+        }
+        shared.scheduled = true;
+        true
+    }
+
+    pub fn release(&self) {
+        self.0.lock().unwrap().scheduled = false;
+    }
+
+    /// Whether nothing was received for `duration`
+    pub fn quiet_for(&self, duration: Duration) -> bool {
+        match self.0.lock().unwrap().last_byte {
+            Some(last_byte) => last_byte.elapsed() >= duration,
+            None => true,
+        }
+    }
+
+    /// Only while the port is claimed, so a response keeps its own label
+    pub fn set(&self, command: &str) {
+        self.0.lock().unwrap().last_command = Some(command.to_string());
+    }
+}
+
+/// Reads the serial port on its own thread while the write REPL waits for input.
+///
+/// Lines that arrive while a response is expected go to the `Receiver` returned by
+/// `begin_response()`. Everything else is printed with the `Printer`, along with the
+/// command that preceded it.
+pub struct BackgroundReader {
+    shared: Arc<Mutex<Shared>>,
+    running: Arc<AtomicBool>,
+    /// The thread hands the printer back when it stops, so it can be restarted
+    handle: Option<thread::JoinHandle<Printer>>,
+    /// Only set while stopped
+    printer: Option<Printer>,
+}
+
+impl BackgroundReader {
+    pub fn start(serial_port: Box<dyn SerialPort>, printer: Printer) -> Self {
+        let mut background_reader = Self {
+            shared: Arc::new(Mutex::new(Shared {
+                last_command: None,
+                response: None,
+                first_byte: None,
+                scheduled: false,
+                last_byte: None,
+            })),
+            running: Arc::new(AtomicBool::new(false)),
+            handle: None,
+            printer: None,
+        };
+        background_reader.spawn(serial_port, printer);
+        background_reader
+    }
+
+    /// Start reading `serial_port` instead, e.g. after reopening the port
+    pub fn restart(&mut self, serial_port: Box<dyn SerialPort>) {
+        self.stop();
+        if let Some(printer) = self.printer.take() {
+            self.spawn(serial_port, printer);
+        }
+    }
+
+    fn spawn(&mut self, mut serial_port: Box<dyn SerialPort>, mut printer: Printer) {
+        self.running.store(true, Ordering::SeqCst);
+        let thread_shared = Arc::clone(&self.shared);
+        let thread_running = Arc::clone(&self.running);
+        let handle = thread::spawn(move || {
+            let _ = serial_port.set_timeout(Duration::from_millis(POLL_INTERVAL_MS));
+            let mut buffer: [u8; 256] = [0; 256];
+            let mut pending = Vec::<u8>::new();
+            let mut last_byte_time = Instant::now();
+
+            while thread_running.load(Ordering::SeqCst) {
+                let bytes_read = match serial_port.read(&mut buffer) {
+                    Ok(bytes_read) => bytes_read,
+                    Err(error) => match error.kind() {
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted => 0,
+                        _ => {
+                            // E.g. unplugged: every read fails at once from now on
+                            BackgroundReader::dispatch(&thread_shared, &mut printer, &mut pending);
+                            thread_running.store(false, Ordering::SeqCst);
+                            // A response being waited for ends as disconnected
+                            thread_shared.lock().unwrap().response = None;
+                            println!(
+                                "--- Cannot read the port: {}. Type `:reconnect` once it is back ---",
+                                error
+                            );
+                            break;
+                        }
+                    },
+                };
+                if bytes_read > 0 {
+                    last_byte_time = Instant::now();
+                    let mut shared = thread_shared.lock().unwrap();
+                    shared.last_byte = Some(last_byte_time);
+                    if shared.response.is_some() {
+                        shared.first_byte.get_or_insert(last_byte_time);
+                    }
+                }
+
+                for byte in &buffer[..bytes_read] {
+                    pending.push(*byte);
+                    if *byte == b'\n' {
+                        BackgroundReader::dispatch(&thread_shared, &mut printer, &mut pending);
+                    }
+                }
+
+                let idle = last_byte_time.elapsed() >= Duration::from_millis(PARTIAL_LINE_FLUSH_MS);
+                if !pending.is_empty() && idle {
+                    BackgroundReader::dispatch(&thread_shared, &mut printer, &mut pending);
+                }
+            }
+            printer
+        });
+        self.handle = Some(handle);
+    }
+
+    /// Route received lines to the returned `Receiver` until `end_response()` is called.
+    /// If the thread stopped after a read error, the `Receiver` is disconnected right away.
+    /// Waits for the scheduler to finish what it is sending first.
+    pub fn begin_response(&self, command: &str) -> Receiver<RxLine> {
+        let (sender, receiver) = mpsc::channel::<RxLine>();
+        let mut shared = self.shared.lock().unwrap();
+        while shared.scheduled {
+            drop(shared);
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            shared = self.shared.lock().unwrap();
+        }
+        shared.last_command = Some(command.to_string());
+        if self.running.load(Ordering::SeqCst) {
+            shared.response = Some(sender);
+        }
+        shared.first_byte = None;
+        receiver
+    }
+
+    pub fn command_label(&self) -> CommandLabel {
+        CommandLabel(Arc::clone(&self.shared))
+    }
+
+    /// Go back to printing received lines as they arrive. Returns when the first byte of
+    /// the response arrived, if any did.
+    pub fn end_response(&self) -> Option<Instant> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.response = None;
+        shared.first_byte.take()
+    }
+
+    /// Stop the background thread and wait for it to finish
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            self.printer = Some(handle.join().expect("Background reader did not join()"));
+        }
+    }
+
+    /// Pass on the last line in `pending`, along with all of `pending`. Empty lines are
+    /// left in it, for the next line.
+    fn dispatch(shared: &Arc<Mutex<Shared>>, printer: &mut Printer, pending: &mut Vec<u8>) {
+        let text = last_line(pending);
+        if text.is_empty() {
+            return;
+        }
+        let line = RxLine {
+            text,
+            bytes: mem::take(pending),
+        };
+
+        let last_command = {
+            let shared = shared.lock().unwrap();
+            if let Some(sender) = &shared.response {
+                if sender.send(line.clone()).is_ok() {
+                    return;
+                }
+            }
+            shared.last_command.clone()
+        };
+        // Printing writes to the terminal and the log, which must not hold up other threads
+        printer(last_command.as_deref(), &line);
+    }
+}
+
+impl Drop for BackgroundReader {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The last line in `bytes`, decoded, without its line ending
+fn last_line(bytes: &[u8]) -> String {
+    let end = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let start = end
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |i| i + 1);
+    String::from_utf8_lossy(&end[start..])
+        .trim_end_matches('\r')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_not_let_the_scheduler_send_during_a_response() {
+        let shared = Arc::new(Mutex::new(Shared {
+            last_command: None,
+            response: None,
+            first_byte: None,
+            scheduled: false,
+            last_byte: None,
+        }));
+        let label = CommandLabel(Arc::clone(&shared));
+        assert!(label.claim());
+        label.release();
+
+        let (sender, _receiver) = mpsc::channel::<RxLine>();
+        shared.lock().unwrap().response = Some(sender);
+        assert!(!label.claim());
+        assert!(!shared.lock().unwrap().scheduled);
+    }
+
+    #[test]
+    fn should_print_lines_outside_of_a_response_without_holding_the_lock() {
+        let shared = Arc::new(Mutex::new(Shared {
+            last_command: Some(String::from("AT+RST")),
+            response: None,
+            first_byte: None,
+            scheduled: false,
+            last_byte: None,
+        }));
+        let printed = Arc::new(Mutex::new(Vec::<(Option<String>, String, bool)>::new()));
+        let printer_shared = Arc::clone(&shared);
+        let printer_printed = Arc::clone(&printed);
+        let mut printer: Printer = Box::new(move |command, line| {
+            printer_printed.lock().unwrap().push((
+                command.map(String::from),
+                line.text.clone(),
+                printer_shared.try_lock().is_ok(),
+            ));
+        });
+
+        let mut pending = b"ready\r\n".to_vec();
+        BackgroundReader::dispatch(&shared, &mut printer, &mut pending);
+        assert_eq!(
+            vec![(Some(String::from("AT+RST")), String::from("ready"), true)],
+            *printed.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn should_pass_on_the_bytes_of_empty_lines_with_the_next_line() {
+        let shared = Arc::new(Mutex::new(Shared {
+            last_command: None,
+            response: None,
+            first_byte: None,
+            scheduled: false,
+            last_byte: None,
+        }));
+        let (sender, receiver) = mpsc::channel::<RxLine>();
+        shared.lock().unwrap().response = Some(sender);
+        let mut printer: Printer = Box::new(|_, _| {});
+
+        let mut pending = b"\r\n".to_vec();
+        BackgroundReader::dispatch(&shared, &mut printer, &mut pending);
+        pending.extend_from_slice(b"OK\xff\r\n");
+        BackgroundReader::dispatch(&shared, &mut printer, &mut pending);
+        assert_eq!(
+            RxLine {
+                text: String::from("OK\u{fffd}"),
+                bytes: b"\r\nOK\xff\r\n".to_vec(),
+            },
+            receiver.try_recv().unwrap()
+        );
+        assert!(pending.is_empty());
+        assert!(receiver.try_recv().is_err());
+    }
+}
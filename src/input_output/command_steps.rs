@@ -0,0 +1,292 @@
+use crate::custom_commands::{CustomCommand, CustomCommands, Step};
+use crate::input_output::response::{EndReason, Response, ResponseRule};
+use crate::input_output::tx_format::LineEnding;
+use crate::input_output::write_serial::Outcome;
+use crate::parse_config::WriteConfig;
+use crate::script::rhai_script;
+
+use regex::Regex;
+
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How a single line is sent, and how its response is read
+pub struct TxSettings {
+    pub line_ending: LineEnding,
+    pub response: ResponseRule,
+    /// Response times are counted under the custom command's name, else the line sent
+    pub command: Option<String>,
+}
+
+/// Where the steps of a custom command are carried out: the write REPL's port, or the
+/// port of a running script
+pub trait StepTarget {
+    /// Send a line and read its response
+    fn send_and_read(&mut self, text: &str, tx_settings: &TxSettings) -> Outcome;
+    /// Print received lines until one matches `pattern`. Returns false on timeout.
+    fn wait_for(&mut self, pattern: &Regex, timeout: Duration) -> bool;
+    /// Run a Rhai script. `running` holds the custom commands that led to it, which the
+    /// script must not run again.
+    fn run_script(
+        &mut self,
+        path: &Path,
+        args: &[String],
+        running: &[String],
+    ) -> Result<(), Outcome>;
+}
+
+/// Runs the steps of custom commands against a `StepTarget`
+pub struct StepRunner<'a> {
+    custom_commands: &'a CustomCommands,
+    write_config: &'a WriteConfig,
+    /// The commands being run, outermost first
+    running: Vec<String>,
+}
+
+impl TxSettings {
+    /// Settings from the config, overridden by the custom command's own, if any
+    pub fn new(write_config: &WriteConfig, custom_command: Option<&CustomCommand>) -> Self {
+        let line_ending = custom_command
+            .and_then(|command| command.line_ending.clone())
+            .unwrap_or_else(|| write_config.tx_line_ending.clone());
+        let response = match custom_command.and_then(|command| command.response.as_ref()) {
+            Some(response) => response.or(&write_config.response),
+            None => write_config.response.clone(),
+        };
+
+        TxSettings {
+            line_ending,
+            response,
+            command: custom_command.map(|command| command.name.clone()),
+        }
+    }
+}
+
+impl<'a> StepRunner<'a> {
+    /// `running` holds the commands already running, e.g. those that started the script
+    /// calling `run_command()`
+    pub fn new(
+        custom_commands: &'a CustomCommands,
+        write_config: &'a WriteConfig,
+        running: Vec<String>,
+    ) -> Self {
+        StepRunner {
+            custom_commands,
+            write_config,
+            running,
+        }
+    }
+
+    /// Run every step of a custom command. Returns the worst outcome of its sends, or
+    /// `Err` if a step failed and the remaining steps were skipped.
+    pub fn run(
+        &mut self,
+        target: &mut dyn StepTarget,
+        custom_command: &CustomCommand,
+        args: &[String],
+    ) -> Result<Outcome, Outcome> {
+        // `call` cycles are rejected when loading, but scripts can still run their callers
+        if self.running.contains(&custom_command.name) {
+            println!(
+                "Commands run each other: {} -> {}",
+                self.running.join(" -> "),
+                custom_command.name
+            );
+            return Err(Outcome::Failed);
+        }
+        let values = match custom_command.bind_args(args) {
+            Ok(values) => values,
+            Err(error) => {
+                println!("{}", error);
+                return Err(Outcome::Failed);
+            }
+        };
+
+        self.running.push(custom_command.name.clone());
+        let result = self.run_steps(target, custom_command, &values);
+        self.running.pop();
+        result
+    }
+
+    fn run_steps(
+        &mut self,
+        target: &mut dyn StepTarget,
+        custom_command: &CustomCommand,
+        values: &[String],
+    ) -> Result<Outcome, Outcome> {
+        let tx_settings = TxSettings::new(self.write_config, Some(custom_command));
+        let mut outcome = Outcome::Completed;
+        for step in &custom_command.steps {
+            match step {
+                Step::Send { text, delay } => {
+                    let text = custom_command.expand(text, values);
+                    outcome = outcome.max(target.send_and_read(&text, &tx_settings));
+                    thread::sleep(delay.unwrap_or(self.write_config.step_delay));
+                }
+                Step::WaitFor { pattern, timeout } => {
+                    if !target.wait_for(pattern, *timeout) {
+                        println!("--- Timed out waiting for /{}/, stopping ---", pattern);
+                        return Err(Outcome::Timeout);
+                    }
+                }
+                Step::Sleep(duration) => thread::sleep(*duration),
+                Step::Call { name, args } => {
+                    // Checked when the commands were loaded
+                    let callee = self.custom_commands.get(name).unwrap();
+                    let callee_args: Vec<String> = args
+                        .iter()
+                        .map(|arg| custom_command.expand(arg, values))
+                        .collect();
+                    outcome = outcome.max(self.run(target, callee, &callee_args)?);
+                }
+                Step::Script { path, args } => {
+                    let step_args: Vec<String> = args
+                        .iter()
+                        .map(|arg| custom_command.expand(arg, values))
+                        .collect();
+                    let args = rhai_script::script_args(step_args, values);
+                    target.run_script(path, &args, &self.running)?;
+                }
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+/// What a collected response means for the command, printing why it was not complete
+pub fn response_outcome(response: &Response, rule: &ResponseRule) -> Outcome {
+    // With an `until_regex`, only a match is a complete response
+    let matched = rule.until_regex.is_none() || response.ended_by == EndReason::Matched;
+    match response.ended_by {
+        EndReason::Timeout if response.lines.is_empty() => {
+            println!("Response timed out!");
+            Outcome::Timeout
+        }
+        EndReason::Disconnected if response.lines.is_empty() => {
+            println!("No response!");
+            Outcome::Timeout
+        }
+        EndReason::LineCount if matched => Outcome::Completed,
+        ref ended_by => {
+            println!("--- Response ended: {} ---", ended_by);
+            match ended_by {
+                EndReason::Timeout | EndReason::Disconnected => Outcome::NoMatch,
+                _ if !matched => Outcome::NoMatch,
+                _ => Outcome::Completed,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Records what was sent, and answers every line with `outcome`
+    struct Recorder {
+        sent: Vec<String>,
+        outcome: Outcome,
+    }
+
+    impl StepTarget for Recorder {
+        fn send_and_read(&mut self, text: &str, _tx_settings: &TxSettings) -> Outcome {
+            self.sent.push(text.to_string());
+            self.outcome
+        }
+
+        fn wait_for(&mut self, _pattern: &Regex, _timeout: Duration) -> bool {
+            true
+        }
+
+        fn run_script(
+            &mut self,
+            _path: &Path,
+            _args: &[String],
+            _running: &[String],
+        ) -> Result<(), Outcome> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_run_called_commands_and_keep_the_worst_outcome() {
+        let mut custom_commands = CustomCommands::default();
+        custom_commands.insert(CustomCommand::with_steps(
+            "INNER",
+            vec![Step::send(String::from("AT+B"))],
+        ));
+        let outer = CustomCommand::with_steps(
+            "OUTER",
+            vec![
+                Step::send(String::from("AT+A")),
+                Step::Call {
+                    name: String::from("inner"),
+                    args: Vec::new(),
+                },
+            ],
+        );
+        let write_config = WriteConfig::with_response(ResponseRule::default());
+        let mut recorder = Recorder {
+            sent: Vec::new(),
+            outcome: Outcome::NoMatch,
+        };
+
+        let outcome = StepRunner::new(&custom_commands, &write_config, Vec::new()).run(
+            &mut recorder,
+            &outer,
+            &[],
+        );
+        assert_eq!(Ok(Outcome::NoMatch), outcome);
+        assert_eq!(vec!["AT+A", "AT+B"], recorder.sent);
+    }
+
+    #[test]
+    fn should_not_run_a_command_that_is_already_running() {
+        let custom_commands = CustomCommands::default();
+        let reset = CustomCommand::with_steps("RESET", vec![Step::send(String::from("ATZ"))]);
+        let write_config = WriteConfig::with_response(ResponseRule::default());
+        let mut recorder = Recorder {
+            sent: Vec::new(),
+            outcome: Outcome::Completed,
+        };
+
+        let running = vec![String::from("SETUP"), String::from("RESET")];
+        let outcome = StepRunner::new(&custom_commands, &write_config, running).run(
+            &mut recorder,
+            &reset,
+            &[],
+        );
+        assert_eq!(Err(Outcome::Failed), outcome);
+        assert!(recorder.sent.is_empty());
+    }
+
+    #[test]
+    fn should_only_complete_responses_that_matched_until_regex() {
+        let response = |lines: &[&str], ended_by| Response {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            last_line_at: None,
+            ended_by,
+        };
+        let until_ok = ResponseRule {
+            until_regex: Some(Regex::new("^OK$").unwrap()),
+            ..ResponseRule::default()
+        };
+
+        let outcome = response_outcome(&response(&["OK"], EndReason::Matched), &until_ok);
+        assert_eq!(Outcome::Completed, outcome);
+        let outcome = response_outcome(&response(&["BUSY"], EndReason::Idle), &until_ok);
+        assert_eq!(Outcome::NoMatch, outcome);
+        let outcome = response_outcome(&response(&["BUSY"], EndReason::LineCount), &until_ok);
+        assert_eq!(Outcome::NoMatch, outcome);
+        let outcome = response_outcome(&response(&[], EndReason::Timeout), &until_ok);
+        assert_eq!(Outcome::Timeout, outcome);
+
+        let default = ResponseRule::default();
+        let outcome = response_outcome(&response(&["BUSY"], EndReason::LineCount), &default);
+        assert_eq!(Outcome::Completed, outcome);
+        let outcome = response_outcome(&response(&["BU"], EndReason::Timeout), &default);
+        assert_eq!(Outcome::NoMatch, outcome);
+    }
+}
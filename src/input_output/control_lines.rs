@@ -0,0 +1,139 @@
+use serialport::SerialPort;
+
+use std::time::{Duration, Instant};
+
+/// How often the input lines are read at most
+pub const POLL_INTERVAL_MS: u64 = 50;
+
+/// The modem control lines driven by the other end
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputLines {
+    pub cts: bool,
+    pub dsr: bool,
+    pub ri: bool,
+    pub cd: bool,
+}
+
+impl InputLines {
+    pub fn read(serial_port: &mut dyn SerialPort) -> serialport::Result<InputLines> {
+        Ok(InputLines {
+            cts: serial_port.read_clear_to_send()?,
+            dsr: serial_port.read_data_set_ready()?,
+            ri: serial_port.read_ring_indicator()?,
+            cd: serial_port.read_carrier_detect()?,
+        })
+    }
+
+    fn named(&self) -> [(&'static str, bool); 4] {
+        [
+            ("CTS", self.cts),
+            ("DSR", self.dsr),
+            ("RI", self.ri),
+            ("CD", self.cd),
+        ]
+    }
+
+    /// `CTS on, DSR off, RI off, CD on`
+    pub fn describe(&self) -> String {
+        describe(self.named().iter())
+    }
+
+    /// The lines that differ in `other`, e.g. `DSR off`, or `None` if none do
+    pub fn changes(&self, other: &InputLines) -> Option<String> {
+        let before = self.named();
+        let changed: Vec<(&str, bool)> = other
+            .named()
+            .iter()
+            .zip(before.iter())
+            .filter(|(now, before)| now.1 != before.1)
+            .map(|(now, _)| *now)
+            .collect();
+        match changed.is_empty() {
+            true => None,
+            false => Some(describe(changed.iter())),
+        }
+    }
+}
+
+fn describe<'a>(lines: impl Iterator<Item = &'a (&'static str, bool)>) -> String {
+    lines
+        .map(|(name, level)| format!("{} {}", name, if *level { "on" } else { "off" }))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Reads CTS, DSR, RI and CD every now and then, and reports what changed
+pub struct LineWatcher {
+    last: Option<InputLines>,
+    last_poll: Option<Instant>,
+    /// Set once the port could not report its lines, e.g. a pseudo terminal
+    failed: bool,
+}
+
+impl LineWatcher {
+    pub fn new() -> Self {
+        LineWatcher {
+            last: None,
+            last_poll: None,
+            failed: false,
+        }
+    }
+
+    /// The event to record, if any: every line the first time, then only the ones that
+    /// changed
+    pub fn poll(&mut self, serial_port: &mut dyn SerialPort) -> Option<String> {
+        let now = Instant::now();
+        // `is_none_or` would need Rust 1.82
+        #[allow(clippy::unnecessary_map_or)]
+        let due = self.last_poll.map_or(true, |last_poll| {
+            now >= last_poll + Duration::from_millis(POLL_INTERVAL_MS)
+        });
+        if self.failed || !due {
+            return None;
+        }
+        self.last_poll = Some(now);
+
+        let lines = match InputLines::read(serial_port) {
+            Ok(lines) => lines,
+            Err(error) => {
+                self.failed = true;
+                eprintln!("Cannot read the control lines: {}", error);
+                return None;
+            }
+        };
+        let event = match &self.last {
+            Some(last) => last.changes(&lines),
+            None => Some(lines.describe()),
+        };
+        self.last = Some(lines);
+        event
+    }
+}
+
+impl Default for LineWatcher {
+    fn default() -> Self {
+        LineWatcher::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn lines(cts: bool, dsr: bool, ri: bool, cd: bool) -> InputLines {
+        InputLines { cts, dsr, ri, cd }
+    }
+
+    #[test]
+    fn should_describe_changed_lines() {
+        let before = lines(true, true, false, false);
+
+        assert_eq!("CTS on, DSR on, RI off, CD off", before.describe());
+        assert_eq!(None, before.changes(&before));
+        assert_eq!(
+            Some(String::from("DSR off, CD on")),
+            before.changes(&lines(true, false, false, true))
+        );
+    }
+}
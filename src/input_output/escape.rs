@@ -0,0 +1,159 @@
+use std::fmt;
+
+/// Errors returned when the user's input cannot be turned into bytes.
+#[derive(Debug, PartialEq)]
+pub enum EscapeError {
+    /// A `\` was the last character of the input
+    TrailingBackslash,
+    /// `\q`, `\z`, ... are not escapes we know about
+    UnknownEscape(char),
+    /// `\x` was not followed by two hex digits
+    InvalidHexEscape(String),
+    /// A `:hex` token was not a valid byte
+    InvalidHexByte(String),
+}
+
+impl fmt::Display for EscapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EscapeError::TrailingBackslash => write!(f, "Input ends with a lone '\\'"),
+            EscapeError::UnknownEscape(c) => write!(f, "Unknown escape sequence '\\{}'", c),
+            EscapeError::InvalidHexEscape(s) => write!(f, "Invalid hex escape '\\x{}'", s),
+            EscapeError::InvalidHexByte(s) => write!(f, "Invalid hex byte '{}'", s),
+        }
+    }
+}
+
+/// Turn C-style escape sequences into the bytes they represent.
+///
+/// Supported: `\r`, `\n`, `\t`, `\0`, `\a`, `\b`, `\e`, `\f`, `\v`, `\\`, `\'`, `\"`
+/// and `\xHH`. Every other character is sent as its UTF-8 bytes.
+pub fn parse_escapes(input: &str) -> Result<Vec<u8>, EscapeError> {
+    let mut bytes = Vec::<u8>::new();
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8: [u8; 4] = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+
+        let escaped = chars.next().ok_or(EscapeError::TrailingBackslash)?;
+        let byte = match escaped {
+            'r' => b'\r',
+            'n' => b'\n',
+            't' => b'\t',
+            '0' => 0x00,
+            'a' => 0x07,
+            'b' => 0x08,
+            'e' => 0x1b,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '\\' => b'\\',
+            '\'' => b'\'',
+            '"' => b'"',
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 {
+                    return Err(EscapeError::InvalidHexEscape(hex));
+                }
+                u8::from_str_radix(&hex, 16).map_err(|_| EscapeError::InvalidHexEscape(hex))?
+            }
+            other => return Err(EscapeError::UnknownEscape(other)),
+        };
+        bytes.push(byte);
+    }
+
+    Ok(bytes)
+}
+
+/// Parse whitespace separated hex bytes, e.g. `02 41 0x03`.
+/// Tokens longer than one byte, e.g. `024103`, are split into pairs.
+pub fn parse_hex(input: &str) -> Result<Vec<u8>, EscapeError> {
+    let mut bytes = Vec::<u8>::new();
+
+    for token in input.split_whitespace() {
+        let digits = token.trim_start_matches("0x").trim_start_matches("0X");
+        if digits.is_empty() || digits.len() % 2 != 0 {
+            return Err(EscapeError::InvalidHexByte(token.to_string()));
+        }
+
+        for i in (0..digits.len()).step_by(2) {
+            let pair = digits
+                .get(i..i + 2)
+                .ok_or_else(|| EscapeError::InvalidHexByte(token.to_string()))?;
+            let byte = u8::from_str_radix(pair, 16)
+                .map_err(|_| EscapeError::InvalidHexByte(token.to_string()))?;
+            bytes.push(byte);
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Make bytes printable again, the inverse of `parse_escapes()`.
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for byte in bytes {
+        match *byte {
+            b'\r' => result.push_str("\\r"),
+            b'\n' => result.push_str("\\n"),
+            b'\t' => result.push_str("\\t"),
+            b'\\' => result.push_str("\\\\"),
+            0x00 => result.push_str("\\0"),
+            0x20..=0x7e => result.push(*byte as char),
+            other => result.push_str(&format!("\\x{:02X}", other)),
+        }
+    }
+    result
+}
+
+/// Format bytes as `02 41 03`.
+pub fn hex_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_convert_escape_sequences_into_bytes() {
+        let result = parse_escapes("AT\\r\\x02\\0\\\\");
+        assert_eq!(Ok(vec![b'A', b'T', b'\r', 0x02, 0x00, b'\\']), result);
+    }
+
+    #[test]
+    fn should_reject_unknown_and_incomplete_escapes() {
+        assert_eq!(Err(EscapeError::UnknownEscape('q')), parse_escapes("\\q"));
+        assert_eq!(Err(EscapeError::TrailingBackslash), parse_escapes("AT\\"));
+        assert_eq!(
+            Err(EscapeError::InvalidHexEscape(String::from("4"))),
+            parse_escapes("\\x4")
+        );
+    }
+
+    #[test]
+    fn should_parse_hex_bytes() {
+        assert_eq!(Ok(vec![0x02, 0x41, 0x03]), parse_hex("02 41 0x03"));
+        assert_eq!(Ok(vec![0x02, 0x41, 0x03]), parse_hex("024103"));
+        assert_eq!(
+            Err(EscapeError::InvalidHexByte(String::from("4G"))),
+            parse_hex("02 4G")
+        );
+    }
+
+    #[test]
+    fn should_escape_bytes_for_printing() {
+        assert_eq!(
+            "AT\\r\\n\\x02",
+            escape_bytes(&[b'A', b'T', b'\r', b'\n', 0x02])
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use rustyline::history::History;
+use serde::Deserialize;
+
+use std::path::PathBuf;
+
+/// Entries kept when nothing else is configured
+const DEFAULT_MAX_LEN: usize = (1 << 7) + ((1 << 7) - 1);
+/// Directory under the user's data directory, e.g. `~/.local/share/serial-port-reader-writer`
+const DATA_DIR_NAME: &str = "serial-port-reader-writer";
+
+/// `[write.history]` in the config
+#[derive(Deserialize, Default)]
+pub struct HistoryToml {
+    /// Number of entries kept
+    max_len: Option<usize>,
+    /// Don't add a line that is the same as the one before it
+    dedup: Option<bool>,
+    /// Keeps a separate history per profile. Defaults to the serial port name.
+    profile: Option<String>,
+    /// Directory for the history files, instead of the user's data directory
+    dir: Option<String>,
+}
+
+/// Where and how the write REPL keeps its history
+#[derive(Clone)]
+pub struct HistoryConfig {
+    pub max_len: usize,
+    pub dedup: bool,
+    pub path: PathBuf,
+}
+
+impl HistoryConfig {
+    pub fn from_toml(toml_val: &HistoryToml, serial_port: &str) -> HistoryConfig {
+        let dir = match &toml_val.dir {
+            Some(dir) => PathBuf::from(dir),
+            None => match dirs::data_dir() {
+                Some(data_dir) => data_dir.join(DATA_DIR_NAME).join("history"),
+                None => PathBuf::from("."),
+            },
+        };
+        let profile = toml_val.profile.as_deref().unwrap_or(serial_port);
+
+        HistoryConfig {
+            max_len: toml_val.max_len.unwrap_or(DEFAULT_MAX_LEN),
+            dedup: toml_val.dedup.unwrap_or(true),
+            path: dir.join(format!("{}.txt", file_stem(profile))),
+        }
+    }
+}
+
+/// A file name for a profile or port, e.g. `/dev/ttyUSB0` becomes `dev_ttyUSB0`
+pub fn file_stem(profile: &str) -> String {
+    let stem: String = profile
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '-' || c == '.' => c,
+            _ => '_',
+        })
+        .collect();
+    match stem.trim_matches('_') {
+        "" => String::from("history"),
+        stem => stem.to_string(),
+    }
+}
+
+/// Entries containing `term`, ignoring case, numbered from 1. An empty `term` matches all.
+pub fn search<'h>(history: &'h History, term: &str) -> Vec<(usize, &'h String)> {
+    let term = term.to_lowercase();
+    history
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.to_lowercase().contains(&term))
+        .map(|(index, entry)| (index + 1, entry))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_key_history_by_profile_or_port() {
+        let toml_val = HistoryToml {
+            dir: Some(String::from("/tmp/history")),
+            ..HistoryToml::default()
+        };
+        let history_config = HistoryConfig::from_toml(&toml_val, "/dev/ttyUSB0");
+        assert_eq!(
+            PathBuf::from("/tmp/history/dev_ttyUSB0.txt"),
+            history_config.path
+        );
+        assert_eq!(DEFAULT_MAX_LEN, history_config.max_len);
+
+        let toml_val = HistoryToml {
+            dir: Some(String::from("/tmp/history")),
+            profile: Some(String::from("board-a")),
+            ..HistoryToml::default()
+        };
+        let history_config = HistoryConfig::from_toml(&toml_val, "COM3");
+        assert_eq!(
+            PathBuf::from("/tmp/history/board-a.txt"),
+            history_config.path
+        );
+    }
+
+    #[test]
+    fn should_search_history() {
+        let mut history = History::new();
+        history.add("AT+GMR");
+        history.add("reset");
+        history.add("at+rst");
+
+        let found: Vec<(usize, &String)> = search(&history, "AT+");
+        assert_eq!(
+            vec![(1, &String::from("AT+GMR")), (3, &String::from("at+rst"))],
+            found
+        );
+        assert_eq!(3, search(&history, "").len());
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Width of the longest bar of a histogram
+const HISTOGRAM_WIDTH: usize = 40;
+/// Number of bars of a histogram
+const HISTOGRAM_BUCKETS: u32 = 10;
+
+/// How long a device took to answer a line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Latency {
+    /// Until the first byte of the response arrived
+    pub first_byte: Option<Duration>,
+    /// Until the response was complete
+    pub complete: Duration,
+}
+
+impl Latency {
+    /// `first byte 3.2 ms, complete 45.1 ms`
+    pub fn describe(&self) -> String {
+        match self.first_byte {
+            Some(first_byte) => format!(
+                "first byte {}, complete {}",
+                ms(first_byte),
+                ms(self.complete)
+            ),
+            None => format!("complete {}", ms(self.complete)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Samples {
+    first_byte: Vec<Duration>,
+    complete: Vec<Duration>,
+}
+
+/// Latencies of the completed responses, per custom command or line sent
+#[derive(Default)]
+pub struct LatencyStats {
+    samples: BTreeMap<String, Samples>,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, command: &str, latency: Latency) {
+        let samples = self.samples.entry(command.to_string()).or_default();
+        if let Some(first_byte) = latency.first_byte {
+            samples.first_byte.push(first_byte);
+        }
+        samples.complete.push(latency.complete);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Percentiles of each command, in alphabetical order
+    pub fn report(&self) -> Vec<String> {
+        self.samples
+            .iter()
+            .map(|(command, samples)| {
+                let mut line = format!(
+                    "{}: {} response(s), complete {}",
+                    command,
+                    samples.complete.len(),
+                    percentiles(&samples.complete)
+                );
+                if !samples.first_byte.is_empty() {
+                    line.push_str(&format!(
+                        "; first byte {}",
+                        percentiles(&samples.first_byte)
+                    ));
+                }
+                line
+            })
+            .collect()
+    }
+
+    /// Bars of how many responses to `command` took how long to complete
+    pub fn histogram(&self, command: &str) -> Vec<String> {
+        let complete = match self.samples.get(command) {
+            Some(samples) if !samples.complete.is_empty() => &samples.complete,
+            _ => return Vec::new(),
+        };
+        let min = *complete.iter().min().unwrap();
+        let max = *complete.iter().max().unwrap();
+        let width = ((max - min) / HISTOGRAM_BUCKETS).max(Duration::from_micros(1));
+
+        let mut counts = vec![0usize; HISTOGRAM_BUCKETS as usize];
+        for duration in complete {
+            let bucket =
+                (((*duration - min).as_nanos() / width.as_nanos()) as usize).min(counts.len() - 1);
+            counts[bucket] += 1;
+        }
+        let most = *counts.iter().max().unwrap();
+        counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                let from = min + width * bucket as u32;
+                let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(most));
+                format!(
+                    "{:>10} - {:>10} | {:<width$} {}",
+                    ms(from),
+                    ms(from + width),
+                    bar,
+                    count,
+                    width = HISTOGRAM_WIDTH
+                )
+            })
+            .collect()
+    }
+}
+
+/// `p50 12.1 ms, p90 15.0 ms, p99 30.2 ms, max 31.0 ms`
+fn percentiles(durations: &[Duration]) -> String {
+    let mut sorted = durations.to_vec();
+    sorted.sort();
+    let percentile = |p: usize| {
+        // Nearest rank
+        let rank = (p * sorted.len()).div_ceil(100);
+        sorted[rank.max(1) - 1]
+    };
+    format!(
+        "p50 {}, p90 {}, p99 {}, max {}",
+        ms(percentile(50)),
+        ms(percentile(90)),
+        ms(percentile(99)),
+        ms(sorted[sorted.len() - 1])
+    )
+}
+
+fn ms(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn latency(first_byte_ms: u64, complete_ms: u64) -> Latency {
+        Latency {
+            first_byte: Some(Duration::from_millis(first_byte_ms)),
+            complete: Duration::from_millis(complete_ms),
+        }
+    }
+
+    #[test]
+    fn should_report_percentiles_per_command() {
+        let mut stats = LatencyStats::default();
+        for complete_ms in 1..=100 {
+            stats.record("AT+GMR", latency(2, complete_ms));
+        }
+        stats.record("VERSION", latency(5, 40));
+
+        assert_eq!(
+            vec![
+                String::from(
+                    "AT+GMR: 100 response(s), complete p50 50.0 ms, p90 90.0 ms, p99 99.0 ms, \
+                     max 100.0 ms; first byte p50 2.0 ms, p90 2.0 ms, p99 2.0 ms, max 2.0 ms"
+                ),
+                String::from(
+                    "VERSION: 1 response(s), complete p50 40.0 ms, p90 40.0 ms, p99 40.0 ms, \
+                     max 40.0 ms; first byte p50 5.0 ms, p90 5.0 ms, p99 5.0 ms, max 5.0 ms"
+                ),
+            ],
+            stats.report()
+        );
+        assert_eq!(
+            "first byte 2.0 ms, complete 7.5 ms",
+            Latency {
+                first_byte: Some(Duration::from_millis(2)),
+                complete: Duration::from_micros(7500),
+            }
+            .describe()
+        );
+    }
+
+    #[test]
+    fn should_draw_histogram() {
+        let mut stats = LatencyStats::default();
+        for complete_ms in &[10, 10, 10, 11, 20] {
+            stats.record("PING", latency(1, *complete_ms));
+        }
+
+        let histogram = stats.histogram("PING");
+        assert_eq!(10, histogram.len());
+        assert_eq!(
+            format!(
+                "{:>10} - {:>10} | {:<40} 3",
+                "10.0 ms",
+                "11.0 ms",
+                "#".repeat(40)
+            ),
+            histogram[0]
+        );
+        assert_eq!(
+            format!(
+                "{:>10} - {:>10} | {:<40} 1",
+                "19.0 ms",
+                "20.0 ms",
+                "#".repeat(14)
+            ),
+            histogram[9]
+        );
+        assert_eq!(Vec::<String>::new(), stats.histogram("NOPE"));
+    }
+}
@@ -0,0 +1,246 @@
+use regex::Regex;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+
+/// `[filter]` in the config
+#[derive(Deserialize, Default)]
+pub struct FilterToml {
+    /// Only lines matching one of these are shown, if any are given
+    #[serde(default)]
+    include: Vec<String>,
+    /// Lines matching any of these are hidden
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Hidden lines are still written to the log file
+    #[serde(default)]
+    log_filtered: bool,
+    #[serde(default)]
+    highlight: Vec<HighlightToml>,
+}
+
+/// `[[filter.highlight]]` in the config
+#[derive(Deserialize)]
+struct HighlightToml {
+    pattern: String,
+    color: Option<String>,
+    #[serde(default)]
+    bold: bool,
+}
+
+/// Filter and highlight flags of `read` and `write`, added to the [filter] section
+#[derive(StructOpt, Clone, Default)]
+pub struct FilterArgs {
+    /// Only show received lines matching this regex. Can be given more than once.
+    #[structopt(long = "--include")]
+    pub include: Vec<String>,
+    /// Hide received lines matching this regex. Can be given more than once.
+    #[structopt(long = "--exclude")]
+    pub exclude: Vec<String>,
+    /// Color matches of a regex, e.g. `--highlight "ERROR=red,bold"`.
+    /// Can be given more than once.
+    #[structopt(long = "--highlight")]
+    pub highlight: Vec<String>,
+}
+
+/// Colors parts of a line that match `pattern`
+#[derive(Clone)]
+struct Highlight {
+    pattern: Regex,
+    style: String,
+}
+
+/// Decides which received lines are shown, and highlights them
+#[derive(Clone, Default)]
+pub struct LineFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    highlights: Vec<Highlight>,
+    pub log_filtered: bool,
+}
+
+impl LineFilter {
+    pub fn from_toml(toml_val: &FilterToml) -> Result<LineFilter, String> {
+        let mut filter = LineFilter {
+            include: compile_all(&toml_val.include)?,
+            exclude: compile_all(&toml_val.exclude)?,
+            highlights: Vec::new(),
+            log_filtered: toml_val.log_filtered,
+        };
+        for highlight in &toml_val.highlight {
+            filter.highlights.push(Highlight::new(
+                &highlight.pattern,
+                highlight.color.as_deref(),
+                highlight.bold,
+            )?);
+        }
+        Ok(filter)
+    }
+
+    pub fn from_args(args: &FilterArgs) -> Result<LineFilter, String> {
+        Ok(LineFilter {
+            include: compile_all(&args.include)?,
+            exclude: compile_all(&args.exclude)?,
+            highlights: args
+                .highlight
+                .iter()
+                .map(|spec| Highlight::parse(spec))
+                .collect::<Result<Vec<Highlight>, String>>()?,
+            log_filtered: false,
+        })
+    }
+
+    /// Add the rules of `other`, e.g. from the command line. Its highlights take precedence.
+    pub fn extend(&mut self, other: LineFilter) {
+        self.include.extend(other.include);
+        self.exclude.extend(other.exclude);
+        let highlights = std::mem::take(&mut self.highlights);
+        self.highlights = other.highlights;
+        self.highlights.extend(highlights);
+        self.log_filtered |= other.log_filtered;
+    }
+
+    /// Whether `line` passes the include and exclude patterns
+    pub fn is_shown(&self, line: &str) -> bool {
+        let included =
+            self.include.is_empty() || self.include.iter().any(|regex| regex.is_match(line));
+        included && !self.exclude.iter().any(|regex| regex.is_match(line))
+    }
+
+    /// `text` with the matches of the highlight rules colored. Where matches overlap, the
+    /// first rule wins.
+    pub fn highlight(&self, text: &str) -> String {
+        let mut spans = Vec::<(usize, usize, &str)>::new();
+        for highlight in &self.highlights {
+            for found in highlight.pattern.find_iter(text) {
+                let overlaps = spans
+                    .iter()
+                    .any(|(start, end, _)| found.start() < *end && *start < found.end());
+                if !found.as_str().is_empty() && !overlaps {
+                    spans.push((found.start(), found.end(), &highlight.style));
+                }
+            }
+        }
+        spans.sort_by_key(|(start, _, _)| *start);
+
+        let mut highlighted = String::new();
+        let mut position = 0;
+        for (start, end, style) in spans {
+            highlighted.push_str(&text[position..start]);
+            highlighted.push_str(style);
+            highlighted.push_str(&text[start..end]);
+            highlighted.push_str(RESET);
+            position = end;
+        }
+        highlighted.push_str(&text[position..]);
+        highlighted
+    }
+}
+
+impl Highlight {
+    fn new(pattern: &str, color: Option<&str>, bold: bool) -> Result<Highlight, String> {
+        let mut style = match color {
+            Some(color) => color_code(color)?.to_string(),
+            None => String::new(),
+        };
+        if bold {
+            style.push_str(BOLD);
+        }
+        if style.is_empty() {
+            return Err(format!(
+                "Highlight '{}' has no color, and is not bold",
+                pattern
+            ));
+        }
+        Ok(Highlight {
+            pattern: compile(pattern)?,
+            style,
+        })
+    }
+
+    /// `ERROR=red`, `OK=green,bold` or `WARN=bold`
+    fn parse(spec: &str) -> Result<Highlight, String> {
+        let (pattern, style) = spec
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected PATTERN=COLOR[,bold], got '{}'", spec))?;
+        let mut color = None;
+        let mut bold = false;
+        for part in style.split(',').map(str::trim) {
+            if part.eq_ignore_ascii_case("bold") {
+                bold = true;
+            } else {
+                color = Some(part);
+            }
+        }
+        Highlight::new(pattern, color, bold)
+    }
+}
+
+fn color_code(color: &str) -> Result<&'static str, String> {
+    match color.to_lowercase().as_str() {
+        "red" => Ok("\x1b[31m"),
+        "green" => Ok("\x1b[32m"),
+        "yellow" => Ok("\x1b[33m"),
+        "blue" => Ok("\x1b[34m"),
+        "magenta" => Ok("\x1b[35m"),
+        "cyan" => Ok("\x1b[36m"),
+        "white" => Ok("\x1b[37m"),
+        "gray" | "grey" => Ok("\x1b[90m"),
+        _ => Err(format!("Unknown color '{}'", color)),
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|err| format!("Invalid pattern '{}': {}", pattern, err))
+}
+
+fn compile_all(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns.iter().map(|pattern| compile(pattern)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn args(include: &[&str], exclude: &[&str], highlight: &[&str]) -> FilterArgs {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        FilterArgs {
+            include: strings(include),
+            exclude: strings(exclude),
+            highlight: strings(highlight),
+        }
+    }
+
+    #[test]
+    fn should_include_and_exclude_lines() {
+        let mut filter = LineFilter::from_args(&args(&["^TEMP|^ERR"], &[], &[])).unwrap();
+        filter.extend(LineFilter::from_args(&args(&[], &["DEBUG"], &[])).unwrap());
+
+        assert_eq!(true, filter.is_shown("TEMP 21.5"));
+        assert_eq!(true, filter.is_shown("ERROR 3"));
+        assert_eq!(false, filter.is_shown("heartbeat"));
+        assert_eq!(false, filter.is_shown("TEMP DEBUG"));
+        assert_eq!(true, LineFilter::default().is_shown("anything"));
+        assert!(LineFilter::from_args(&args(&["("], &[], &[])).is_err());
+    }
+
+    #[test]
+    fn should_highlight_matches() {
+        let filter = LineFilter::from_args(&args(
+            &[],
+            &[],
+            &["ERR\\w*=red,bold", "ERROR 4=yellow", "[0-9]+=cyan"],
+        ))
+        .unwrap();
+
+        assert_eq!(
+            "Rx: '\x1b[31m\x1b[1mERROR\x1b[0m \x1b[36m42\x1b[0m'",
+            filter.highlight("Rx: 'ERROR 42'")
+        );
+        assert_eq!("ok", filter.highlight("ok"));
+        assert!(Highlight::parse("OK=purple").is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use crate::input_output::history;
+
+use chrono::{DateTime, Local};
+use regex::Regex;
+use serde::Deserialize;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::SystemTime;
+
+/// `[log]` in the config
+#[derive(Deserialize, Default)]
+pub struct LogToml {
+    /// e.g. `logs/{port}-{date}.log`. Nothing is logged without it.
+    path: Option<String>,
+    /// Start a new file once the current one is this big
+    max_size_kb: Option<u64>,
+    /// "hourly", "daily" or "never"
+    rotate: Option<String>,
+    /// Number of rotated files kept. All are kept without it.
+    keep: Option<usize>,
+    /// "gzip", "zstd" or "none", for rotated files
+    compress: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotate {
+    Hourly,
+    Daily,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Where received data is logged, and when log files are rotated
+#[derive(Clone)]
+pub struct LogConfig {
+    /// With `{date}`, `{time}` and `{port}` placeholders
+    pub path: String,
+    pub max_size: Option<u64>,
+    pub rotate: Option<Rotate>,
+    pub keep: Option<usize>,
+    pub compress: Compression,
+}
+
+impl LogConfig {
+    /// `None` if no `path` is configured
+    pub fn from_toml(toml_val: &LogToml) -> Result<Option<LogConfig>, String> {
+        let path = match &toml_val.path {
+            Some(path) if !path.trim().is_empty() => path.clone(),
+            _ => return Ok(None),
+        };
+        let rotate = match toml_val.rotate.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("never") => None,
+            Some("hourly") => Some(Rotate::Hourly),
+            Some("daily") => Some(Rotate::Daily),
+            Some(value) => return Err(format!("Invalid `rotate` '{}'", value)),
+        };
+        let compress = match toml_val
+            .compress
+            .as_deref()
+            .map(str::to_lowercase)
+            .as_deref()
+        {
+            None | Some("none") => Compression::None,
+            Some("gzip") => Compression::Gzip,
+            Some("zstd") => Compression::Zstd,
+            Some(value) => return Err(format!("Invalid `compress` '{}'", value)),
+        };
+
+        Ok(Some(LogConfig {
+            path,
+            max_size: toml_val.max_size_kb.map(|kb| kb * 1024),
+            rotate,
+            keep: toml_val.keep,
+            compress,
+        }))
+    }
+}
+
+/// A log file that moves on to a new file when it gets too big or a new hour or day
+/// starts. Rotated files are compressed and pruned on a separate thread.
+pub struct LogFile {
+    config: LogConfig,
+    port: String,
+    path: PathBuf,
+    file: LineWriter<File>,
+    size: u64,
+    /// The hour or day the file was opened in, if rotating by time
+    period: String,
+    /// Compressing and pruning the last rotated file
+    housekeeping: Option<thread::JoinHandle<()>>,
+    /// Written at the start of every new file, e.g. CSV column names
+    header: Option<String>,
+    /// Write errors are only reported once
+    failed: bool,
+}
+
+impl LogFile {
+    /// Appends to the file if it already exists
+    pub fn open(config: &LogConfig, port: &str, header: Option<String>) -> io::Result<LogFile> {
+        let now = Local::now();
+        let path = render(&config.path, port, &now);
+        let (file, size) = open_append(&path, header.as_deref())?;
+
+        Ok(LogFile {
+            config: config.clone(),
+            port: port.to_string(),
+            path,
+            file,
+            size,
+            period: period(config.rotate, &now),
+            housekeeping: None,
+            header,
+            failed: false,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) {
+        if let Err(err) = self.try_write_line(line) {
+            if !self.failed {
+                println!("Cannot write to '{}': {}", self.path.display(), err);
+                self.failed = true;
+            }
+        }
+    }
+
+    fn try_write_line(&mut self, line: &str) -> io::Result<()> {
+        let now = Local::now();
+        let too_big = match self.config.max_size {
+            Some(max_size) => self.size >= max_size,
+            None => false,
+        };
+        if too_big || period(self.config.rotate, &now) != self.period {
+            self.rotate(&now)?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: &DateTime<Local>) -> io::Result<()> {
+        self.file.flush()?;
+        let mut rotated = self.path.clone();
+        let path = render(&self.config.path, &self.port, now);
+        if path == rotated {
+            let numbered = numbered(&rotated, next_number(&rotated)?);
+            fs::rename(&rotated, &numbered)?;
+            rotated = numbered;
+        }
+
+        let (file, size) = open_append(&path, self.header.as_deref())?;
+        self.file = file;
+        self.size = size;
+        self.path = path;
+        self.period = period(self.config.rotate, now);
+
+        self.finish_housekeeping();
+        let compress = self.config.compress;
+        let keep = self.config.keep;
+        let pattern = rotated_pattern(&self.config.path, &self.port);
+        let current = self.path.clone();
+        self.housekeeping = Some(thread::spawn(move || {
+            if let Err(err) = compress_file(&rotated, compress) {
+                println!("Cannot compress '{}': {}", rotated.display(), err);
+            }
+            if let Some(keep) = keep {
+                if let Err(err) = prune(dir_of(&current), &pattern, keep, &current) {
+                    println!("Cannot remove old log files: {}", err);
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Write out what is buffered, and wait for a rotated file to be compressed
+    pub fn flush(&mut self) {
+        let _ = self.file.flush();
+        self.finish_housekeeping();
+    }
+
+    fn finish_housekeeping(&mut self) {
+        if let Some(handle) = self.housekeeping.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Fill in the placeholders of the `path` template
+fn render(template: &str, port: &str, now: &DateTime<Local>) -> PathBuf {
+    PathBuf::from(
+        template
+            .replace("{date}", &now.format("%Y-%m-%d").to_string())
+            .replace("{time}", &now.format("%H%M%S").to_string())
+            .replace("{port}", &history::file_stem(port)),
+    )
+}
+
+fn period(rotate: Option<Rotate>, now: &DateTime<Local>) -> String {
+    match rotate {
+        Some(Rotate::Hourly) => now.format("%Y-%m-%d %H").to_string(),
+        Some(Rotate::Daily) => now.format("%Y-%m-%d").to_string(),
+        None => String::new(),
+    }
+}
+
+/// Starts an empty file with `header`
+fn open_append(path: &Path, header: Option<&str>) -> io::Result<(LineWriter<File>, u64)> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut size = file.metadata()?.len();
+    let mut file = LineWriter::new(file);
+    if let (0, Some(header)) = (size, header) {
+        writeln!(file, "{}", header)?;
+        size = header.len() as u64 + 1;
+    }
+    Ok((file, size))
+}
+
+/// The directory of a file, which is `.` for a bare file name
+fn dir_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// A name and extension, split at the last `.` unless a placeholder follows it
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(index) if index > 0 && !name[index..].contains('}') => name.split_at(index),
+        _ => (name, ""),
+    }
+}
+
+/// `capture.log` becomes `capture.2.log`
+fn numbered(path: &Path, number: u32) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (stem, extension) = split_extension(&name);
+    path.with_file_name(format!("{}.{}{}", stem, number, extension))
+}
+
+/// One more than the highest number of any file rotated from `path`
+fn next_number(path: &Path) -> io::Result<u32> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (stem, extension) = split_extension(&name);
+    let pattern = Regex::new(&format!(
+        r"^{}\.(\d+){}(\.gz|\.zst)?$",
+        regex::escape(stem),
+        regex::escape(extension)
+    ))
+    .unwrap();
+
+    let mut highest = 0;
+    for entry in fs::read_dir(dir_of(path))? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Some(captures) = pattern.captures(&name) {
+            highest = highest.max(captures[1].parse::<u32>().unwrap_or(0));
+        }
+    }
+    Ok(highest + 1)
+}
+
+fn with_extension_added(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Matches the names of files rotated from the `path` template for `port`, compressed or
+/// not. Logs of other ports sharing the template are left alone.
+fn rotated_pattern(template: &str, port: &str) -> Regex {
+    let name = Path::new(template)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (stem, extension) = split_extension(&name);
+    let stem = regex::escape(stem)
+        .replace(r"\{date\}", r"\d{4}-\d{2}-\d{2}")
+        .replace(r"\{time\}", r"\d{6}")
+        .replace(r"\{port\}", &regex::escape(&history::file_stem(port)));
+
+    Regex::new(&format!(
+        r"^{}(\.\d+)?{}(\.gz|\.zst)?$",
+        stem,
+        regex::escape(extension)
+    ))
+    .unwrap()
+}
+
+/// Replace the file with a compressed copy
+fn compress_file(path: &Path, compression: Compression) -> io::Result<()> {
+    let mut input = File::open(path)?;
+    match compression {
+        Compression::None => return Ok(()),
+        Compression::Gzip => {
+            let output = File::create(with_extension_added(path, "gz"))?;
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
+        }
+        Compression::Zstd => {
+            let output = File::create(with_extension_added(path, "zst"))?;
+            zstd::stream::copy_encode(input, output, 0)?;
+        }
+    }
+    fs::remove_file(path)
+}
+
+/// Remove all but the newest `keep` rotated files
+fn prune(dir: &Path, pattern: &Regex, keep: usize, current: &Path) -> io::Result<()> {
+    let mut rotated = Vec::<(SystemTime, PathBuf)>::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if path != current && pattern.is_match(&name) {
+            rotated.push((fs::metadata(&path)?.modified()?, path));
+        }
+    }
+
+    rotated.sort_by(|a, b| b.cmp(a));
+    for (_, path) in rotated.into_iter().skip(keep) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+
+    #[test]
+    fn should_name_log_files_from_template() {
+        let now = Local.with_ymd_and_hms(2024, 3, 9, 14, 5, 0).unwrap();
+        assert_eq!(
+            PathBuf::from("logs/dev_ttyUSB0-2024-03-09-140500.log"),
+            render("logs/{port}-{date}-{time}.log", "/dev/ttyUSB0", &now)
+        );
+        assert_eq!(
+            PathBuf::from("logs/COM3-2024-03-09.3.log"),
+            numbered(Path::new("logs/COM3-2024-03-09.log"), 3)
+        );
+
+        let pattern = rotated_pattern("logs/{port}-{date}.log", "COM3");
+        assert!(pattern.is_match("COM3-2024-03-09.log"));
+        assert!(!pattern.is_match("COM4-2024-03-09.log"));
+        assert!(!pattern.is_match("COM33-2024-03-09.log"));
+        assert!(pattern.is_match("COM3-2024-03-09.3.log.gz"));
+        assert!(pattern.is_match("COM3-2024-03-09.log.zst"));
+        assert!(!pattern.is_match("notes.txt"));
+        assert!(!pattern.is_match("COM3-2024-03-09.log.bak"));
+    }
+
+    #[test]
+    fn should_rotate_compress_and_prune() {
+        let dir = std::env::temp_dir().join(format!("serial-log-test-{}", std::process::id()));
+        let config = LogConfig {
+            path: format!("{}/capture-{{port}}.log", dir.display()),
+            max_size: Some(100),
+            rotate: None,
+            keep: Some(2),
+            compress: Compression::Gzip,
+        };
+
+        {
+            let mut log_file = LogFile::open(&config, "COM1", None).unwrap();
+            for number in 0..20 {
+                log_file.write_line(&format!("line {:02} {}", number, "x".repeat(40)));
+            }
+        }
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            vec![
+                "capture-COM1.5.log.gz",
+                "capture-COM1.6.log.gz",
+                "capture-COM1.log"
+            ],
+            names
+        );
+
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(File::open(dir.join("capture-COM1.6.log.gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert!(text.starts_with("line 15 "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use crate::parse_config::ParseConfig;
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use std::thread;
+use std::time::Duration;
+
+/// REPL commands that change or show the port settings, with their descriptions
+pub const META_COMMANDS: &[(&str, &str)] = &[
+    (":baud", "Set the baud rate, e.g. `:baud 115200`"),
+    (":databits", "Set the data bits: 5, 6, 7 or 8"),
+    (":parity", "Set the parity: none, odd or even"),
+    (":stopbits", "Set the stop bits: 1 or 2"),
+    (":flow", "Set the flow control: none, software or hardware"),
+    (":dtr", "Set Data Terminal Ready: on or off"),
+    (":rts", "Set Request To Send: on or off"),
+    (
+        ":break",
+        "Send a break for N milliseconds, e.g. `:break 250`",
+    ),
+    (
+        ":flush",
+        "Discard everything in the input and output buffers",
+    ),
+    (
+        ":reconnect",
+        "Close and reopen the port, keeping the current settings",
+    ),
+    (":status", "Show the port settings and control lines"),
+];
+
+/// A REPL command starting with `:` that acts on the port instead of sending data
+#[derive(Debug, PartialEq)]
+pub enum MetaCommand {
+    Baud(u32),
+    DataBits(DataBits),
+    Parity(Parity),
+    StopBits(StopBits),
+    FlowControl(FlowControl),
+    Dtr(bool),
+    Rts(bool),
+    Break(Duration),
+    Flush,
+    Reconnect,
+    Status,
+}
+
+impl MetaCommand {
+    /// `None` if `input` is not one of the `META_COMMANDS`
+    pub fn parse(input: &str) -> Option<Result<MetaCommand, String>> {
+        let mut words = input.split_whitespace();
+        let name = words.next()?.to_lowercase();
+        if !META_COMMANDS.iter().any(|(known, _)| *known == name) {
+            return None;
+        }
+        let value = words.next().unwrap_or("");
+
+        let invalid = || format!("Invalid value '{}' for {}", value, name);
+        let meta_command = match name.as_str() {
+            ":baud" => value
+                .parse::<u32>()
+                .map(MetaCommand::Baud)
+                .map_err(|_| invalid()),
+            ":databits" => value
+                .parse::<u32>()
+                .ok()
+                .and_then(ParseConfig::data_bits_from)
+                .map(MetaCommand::DataBits)
+                .ok_or_else(invalid),
+            ":parity" => ParseConfig::parity_from(value)
+                .map(MetaCommand::Parity)
+                .ok_or_else(invalid),
+            ":stopbits" => value
+                .parse::<u32>()
+                .ok()
+                .and_then(ParseConfig::stop_bits_from)
+                .map(MetaCommand::StopBits)
+                .ok_or_else(invalid),
+            ":flow" => ParseConfig::flow_control_from(value)
+                .map(MetaCommand::FlowControl)
+                .ok_or_else(invalid),
+            ":dtr" => parse_on_off(value)
+                .map(MetaCommand::Dtr)
+                .ok_or_else(invalid),
+            ":rts" => parse_on_off(value)
+                .map(MetaCommand::Rts)
+                .ok_or_else(invalid),
+            ":break" => value
+                .parse::<u64>()
+                .map(|millis| MetaCommand::Break(Duration::from_millis(millis)))
+                .map_err(|_| invalid()),
+            ":flush" => Ok(MetaCommand::Flush),
+            ":reconnect" => Ok(MetaCommand::Reconnect),
+            _ => Ok(MetaCommand::Status),
+        };
+        Some(meta_command)
+    }
+
+    /// Apply a setting to the port. `Reconnect` and `Status` don't change anything here.
+    pub fn apply(&self, serial_port: &mut Box<dyn SerialPort>) -> serialport::Result<()> {
+        match self {
+            MetaCommand::Baud(baud_rate) => serial_port.set_baud_rate(*baud_rate),
+            MetaCommand::DataBits(data_bits) => serial_port.set_data_bits(*data_bits),
+            MetaCommand::Parity(parity) => serial_port.set_parity(*parity),
+            MetaCommand::StopBits(stop_bits) => serial_port.set_stop_bits(*stop_bits),
+            MetaCommand::FlowControl(flow_control) => serial_port.set_flow_control(*flow_control),
+            MetaCommand::Dtr(level) => serial_port.write_data_terminal_ready(*level),
+            MetaCommand::Rts(level) => serial_port.write_request_to_send(*level),
+            MetaCommand::Break(duration) => {
+                serial_port.set_break()?;
+                thread::sleep(*duration);
+                serial_port.clear_break()
+            }
+            MetaCommand::Flush => serial_port.clear(ClearBuffer::All),
+            MetaCommand::Reconnect | MetaCommand::Status => Ok(()),
+        }
+    }
+}
+
+/// Line settings of a port, kept while it is reopened
+pub struct PortSettings {
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    flow_control: FlowControl,
+    /// DTR and RTS cannot be read back from the port, so they are only restored if known
+    dtr: Option<bool>,
+    rts: Option<bool>,
+}
+
+impl PortSettings {
+    pub fn read(serial_port: &dyn SerialPort) -> serialport::Result<PortSettings> {
+        Ok(PortSettings {
+            baud_rate: serial_port.baud_rate()?,
+            data_bits: serial_port.data_bits()?,
+            parity: serial_port.parity()?,
+            stop_bits: serial_port.stop_bits()?,
+            flow_control: serial_port.flow_control()?,
+            dtr: None,
+            rts: None,
+        })
+    }
+
+    /// Also restore these levels of DTR and RTS, if set
+    pub fn with_output_lines(mut self, dtr: Option<bool>, rts: Option<bool>) -> Self {
+        self.dtr = dtr;
+        self.rts = rts;
+        self
+    }
+
+    pub fn apply(&self, serial_port: &mut Box<dyn SerialPort>) -> serialport::Result<()> {
+        serial_port.set_baud_rate(self.baud_rate)?;
+        serial_port.set_data_bits(self.data_bits)?;
+        serial_port.set_parity(self.parity)?;
+        serial_port.set_stop_bits(self.stop_bits)?;
+        serial_port.set_flow_control(self.flow_control)?;
+        if let Some(dtr) = self.dtr {
+            serial_port.write_data_terminal_ready(dtr)?;
+        }
+        if let Some(rts) = self.rts {
+            serial_port.write_request_to_send(rts)?;
+        }
+        Ok(())
+    }
+}
+
+/// The current settings and input control lines of the port
+pub fn port_status(serial_port: &mut Box<dyn SerialPort>) -> String {
+    fn or_unknown<T: ToString>(value: serialport::Result<T>) -> String {
+        value
+            .map(|value| value.to_string())
+            .unwrap_or_else(|_| String::from("?"))
+    }
+    fn on_off(value: serialport::Result<bool>) -> &'static str {
+        match value {
+            Ok(true) => "on",
+            Ok(false) => "off",
+            Err(_) => "?",
+        }
+    }
+
+    let name = serial_port.name().unwrap_or_else(|| String::from("?"));
+    let settings = format!(
+        "Port: '{}'\n  Baud rate: {}  Data bits: {}  Parity: {}  Stop bits: {}  Flow control: {}",
+        name,
+        or_unknown(serial_port.baud_rate()),
+        or_unknown(serial_port.data_bits()),
+        or_unknown(serial_port.parity()),
+        or_unknown(serial_port.stop_bits()),
+        or_unknown(serial_port.flow_control()),
+    );
+    let lines = format!(
+        "  CTS: {}  DSR: {}  RI: {}  CD: {}",
+        on_off(serial_port.read_clear_to_send()),
+        on_off(serial_port.read_data_set_ready()),
+        on_off(serial_port.read_ring_indicator()),
+        on_off(serial_port.read_carrier_detect()),
+    );
+    format!("{}\n{}", settings, lines)
+}
+
+fn parse_on_off(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "1" | "true" | "high" => Some(true),
+        "off" | "0" | "false" | "low" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_meta_commands() {
+        assert_eq!(
+            Some(Ok(MetaCommand::Baud(115200))),
+            MetaCommand::parse(":baud 115200")
+        );
+        assert_eq!(
+            Some(Ok(MetaCommand::Parity(Parity::Even))),
+            MetaCommand::parse(":PARITY even")
+        );
+        assert_eq!(
+            Some(Ok(MetaCommand::Dtr(false))),
+            MetaCommand::parse(":dtr off")
+        );
+        assert_eq!(
+            Some(Err(String::from("Invalid value 'fast' for :baud"))),
+            MetaCommand::parse(":baud fast")
+        );
+        assert_eq!(None, MetaCommand::parse(":hex 02"));
+        assert_eq!(None, MetaCommand::parse("AT"));
+    }
+}
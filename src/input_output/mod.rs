@@ -1,2 +1,22 @@
+pub mod background_reader;
+pub mod command_steps;
+pub mod control_lines;
+pub mod escape;
+pub mod history;
+pub mod latency;
+pub mod line_filter;
+pub mod log_file;
+pub mod meta_command;
+pub mod output_format;
 pub mod read_serial;
+pub mod repl_helper;
+pub mod response;
+pub mod scheduler;
+pub mod shutdown;
+pub mod stats;
+pub mod stop_condition;
+pub mod terminal_mode;
+pub mod timestamp;
+pub mod trigger;
+pub mod tx_format;
 pub mod write_serial;
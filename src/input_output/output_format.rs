@@ -0,0 +1,357 @@
+use crate::input_output::timestamp::{Clock, Precision, Stamp, TimestampConfig};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Columns when `columns` is not set
+const DEFAULT_COLUMNS: &[Column] = &[
+    Column::Timestamp,
+    Column::DeltaMs,
+    Column::Direction,
+    Column::Port,
+    Column::Hex,
+    Column::Text,
+];
+
+/// `[output]` in the config
+#[derive(Deserialize, Default)]
+pub struct OutputToml {
+    /// "text", "jsonl" or "csv"
+    format: Option<String>,
+    /// How JSON Lines records carry the raw bytes: "hex" or "base64"
+    raw: Option<String>,
+    /// CSV columns, in order
+    columns: Option<Vec<String>>,
+    /// "datetime", "iso8601", "monotonic", "epoch" or "none"
+    timestamp: Option<String>,
+    /// "s", "ms", "us" or "ns"
+    precision: Option<String>,
+    /// "local" or "utc"
+    time_zone: Option<String>,
+    /// Time since the "previous" line, the "first" line, the last "transmit", or "none"
+    delta: Option<String>,
+}
+
+/// The [output] section
+#[derive(Clone)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+    pub timestamps: TimestampConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    Timestamp,
+    DeltaMs,
+    Direction,
+    Port,
+    Hex,
+    Base64,
+    Text,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// `[timestamp delta] Rx: '...'`, for people
+    Text,
+    /// One JSON object per line, with the raw bytes as hex or base64
+    JsonLines {
+        base64: bool,
+    },
+    Csv(Vec<Column>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Rx,
+    Tx,
+    /// A change of the control lines, e.g. `DSR off`
+    Event,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta_ms: Option<Value>,
+    direction: &'a str,
+    port: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base64: Option<String>,
+    text: &'a str,
+}
+
+impl Column {
+    fn parse(name: &str) -> Option<Column> {
+        match name.to_lowercase().as_str() {
+            "timestamp" => Some(Column::Timestamp),
+            "delta_ms" => Some(Column::DeltaMs),
+            "direction" => Some(Column::Direction),
+            "port" => Some(Column::Port),
+            "hex" => Some(Column::Hex),
+            "base64" => Some(Column::Base64),
+            "text" => Some(Column::Text),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Column::Timestamp => "timestamp",
+            Column::DeltaMs => "delta_ms",
+            Column::Direction => "direction",
+            Column::Port => "port",
+            Column::Hex => "hex",
+            Column::Base64 => "base64",
+            Column::Text => "text",
+        }
+    }
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+            Direction::Event => "event",
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Records are timestamped in ISO 8601 with milliseconds, and text in the local time,
+    /// unless `timestamp` says otherwise
+    pub fn from_toml(toml_val: &OutputToml) -> Result<OutputConfig, String> {
+        let format = toml_val.format.as_deref().unwrap_or("text");
+        let format =
+            OutputFormat::parse(format, toml_val.raw.as_deref(), toml_val.columns.as_deref())?;
+        let style = match (&toml_val.timestamp, &format) {
+            (Some(style), _) => Some(style.as_str()),
+            (None, OutputFormat::Text) => None,
+            (None, _) => Some("iso8601"),
+        };
+        let timestamps = TimestampConfig::parse(
+            style,
+            toml_val.precision.as_deref(),
+            toml_val.time_zone.as_deref(),
+            toml_val.delta.as_deref(),
+        )?;
+
+        Ok(OutputConfig { format, timestamps })
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            format: OutputFormat::Text,
+            timestamps: TimestampConfig::default(),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// `format` as given in the config or on the command line
+    pub fn parse(
+        format: &str,
+        raw: Option<&str>,
+        columns: Option<&[String]>,
+    ) -> Result<OutputFormat, String> {
+        match format.to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "jsonl" | "json" => match raw.map(str::to_lowercase).as_deref() {
+                None | Some("hex") => Ok(OutputFormat::JsonLines { base64: false }),
+                Some("base64") => Ok(OutputFormat::JsonLines { base64: true }),
+                Some(raw) => Err(format!("Invalid `raw` '{}'", raw)),
+            },
+            "csv" => match columns {
+                Some(columns) => columns
+                    .iter()
+                    .map(|name| {
+                        Column::parse(name).ok_or_else(|| format!("Unknown column '{}'", name))
+                    })
+                    .collect::<Result<Vec<Column>, String>>()
+                    .map(OutputFormat::Csv),
+                None => Ok(OutputFormat::Csv(DEFAULT_COLUMNS.to_vec())),
+            },
+            _ => Err(format!("Invalid `format` '{}'", format)),
+        }
+    }
+
+    /// Printed before the first record
+    pub fn header(&self) -> Option<String> {
+        match self {
+            OutputFormat::Csv(columns) => Some(
+                columns
+                    .iter()
+                    .map(|column| column.name())
+                    .collect::<Vec<&str>>()
+                    .join(","),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Turns sent and received data into records, keeping track of the time between them.
+/// In `Text` format there are no records; callers print their usual text instead.
+pub struct OutputFormatter {
+    format: OutputFormat,
+    clock: Clock,
+    port: String,
+}
+
+impl OutputFormatter {
+    pub fn new(config: &OutputConfig, port: &str) -> Self {
+        OutputFormatter {
+            format: config.format.clone(),
+            clock: Clock::new(config.timestamps.clone()),
+            port: port.to_string(),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    /// `None` in `Text` format
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> Option<String> {
+        if self.is_text() {
+            return None;
+        }
+        let stamp = self.clock.stamp(direction);
+        self.record_at(stamp, direction, bytes)
+    }
+
+    /// Timestamp and time since the chosen line, for text output
+    pub fn text_prefix(&mut self, direction: Direction) -> String {
+        self.clock.text_prefix(direction)
+    }
+
+    fn record_at(&self, stamp: Stamp, direction: Direction, bytes: &[u8]) -> Option<String> {
+        let delta_ms = stamp.delta.map(|delta| {
+            let millis = delta.as_secs_f64() * 1000.0;
+            match self.clock.precision() {
+                Precision::Seconds | Precision::Millis => Value::from(delta.as_millis() as u64),
+                _ => Value::from(millis),
+            }
+        });
+        let timestamp = stamp.timestamp;
+        let decoded = String::from_utf8_lossy(bytes);
+        let text = decoded.trim_end_matches(&['\r', '\n'][..]);
+
+        match &self.format {
+            OutputFormat::Text => None,
+            OutputFormat::JsonLines { base64 } => {
+                let record = JsonRecord {
+                    timestamp,
+                    delta_ms,
+                    direction: direction.name(),
+                    port: &self.port,
+                    hex: if *base64 { None } else { Some(hex(bytes)) },
+                    base64: if *base64 {
+                        Some(base64::encode(bytes))
+                    } else {
+                        None
+                    },
+                    text,
+                };
+                Some(serde_json::to_string(&record).expect("Cannot serialize record"))
+            }
+            OutputFormat::Csv(columns) => {
+                let fields: Vec<String> = columns
+                    .iter()
+                    .map(|column| match column {
+                        Column::Timestamp => timestamp.clone().unwrap_or_default(),
+                        Column::DeltaMs => {
+                            delta_ms.as_ref().map(Value::to_string).unwrap_or_default()
+                        }
+                        Column::Direction => direction.name().to_string(),
+                        Column::Port => csv_field(&self.port),
+                        Column::Hex => hex(bytes),
+                        Column::Base64 => base64::encode(bytes),
+                        Column::Text => csv_field(text),
+                    })
+                    .collect();
+                Some(fields.join(","))
+            }
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Quoted if it contains a comma, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn stamp(delta_ms: u64) -> Stamp {
+        Stamp {
+            timestamp: Some(String::from("2024-03-09T14:05:00.000+01:00")),
+            delta: Some(Duration::from_millis(delta_ms)),
+        }
+    }
+
+    fn formatter(format: OutputFormat) -> OutputFormatter {
+        let config = OutputConfig {
+            format,
+            ..OutputConfig::default()
+        };
+        OutputFormatter::new(&config, "COM3")
+    }
+
+    #[test]
+    fn should_format_json_lines() {
+        let formatter = formatter(OutputFormat::JsonLines { base64: false });
+        let line = formatter
+            .record_at(stamp(12), Direction::Rx, b"OK \"1\"\r\n")
+            .unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(12, value["delta_ms"]);
+        assert_eq!("rx", value["direction"]);
+        assert_eq!("COM3", value["port"]);
+        assert_eq!("4f4b202231220d0a", value["hex"]);
+        assert_eq!("OK \"1\"", value["text"]);
+        assert_eq!(None, value.get("base64"));
+        assert_eq!("2024-03-09T14:05:00.000+01:00", value["timestamp"]);
+    }
+
+    #[test]
+    fn should_format_csv_with_chosen_columns() {
+        let columns = vec![
+            String::from("direction"),
+            String::from("delta_ms"),
+            String::from("base64"),
+            String::from("text"),
+        ];
+        let format = OutputFormat::parse("csv", None, Some(&columns)).unwrap();
+        assert_eq!(
+            Some(String::from("direction,delta_ms,base64,text")),
+            format.header()
+        );
+
+        let formatter = formatter(format);
+        assert_eq!(
+            Some(String::from("tx,5,YSxi,\"a,b\"")),
+            formatter.record_at(stamp(5), Direction::Tx, b"a,b")
+        );
+        assert!(OutputFormat::parse("csv", None, Some(&[String::from("bogus")])).is_err());
+    }
+}
@@ -1,10 +1,29 @@
-use std::time::Instant;
-
+use std::io::{self, ErrorKind, IsTerminal as _, Write as _};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::custom_commands::CustomCommands;
+use crate::input_output::background_reader::RxLine;
+use crate::input_output::control_lines::{self, LineWatcher};
+use crate::input_output::line_filter::LineFilter;
+use crate::input_output::log_file::LogFile;
+use crate::input_output::output_format::{Direction, OutputFormatter};
+use crate::input_output::shutdown;
+use crate::input_output::stats::{self, SessionStats, StatsFormat};
+use crate::input_output::stop_condition::{ReadOutcome, StopArgs, StopConditions};
+use crate::input_output::trigger::{self, Fired, TriggerAction};
+use crate::input_output::tx_format;
+use crate::parse_config::{ParseConfig, ParsedTomlValues};
+use crate::script::rhai_script::{RhaiScript, ScriptContext};
 use crate::serial_port::serial_port_open::SerialPortOpen;
-use chrono::{DateTime, Local};
 use serialport::SerialPort;
 
 const READ_TIMEOUT_SECONDS: u64 = 5;
+/// `line_hook` runs for every line, so a script stuck in a loop is stopped instead of
+/// hanging the session
+const MAX_LINE_HOOK_OPERATIONS: u64 = 1_000_000;
 
 pub trait IReadSerial {
     fn read_serial_line(&self, serial_port: &mut Box<dyn SerialPort>) -> Result<String, ReadError>;
@@ -14,22 +33,52 @@ pub trait IReadSerial {
 pub enum ReadError {
     Timeout,
     NoResponse,
+    /// The port failed, e.g. it was unplugged
+    Port(String),
 }
 
 pub struct ReadSerial<'a> {
     config_file_name: &'a str,
+    filter: LineFilter,
+    stop_args: StopArgs,
+    stats_format: StatsFormat,
+    watch_lines: bool,
 }
 
 impl<'a> IReadSerial for ReadSerial<'a> {
     fn read_serial_line(&self, serial_port: &mut Box<dyn SerialPort>) -> Result<String, ReadError> {
+        let deadline = Instant::now() + Duration::from_secs(READ_TIMEOUT_SECONDS);
+        self.read_line_before(serial_port, deadline, &mut |_| {})
+    }
+}
+
+impl<'a> ReadSerial<'a> {
+    /// Read a line, giving up at `deadline`. `on_poll` is called after every read of the
+    /// port, even if nothing was received.
+    fn read_line_before(
+        &self,
+        serial_port: &mut Box<dyn SerialPort>,
+        deadline: Instant,
+        on_poll: &mut dyn FnMut(&mut dyn SerialPort),
+    ) -> Result<String, ReadError> {
         let mut result = String::new();
         let mut buffer: [u8; 256] = [0; 256];
         let mut is_carriage_return_char = false;
-        let start_time = Instant::now();
         let mut timed_out = false;
 
         loop {
-            let bytes_read = serial_port.read(&mut buffer).unwrap_or(0);
+            if shutdown::requested() {
+                timed_out = true;
+                break;
+            }
+            let bytes_read = match serial_port.read(&mut buffer) {
+                Ok(bytes_read) => bytes_read,
+                Err(error) => match error.kind() {
+                    ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted => 0,
+                    _ => return Err(ReadError::Port(error.to_string())),
+                },
+            };
+            on_poll(serial_port.as_mut());
             if bytes_read > 0 {
                 for i in 0..bytes_read {
                     let c1 = buffer[i] as char;
@@ -40,7 +89,7 @@ impl<'a> IReadSerial for ReadSerial<'a> {
                 }
             }
 
-            if start_time.elapsed().as_secs() >= READ_TIMEOUT_SECONDS {
+            if Instant::now() >= deadline {
                 timed_out = true;
                 break;
             }
@@ -58,32 +107,283 @@ impl<'a> IReadSerial for ReadSerial<'a> {
             Ok(result)
         }
     }
-}
 
-impl<'a> ReadSerial<'a> {
     pub fn new(config_file_name: &'a str) -> ReadSerial {
-        ReadSerial { config_file_name }
+        ReadSerial {
+            config_file_name,
+            filter: LineFilter::default(),
+            stop_args: StopArgs::default(),
+            stats_format: StatsFormat::default(),
+            watch_lines: false,
+        }
     }
 
-    pub fn execute(&self) {
-        let serial_port_results = SerialPortOpen::get_serial_port(self.config_file_name);
-        let mut serial_port = serial_port_results.serial_port;
-        let mut start_time_ms = Local::now().timestamp_millis();
+    /// End the session on its own, e.g. after `--max-lines`
+    pub fn with_stop_args(mut self, stop_args: StopArgs) -> Self {
+        self.stop_args = stop_args;
+        self
+    }
 
-        loop {
+    /// Record changes of the control lines, as well as when `watch_lines` is set in [read]
+    pub fn with_watch_lines(mut self, watch_lines: bool) -> Self {
+        self.watch_lines = watch_lines;
+        self
+    }
+
+    /// How the statistics are printed when the session ends
+    pub fn with_stats_format(mut self, stats_format: StatsFormat) -> Self {
+        self.stats_format = stats_format;
+        self
+    }
+
+    /// Filter and highlight with these as well as the [filter] section
+    pub fn with_filter(mut self, filter: LineFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Read until Ctrl + C, a stop condition is met, or a trigger stops the session.
+    /// Returns the exit code.
+    pub fn execute(&self) -> i32 {
+        let mut config = ParseConfig::get_config(self.config_file_name);
+        config.filter.extend(self.filter.clone());
+        if !config.triggers.runs().is_empty() {
+            panic!("Invalid [[trigger]]: `run` needs custom commands, which only `write` mode has");
+        }
+        let stats = Arc::new(Mutex::new(SessionStats::new()));
+
+        let mut serial_port = match SerialPortOpen::try_open_port(&config) {
+            Ok(serial_port_results) => serial_port_results.serial_port,
+            Err(error) => {
+                eprintln!("Cannot open '{}': {}", config.serial_port, error);
+                return self.end_session(ReadOutcome::PortError, &stats.lock().unwrap(), None);
+            }
+        };
+        println!("Opening serial port: '{}'", config.serial_port);
+        // Not on stdout, which may be piped as JSON Lines or CSV
+        eprintln!("--- Press Ctrl + C to end the session ---");
+        stats::report_on_signal(Arc::clone(&stats), self.stats_format);
+        let mut line_hook = config
+            .read
+            .line_hook
+            .as_ref()
+            .map(|path| self.load_line_hook(path, serial_port.as_ref(), &config));
+        let header = config.output.format.header();
+        let mut log_file = config.log.as_ref().map(|log_config| {
+            LogFile::open(log_config, &config.serial_port, header.clone())
+                .unwrap_or_else(|err| panic!("Cannot open log file: {}", err))
+        });
+        let mut formatter = OutputFormatter::new(&config.output, &config.serial_port);
+        // No colors in records, files and pipes
+        let highlight = formatter.is_text() && io::stdout().is_terminal();
+        if let Some(header) = &header {
+            println!("{}", header);
+        }
+        let mut triggers = config.triggers.clone();
+        let mut exit_code = None;
+        let mut stop_conditions = StopConditions::new(self.stop_args.clone());
+        let mut line_watcher = (self.watch_lines || config.read.watch_lines).then(LineWatcher::new);
+        if line_watcher.is_some() {
+            // Reads return after the port's timeout when nothing arrives, so shorten it to see
+            // short changes of the lines in between
+            let poll_interval = Duration::from_millis(control_lines::POLL_INTERVAL_MS);
+            if let Err(error) =
+                serial_port.set_timeout(poll_interval.min(config.timeout_in_milliseconds))
+            {
+                eprintln!("Cannot watch the control lines closely: {}", error);
+            }
+        }
+
+        let outcome = loop {
+            if shutdown::requested() {
+                break ReadOutcome::Interrupted;
+            }
+            if let Some(outcome) = stop_conditions.expired() {
+                break outcome;
+            }
+            let deadline =
+                stop_conditions.limit(Instant::now() + Duration::from_secs(READ_TIMEOUT_SECONDS));
+            // Control line changes are recorded as they happen, even mid-line
+            let mut on_poll = |serial_port: &mut dyn SerialPort| {
+                let event = match &mut line_watcher {
+                    Some(line_watcher) => line_watcher.poll(serial_port),
+                    None => None,
+                };
+                if let Some(event) = event {
+                    let output = formatter
+                        .record(Direction::Event, event.as_bytes())
+                        .unwrap_or_else(|| {
+                            let prefix = formatter.text_prefix(Direction::Event);
+                            format!("{}Control lines: {}", prefix, event)
+                        });
+                    println!("{}", output);
+                    if let Some(log_file) = &mut log_file {
+                        log_file.write_line(&output);
+                    }
+                }
+            };
+            let line_read = match self.read_line_before(&mut serial_port, deadline, &mut on_poll) {
+                Ok(line_read) => Some(line_read),
+                Err(ReadError::Port(error)) => {
+                    eprintln!("Cannot read '{}': {}", config.serial_port, error);
+                    break ReadOutcome::PortError;
+                }
+                Err(ReadError::Timeout)
+                    if !shutdown::requested() && stop_conditions.expired().is_none() =>
+                {
+                    stats.lock().unwrap().timed_out();
+                    None
+                }
+                Err(_) => None,
+            };
             // On error, don't print anything.
             // Only print when lines are actually read
-            if let Ok(line_read) = self.read_serial_line(&mut serial_port) {
-                let now: DateTime<Local> = Local::now();
-                let timestamp = now.format("%Y-%m-%d %H:%M:%S");
-                let now_ms = now.timestamp_millis();
-                let delta_ms = now_ms - start_time_ms;
-                start_time_ms = now_ms;
+            if let Some(line_read) = line_read {
+                // Each char was made from one byte
+                let bytes: Vec<u8> = line_read.chars().map(|c| c as u8).collect();
+                {
+                    let mut stats = stats.lock().unwrap();
+                    stats.received(bytes.len());
+                    if std::str::from_utf8(&bytes).is_err() {
+                        stats.decode_error();
+                    }
+                }
+                let line = line_read.trim_end_matches(&['\r', '\n'][..]);
+                let mut hidden_by_hook = false;
+                if let Some(line_hook) = &mut line_hook {
+                    match line_hook.on_line(line) {
+                        Ok(true) => {}
+                        Ok(false) => hidden_by_hook = true,
+                        Err(error) => println!("{}", error),
+                    }
+                }
+                let shown = !hidden_by_hook && config.filter.is_shown(line);
+
+                if shown || (!hidden_by_hook && config.filter.log_filtered) {
+                    let output = formatter.record(Direction::Rx, &bytes).unwrap_or_else(|| {
+                        let line_read = line_read.replace("\n", "\\n").replace("\r", "\\r");
+                        let prefix = formatter.text_prefix(Direction::Rx);
+                        format!("{}Rx: '{}'", prefix, line_read)
+                    });
+                    if shown && highlight {
+                        println!("{}", config.filter.highlight(&output));
+                    } else if shown {
+                        println!("{}", output);
+                    }
+                    if let Some(log_file) = &mut log_file {
+                        log_file.write_line(&output);
+                    }
+                }
+
+                // Lines hidden by the hook or a filter still fire triggers
+                let mut stopped_by_trigger = false;
+                for fired in triggers.check(line) {
+                    stopped_by_trigger |= self.fire_trigger(
+                        &fired,
+                        &mut serial_port,
+                        &config,
+                        &mut stats.lock().unwrap(),
+                        &mut exit_code,
+                    );
+                }
+                if stopped_by_trigger {
+                    break ReadOutcome::Triggered;
+                }
+                if let Some(outcome) = stop_conditions.on_line(line) {
+                    break outcome;
+                }
+            }
+        };
 
-                let line_read = line_read.replace("\n", "\\n").replace("\r", "\\r");
-                println!("[{} {:04}ms] Rx: '{}'", timestamp, delta_ms, line_read);
+        // Flushes it, and waits for a rotated file to be compressed
+        drop(log_file);
+        let stats = stats.lock().unwrap();
+        self.end_session(outcome, &stats, exit_code)
+    }
+
+    /// Say why the session ended, and summarize it, on stderr as stdout may be piped.
+    /// Returns the exit code.
+    fn end_session(
+        &self,
+        outcome: ReadOutcome,
+        stats: &SessionStats,
+        trigger_exit_code: Option<i32>,
+    ) -> i32 {
+        eprintln!("--- Stopped: {} ---", outcome.describe());
+        if let Some(report) = stats.report(self.stats_format) {
+            eprintln!("{}", report);
+        }
+        match outcome {
+            ReadOutcome::Interrupted | ReadOutcome::PortError => outcome.exit_code(),
+            _ => trigger_exit_code.unwrap_or_else(|| outcome.exit_code()),
+        }
+    }
+
+    /// Carry out the actions of a trigger. Returns whether it stops the session.
+    fn fire_trigger(
+        &self,
+        fired: &Fired,
+        serial_port: &mut Box<dyn SerialPort>,
+        config: &ParsedTomlValues,
+        stats: &mut SessionStats,
+        exit_code: &mut Option<i32>,
+    ) -> bool {
+        let mut stop = false;
+        for action in &fired.actions {
+            match action {
+                TriggerAction::Send(text) => {
+                    match tx_format::encode(
+                        text,
+                        &config.write.tx_line_ending,
+                        config.write.escape_sequences,
+                    ) {
+                        Ok(bytes) => {
+                            let _write_result = serial_port.write_all(&bytes);
+                            let _ = serial_port.flush();
+                            stats.sent(bytes.len());
+                        }
+                        Err(error) => println!("Trigger '{}': {}", fired.pattern, error),
+                    }
+                }
+                TriggerAction::Run(_) => unreachable!("`run` triggers are rejected in read mode"),
+                TriggerAction::Exec(program) => {
+                    if let Err(error) = trigger::exec(program, &fired.env) {
+                        println!("Trigger '{}': {}", fired.pattern, error);
+                    }
+                }
+                TriggerAction::ExitCode(code) => *exit_code = Some(*code),
+                TriggerAction::Stop => stop = true,
             }
         }
+        stop
+    }
+
+    /// Compile the hook script and run its top level once. Its `send()` writes to our port;
+    /// received lines only reach it through `on_line()`.
+    fn load_line_hook(
+        &self,
+        path: &Path,
+        serial_port: &dyn SerialPort,
+        config: &ParsedTomlValues,
+    ) -> RhaiScript {
+        let hook_port = serial_port
+            .try_clone()
+            .expect("Cannot clone serial port for the line hook");
+        let (_, received) = mpsc::channel::<RxLine>();
+        let context = ScriptContext::new(
+            hook_port,
+            received,
+            config.write.clone(),
+            Rc::new(CustomCommands::default()),
+        );
+
+        let mut line_hook = RhaiScript::load(path, context)
+            .unwrap_or_else(|error| panic!("Invalid `line_hook`: {}", error))
+            .with_max_operations(MAX_LINE_HOOK_OPERATIONS);
+        if let Err(error) = line_hook.run(&[]) {
+            panic!("`line_hook` failed: {}", error);
+        }
+        line_hook
     }
 }
 
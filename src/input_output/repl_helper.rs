@@ -0,0 +1,315 @@
+use crate::custom_commands::CustomCommands;
+use crate::input_output::escape;
+use crate::input_output::meta_command::META_COMMANDS;
+use crate::input_output::scheduler::SCHEDULE_COMMANDS;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::{Hint, Hinter};
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use std::borrow::Cow::{self, Borrowed, Owned};
+
+/// `HELP <name>` completes the names of custom commands
+const HELP_PREFIX: &str = "HELP ";
+
+const COLOR_ESCAPE: &str = "\x1b[36m";
+const COLOR_ERROR: &str = "\x1b[31m";
+const COLOR_HINT: &str = "\x1b[90m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+/// Commands handled by the REPL itself rather than sent to the port
+pub const BUILT_IN_COMMANDS: &[(&str, &str)] = &[
+    ("SHOW ALL COMMANDS", "List all custom commands"),
+    ("HELP", "List all custom commands, or `HELP <name>` for one"),
+    (":hex", "Send raw bytes, e.g. `:hex 02 41 03`"),
+    (
+        ":history",
+        "List previous input, or only lines containing some text",
+    ),
+    (
+        ":stats",
+        "Show the session statistics, or `:stats json` for JSON",
+    ),
+    (
+        ":latency",
+        "Show the response time percentiles of each command",
+    ),
+];
+
+/// Something the user can type as the first word
+struct Entry {
+    name: String,
+    /// Arguments part of the usage, e.g. `<addr> [speed=slow]`
+    args: String,
+    description: String,
+}
+
+/// Completion, hints and highlighting for the write REPL
+pub struct ReplHelper {
+    entries: Vec<Entry>,
+    /// Custom command names and aliases, upper-cased
+    custom_names: Vec<String>,
+    escape_sequences: bool,
+}
+
+/// Shown after the cursor. Only the part completing the command name is inserted.
+pub struct CommandHint {
+    display: String,
+    completion: Option<String>,
+}
+
+impl Hint for CommandHint {
+    fn display(&self) -> &str {
+        &self.display
+    }
+
+    fn completion(&self) -> Option<&str> {
+        self.completion.as_deref()
+    }
+}
+
+impl Entry {
+    /// Arguments and description, each preceded by spaces if present
+    fn details(&self) -> String {
+        let mut details = String::new();
+        if !self.args.is_empty() {
+            details.push_str(&format!(" {}", self.args));
+        }
+        if !self.description.is_empty() {
+            details.push_str(&format!("  {}", self.description));
+        }
+        details
+    }
+}
+
+impl ReplHelper {
+    pub fn new(custom_commands: &CustomCommands, escape_sequences: bool) -> Self {
+        let mut entries: Vec<Entry> = BUILT_IN_COMMANDS
+            .iter()
+            .chain(META_COMMANDS)
+            .chain(SCHEDULE_COMMANDS)
+            .map(|(name, description)| Entry {
+                name: name.to_string(),
+                args: String::new(),
+                description: description.to_string(),
+            })
+            .collect();
+
+        for custom_command in custom_commands.sorted() {
+            let usage = custom_command.usage();
+            let args = usage[custom_command.name.len()..].trim_start().to_string();
+            let description = custom_command.description.clone().unwrap_or_default();
+            for name in std::iter::once(&custom_command.name).chain(&custom_command.aliases) {
+                entries.push(Entry {
+                    name: name.clone(),
+                    args: args.clone(),
+                    description: description.clone(),
+                });
+            }
+        }
+
+        Self {
+            entries,
+            custom_names: custom_commands.names().into_iter().cloned().collect(),
+            escape_sequences,
+        }
+    }
+
+    fn is_built_in(&self, word: &str) -> bool {
+        BUILT_IN_COMMANDS
+            .iter()
+            .chain(META_COMMANDS)
+            .chain(SCHEDULE_COMMANDS)
+            .any(|(name, _)| name.eq_ignore_ascii_case(word))
+    }
+
+    /// Colour valid escape sequences, and invalid ones as errors
+    fn highlight_escapes(&self, line: &str) -> String {
+        let mut result = String::new();
+        let mut chars = line.char_indices();
+
+        while let Some((start, c)) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+
+            let mut end = start + 1;
+            if let Some((_, escaped)) = chars.next() {
+                end += escaped.len_utf8();
+                if escaped == 'x' {
+                    for _ in 0..2 {
+                        if let Some((_, hex)) = chars.next() {
+                            end += hex.len_utf8();
+                        }
+                    }
+                }
+            }
+
+            let sequence = &line[start..end];
+            let color = match escape::parse_escapes(sequence) {
+                Ok(_) => COLOR_ESCAPE,
+                Err(_) => COLOR_ERROR,
+            };
+            result.push_str(&format!("{}{}{}", color, sequence, COLOR_RESET));
+        }
+
+        result
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let typed = &line[..pos];
+        let typed_upper = typed.to_uppercase();
+
+        // `HELP <name>` completes custom command names. Upper-casing can change the length
+        // of the rest, so the name starts right after `HELP ` in what was typed.
+        let help = typed.get(..HELP_PREFIX.len());
+        if help.is_some_and(|help| help.eq_ignore_ascii_case(HELP_PREFIX)) {
+            let start = HELP_PREFIX.len();
+            let partial = typed[start..].to_uppercase();
+            let candidates = self
+                .custom_names
+                .iter()
+                .filter(|name| name.starts_with(&partial))
+                .map(|name| Pair {
+                    display: name.clone(),
+                    replacement: name.clone(),
+                })
+                .collect();
+            return Ok((start, candidates));
+        }
+
+        let candidates = self
+            .entries
+            .iter()
+            .filter(|entry| entry.name.to_uppercase().starts_with(&typed_upper))
+            .map(|entry| Pair {
+                display: entry.name.clone(),
+                replacement: entry.name.clone(),
+            })
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = CommandHint;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<CommandHint> {
+        if line.is_empty() || pos < line.len() {
+            return None;
+        }
+        let line_upper = line.to_uppercase();
+
+        // Still typing the name: complete it, and describe the command
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.name.to_uppercase().starts_with(&line_upper))
+        {
+            let rest = entry.name.get(line.len()..)?.to_string();
+            return Some(CommandHint {
+                display: format!("{}{}", rest, entry.details()),
+                completion: Some(rest),
+            });
+        }
+
+        // Name typed, no arguments yet: show what arguments it takes
+        let name = line_upper.strip_suffix(' ')?;
+        self.entries
+            .iter()
+            .find(|entry| entry.name.to_uppercase() == name)
+            .map(|entry| CommandHint {
+                display: entry.details().trim_start().to_string(),
+                completion: None,
+            })
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let first_word = line.split_whitespace().next().unwrap_or("");
+        if first_word.starts_with(':') && !self.is_built_in(first_word) {
+            return Owned(format!("{}{}{}", COLOR_ERROR, line, COLOR_RESET));
+        }
+
+        let line_upper = line.to_uppercase();
+        if let Some(name) = line_upper.strip_prefix("HELP ") {
+            let name = name.trim();
+            if !name.is_empty() && !self.custom_names.iter().any(|known| known == name) {
+                return Owned(format!("{}{}{}", COLOR_ERROR, line, COLOR_RESET));
+            }
+        }
+
+        if self.escape_sequences && line.contains('\\') && !first_word.starts_with(':') {
+            return Owned(self.highlight_escapes(line));
+        }
+        Borrowed(line)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Owned(format!("{}{}{}", COLOR_HINT, hint, COLOR_RESET))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rustyline::history::History;
+
+    #[test]
+    fn should_complete_and_hint_command_names() {
+        let helper = ReplHelper::new(&CustomCommands::default(), true);
+        let history = History::new();
+        let ctx = Context::new(&history);
+
+        let (start, candidates) = helper.complete("sh", 2, &ctx).unwrap();
+        let names: Vec<String> = candidates
+            .into_iter()
+            .map(|pair| pair.replacement)
+            .collect();
+        assert_eq!((0, vec![String::from("SHOW ALL COMMANDS")]), (start, names));
+
+        // Longer once upper-cased
+        let typed = "help \u{149}\u{149}";
+        let (start, candidates) = helper.complete(typed, typed.len(), &ctx).unwrap();
+        assert_eq!((5, 0), (start, candidates.len()));
+
+        let hint = helper.hint(":h", 2, &ctx).unwrap();
+        assert_eq!(Some("ex"), hint.completion());
+    }
+
+    #[test]
+    fn should_highlight_escapes_and_unknown_meta_commands() {
+        let helper = ReplHelper::new(&CustomCommands::default(), true);
+
+        assert_eq!(
+            format!("AT{}\\r{}", COLOR_ESCAPE, COLOR_RESET),
+            helper.highlight("AT\\r", 0)
+        );
+        assert_eq!(
+            format!("{}:nope{}", COLOR_ERROR, COLOR_RESET),
+            helper.highlight(":nope", 0)
+        );
+    }
+}
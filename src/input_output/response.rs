@@ -0,0 +1,248 @@
+use regex::Regex;
+use serde::Deserialize;
+
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a response when the rule has no timeout. Rules from the config
+/// always have one, `timeout_in_milliseconds` unless set.
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+/// `[write.response]` in the config, or `response = { ... }` for a custom command.
+/// The response is complete as soon as any of the conditions is met.
+#[derive(Deserialize, Default)]
+pub struct ResponseToml {
+    /// Regex matched against all lines received so far, joined with `\n`
+    until_regex: Option<String>,
+    /// The device prints this when it is ready for the next command
+    until_prompt: Option<String>,
+    /// Number of lines to wait for
+    line_count: Option<usize>,
+    /// Stop once nothing was received for this long
+    idle_ms: Option<u64>,
+    /// Give up after this long
+    timeout_ms: Option<u64>,
+}
+
+/// When to stop waiting for a response
+#[derive(Clone, Default)]
+pub struct ResponseRule {
+    pub until_regex: Option<Regex>,
+    pub until_prompt: Option<String>,
+    pub line_count: Option<usize>,
+    pub idle: Option<Duration>,
+    pub timeout: Option<Duration>,
+}
+
+/// Which condition ended the wait
+#[derive(Debug, PartialEq)]
+pub enum EndReason {
+    Matched,
+    Prompt,
+    LineCount,
+    Idle,
+    Timeout,
+    /// The background reader went away
+    Disconnected,
+}
+
+/// Everything received for one command
+pub struct Response {
+    pub lines: Vec<String>,
+    /// When the last line arrived, before waiting out `idle` or the timeout
+    pub last_line_at: Option<Instant>,
+    pub ended_by: EndReason,
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            EndReason::Matched => "matched response pattern",
+            EndReason::Prompt => "prompt received",
+            EndReason::LineCount => "line count reached",
+            EndReason::Idle => "no data for idle time",
+            EndReason::Timeout => "timed out",
+            EndReason::Disconnected => "reader stopped",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl ResponseRule {
+    pub fn from_toml(toml_val: &ResponseToml) -> Result<ResponseRule, regex::Error> {
+        let until_regex = match &toml_val.until_regex {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None,
+        };
+
+        Ok(ResponseRule {
+            until_regex,
+            until_prompt: toml_val.until_prompt.clone(),
+            line_count: toml_val.line_count,
+            idle: toml_val.idle_ms.map(Duration::from_millis),
+            timeout: toml_val.timeout_ms.map(Duration::from_millis),
+        })
+    }
+
+    /// Use our own settings, and `fallback`'s for anything we don't set
+    pub fn or(&self, fallback: &ResponseRule) -> ResponseRule {
+        ResponseRule {
+            until_regex: self
+                .until_regex
+                .clone()
+                .or_else(|| fallback.until_regex.clone()),
+            until_prompt: self
+                .until_prompt
+                .clone()
+                .or_else(|| fallback.until_prompt.clone()),
+            line_count: self.line_count.or(fallback.line_count),
+            idle: self.idle.or(fallback.idle),
+            timeout: self.timeout.or(fallback.timeout),
+        }
+    }
+
+    /// Collect lines from `receiver` until one of the conditions is met.
+    /// `on_line` is called for each line as it arrives.
+    pub fn collect<L: AsRef<str>, F: FnMut(&L)>(
+        &self,
+        receiver: &Receiver<L>,
+        mut on_line: F,
+    ) -> Response {
+        let timeout = self
+            .timeout
+            .unwrap_or_else(|| Duration::from_millis(DEFAULT_TIMEOUT_MS));
+        // Without any condition, a single line is a complete response
+        let line_count = match (&self.until_regex, &self.until_prompt, self.idle) {
+            (None, None, None) => Some(self.line_count.unwrap_or(1)),
+            _ => self.line_count,
+        };
+
+        let start_time = Instant::now();
+        let mut lines = Vec::<String>::new();
+        let mut last_line_at = None;
+
+        loop {
+            let remaining = match timeout.checked_sub(start_time.elapsed()) {
+                Some(remaining) => remaining,
+                None => return Response::new(lines, last_line_at, EndReason::Timeout),
+            };
+            let wait = match self.idle {
+                Some(idle) if !lines.is_empty() && idle < remaining => idle,
+                _ => remaining,
+            };
+
+            let line = match receiver.recv_timeout(wait) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) if wait < remaining => {
+                    return Response::new(lines, last_line_at, EndReason::Idle)
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Response::new(lines, last_line_at, EndReason::Timeout)
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Response::new(lines, last_line_at, EndReason::Disconnected)
+                }
+            };
+            last_line_at = Some(Instant::now());
+            on_line(&line);
+            lines.push(line.as_ref().to_string());
+
+            if let Some(regex) = &self.until_regex {
+                if regex.is_match(&lines.join("\n")) {
+                    return Response::new(lines, last_line_at, EndReason::Matched);
+                }
+            }
+            if let Some(prompt) = &self.until_prompt {
+                if lines
+                    .last()
+                    .unwrap()
+                    .trim_end()
+                    .ends_with(prompt.trim_end())
+                {
+                    return Response::new(lines, last_line_at, EndReason::Prompt);
+                }
+            }
+            if let Some(count) = line_count {
+                if lines.len() >= count {
+                    return Response::new(lines, last_line_at, EndReason::LineCount);
+                }
+            }
+        }
+    }
+}
+
+impl Response {
+    fn new(lines: Vec<String>, last_line_at: Option<Instant>, ended_by: EndReason) -> Self {
+        Response {
+            lines,
+            last_line_at,
+            ended_by,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::mpsc::{self, Sender};
+
+    /// Keep the returned `Sender` alive, so the receiver times out instead of disconnecting
+    fn send_lines(lines: &[&str]) -> (Sender<String>, Receiver<String>) {
+        let (sender, receiver) = mpsc::channel::<String>();
+        for line in lines {
+            sender.send(line.to_string()).unwrap();
+        }
+        (sender, receiver)
+    }
+
+    #[test]
+    fn should_read_a_single_line_by_default() {
+        let (_sender, receiver) = send_lines(&["first", "second"]);
+        let response = ResponseRule::default().collect(&receiver, |_| {});
+
+        assert_eq!(vec![String::from("first")], response.lines);
+        assert_eq!(EndReason::LineCount, response.ended_by);
+    }
+
+    #[test]
+    fn should_stop_when_regex_matches() {
+        let (_sender, receiver) = send_lines(&["help", "  reset", "OK", "unrelated"]);
+        let rule = ResponseRule {
+            until_regex: Some(Regex::new("(?m)^OK$").unwrap()),
+            ..ResponseRule::default()
+        };
+        let response = rule.collect(&receiver, |_| {});
+
+        assert_eq!(3, response.lines.len());
+        assert_eq!(EndReason::Matched, response.ended_by);
+    }
+
+    #[test]
+    fn should_stop_when_idle_or_timed_out() {
+        let (_sender, receiver) = send_lines(&["one", "two"]);
+        let rule = ResponseRule {
+            idle: Some(Duration::from_millis(50)),
+            ..ResponseRule::default()
+        };
+        let started = Instant::now();
+        let response = rule.collect(&receiver, |_| {});
+        assert_eq!(2, response.lines.len());
+        assert_eq!(EndReason::Idle, response.ended_by);
+        // The idle time is not part of the response
+        let last_line_at = response.last_line_at.unwrap();
+        assert!(last_line_at.duration_since(started) < Duration::from_millis(50));
+        assert!(last_line_at.elapsed() >= Duration::from_millis(50));
+
+        let (_sender, receiver) = send_lines(&[]);
+        let rule = ResponseRule {
+            until_prompt: Some(String::from("> ")),
+            timeout: Some(Duration::from_millis(50)),
+            ..ResponseRule::default()
+        };
+        let response = rule.collect(&receiver, |_| {});
+        assert_eq!(EndReason::Timeout, response.ended_by);
+        assert_eq!(None, response.last_line_at);
+    }
+}
@@ -0,0 +1,573 @@
+use crate::input_output::background_reader::CommandLabel;
+use crate::input_output::stats::SessionStats;
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::Deserialize;
+use serialport::SerialPort;
+
+use std::fmt;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the scheduler thread checks for due schedules
+const TICK_MS: u64 = 10;
+/// Id of payloads sent once through a `SendQueue`, which are not listed
+const ONE_OFF_ID: usize = 0;
+/// After sending, the REPL waits until nothing was received for this long, so the reply
+/// is not taken for the response to its own line
+const REPLY_SETTLE_MS: u64 = 100;
+/// The REPL waits at most this long for the reply to settle, e.g. when a device streams
+const REPLY_WAIT_MAX_MS: u64 = 1000;
+
+/// REPL commands for schedules, with their descriptions
+pub const SCHEDULE_COMMANDS: &[(&str, &str)] = &[
+    (
+        ":every",
+        "Send a line or run a custom command every N ms, e.g. `:every 500 READ TEMP`. `:every 500 x10 READ TEMP` stops after 10 times.",
+    ),
+    (":schedules", "List the active schedules"),
+    (":cancel", "Stop a schedule, e.g. `:cancel 2`, or `:cancel all`"),
+];
+
+/// A `[[write.schedule]]` entry in the config
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleToml {
+    /// A line to send or a custom command, as typed in the REPL
+    command: String,
+    every_ms: Option<u64>,
+    /// Cron-like: minute hour day-of-month month day-of-week
+    cron: Option<String>,
+    /// Stop after this many times
+    count: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Timing {
+    Every(Duration),
+    Cron(Cron),
+}
+
+/// What to send, and when
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleSpec {
+    /// A line to send or a custom command, as typed in the REPL
+    pub input: String,
+    pub timing: Timing,
+    /// Runs until cancelled without one
+    pub count: Option<u32>,
+}
+
+/// A REPL command starting with `:` that manages schedules
+#[derive(Debug, PartialEq)]
+pub enum ScheduleCommand {
+    Every(ScheduleSpec),
+    List,
+    /// `None` cancels all of them
+    Cancel(Option<usize>),
+}
+
+/// What a schedule does each time it is due, worked out when it is added
+#[derive(Clone)]
+pub enum Transmission {
+    /// Bytes to write, and the command received output is labelled with
+    Send {
+        label: String,
+        bytes: Vec<u8>,
+    },
+    Pause(Duration),
+}
+
+impl ScheduleSpec {
+    pub fn from_toml(toml_val: &ScheduleToml) -> Result<ScheduleSpec, String> {
+        let timing = match (toml_val.every_ms, &toml_val.cron) {
+            (Some(every_ms), None) => every(every_ms)?,
+            (None, Some(cron)) => Timing::Cron(Cron::parse(cron)?),
+            _ => return Err(String::from("needs either `every_ms` or `cron`")),
+        };
+        if toml_val.command.trim().is_empty() {
+            return Err(String::from("`command` is empty"));
+        }
+
+        Ok(ScheduleSpec {
+            input: toml_val.command.trim().to_string(),
+            timing,
+            count: toml_val.count,
+        })
+    }
+
+    /// The arguments of `:every`, e.g. `500 x10 READ TEMP`
+    pub fn parse_every(args: &str) -> Result<ScheduleSpec, String> {
+        let usage = "Usage: :every <ms> [x<count>] <line or custom command>";
+        let mut rest = args.trim();
+        let (period, after) = split_word(rest);
+        let every_ms = period.parse::<u64>().map_err(|_| String::from(usage))?;
+        let timing = every(every_ms)?;
+        rest = after;
+
+        let (word, after) = split_word(rest);
+        let count = match word.strip_prefix('x').map(str::parse::<u32>) {
+            Some(Ok(count)) if count > 0 => {
+                rest = after;
+                Some(count)
+            }
+            _ => None,
+        };
+        if rest.is_empty() {
+            return Err(String::from(usage));
+        }
+
+        Ok(ScheduleSpec {
+            input: rest.to_string(),
+            timing,
+            count,
+        })
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timing::Every(period) => write!(f, "every {} ms", period.as_millis()),
+            Timing::Cron(cron) => write!(f, "cron '{}'", cron.expression),
+        }
+    }
+}
+
+impl ScheduleCommand {
+    /// `None` if `input` is not one of the `SCHEDULE_COMMANDS`
+    pub fn parse(input: &str) -> Option<Result<ScheduleCommand, String>> {
+        let (name, args) = split_word(input.trim());
+        match name.to_lowercase().as_str() {
+            ":every" => Some(ScheduleSpec::parse_every(args).map(ScheduleCommand::Every)),
+            ":schedules" => Some(Ok(ScheduleCommand::List)),
+            ":cancel" => Some(match args {
+                "all" => Ok(ScheduleCommand::Cancel(None)),
+                id => id
+                    .parse::<usize>()
+                    .map(|id| ScheduleCommand::Cancel(Some(id)))
+                    .map_err(|_| String::from("Usage: :cancel <id> or :cancel all")),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A cron-like schedule: minute, hour, day of month, month and day of week.
+///
+/// Fields take `*`, numbers, ranges such as `1-5`, lists such as `0,30` and steps such as
+/// `*/5`. Day of week is 0-7, with both 0 and 7 meaning Sunday. Like cron, when both day
+/// fields are restricted, either one matching is enough.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "'{}' needs 5 fields: minute hour day month weekday",
+                expression
+            ));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Cron {
+            expression: fields.join(" "),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            // As in cron, `*/2` is unrestricted too
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    pub fn matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        let has = |bits: u64, value: u32| bits & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+
+        day_matches
+            && has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+    }
+}
+
+/// Bits set for the values a field allows
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid cron field '{}'", field);
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (
+                    first.parse::<u32>().map_err(|_| invalid())?,
+                    last.parse::<u32>().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let first = range.parse::<u32>().map_err(|_| invalid())?;
+                    // `5/15` means from 5 to the end, every 15
+                    (first, if part.contains('/') { max } else { first })
+                }
+            },
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn every(every_ms: u64) -> Result<Timing, String> {
+    match every_ms {
+        0 => Err(String::from("The period has to be at least 1 ms")),
+        _ => Ok(Timing::Every(Duration::from_millis(every_ms))),
+    }
+}
+
+/// The first word, and the rest without leading whitespace
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (text, ""),
+    }
+}
+
+struct Job {
+    id: usize,
+    spec: ScheduleSpec,
+    payload: Vec<Transmission>,
+    sent: u32,
+    next_run: Instant,
+    /// Minutes since the epoch when a cron schedule last ran, so it runs once a minute
+    last_minute: Option<i64>,
+}
+
+impl Job {
+    fn is_due(&self, now: Instant, local_time: &DateTime<Local>) -> bool {
+        match &self.spec.timing {
+            Timing::Every(_) => now >= self.next_run,
+            Timing::Cron(cron) => {
+                cron.matches(local_time) && self.last_minute != Some(local_time.timestamp() / 60)
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self.spec.count {
+            Some(count) => self.sent >= count,
+            None => false,
+        }
+    }
+}
+
+/// Sends scheduled lines and custom commands on its own thread, through a clone of the
+/// port. Responses are printed by the `BackgroundReader`, labelled with what was sent.
+///
+/// A schedule with pauses, e.g. a custom command with several steps, holds up the
+/// others until it is done. Schedules wait while the REPL waits for a response, and the
+/// REPL waits for a schedule being sent, so the two never share a response.
+pub struct Scheduler {
+    jobs: Arc<Mutex<Vec<Job>>>,
+    serial_port: Arc<Mutex<Box<dyn SerialPort>>>,
+    next_id: usize,
+    running: Arc<AtomicBool>,
+    /// Set while a schedule is being sent
+    busy: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Lines sent are counted in `stats`
+    pub fn start(
+        serial_port: Box<dyn SerialPort>,
+        label: CommandLabel,
+        stats: Arc<Mutex<SessionStats>>,
+    ) -> Self {
+        let jobs = Arc::new(Mutex::new(Vec::<Job>::new()));
+        let serial_port = Arc::new(Mutex::new(serial_port));
+        let running = Arc::new(AtomicBool::new(true));
+        let busy = Arc::new(AtomicBool::new(false));
+
+        let thread_jobs = Arc::clone(&jobs);
+        let thread_port = Arc::clone(&serial_port);
+        let thread_running = Arc::clone(&running);
+        let thread_busy = Arc::clone(&busy);
+        let handle = thread::spawn(move || {
+            while thread_running.load(Ordering::SeqCst) {
+                if !label.claim() {
+                    thread::sleep(Duration::from_millis(TICK_MS));
+                    continue;
+                }
+                let due = Scheduler::take_due(&thread_jobs, &thread_busy);
+                let mut last_sent = None;
+                for transmission in due.iter().flatten() {
+                    match transmission {
+                        Transmission::Send {
+                            label: command,
+                            bytes,
+                        } => {
+                            label.set(command);
+                            let mut serial_port = thread_port.lock().unwrap();
+                            let _write_result = serial_port.write_all(bytes);
+                            let _ = serial_port.flush();
+                            stats.lock().unwrap().sent(bytes.len());
+                            last_sent = Some(Instant::now());
+                        }
+                        Transmission::Pause(duration) => thread::sleep(*duration),
+                    }
+                }
+                if let Some(last_sent) = last_sent {
+                    Scheduler::wait_for_reply(&label, last_sent);
+                }
+                label.release();
+                thread_busy.store(false, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(TICK_MS));
+            }
+        });
+
+        Self {
+            jobs,
+            serial_port,
+            next_id: 1,
+            running,
+            busy,
+            handle: Some(handle),
+        }
+    }
+
+    /// Wait until the reply to what was sent at `last_sent` is over
+    fn wait_for_reply(label: &CommandLabel, last_sent: Instant) {
+        let settle = Duration::from_millis(REPLY_SETTLE_MS);
+        while last_sent.elapsed() < Duration::from_millis(REPLY_WAIT_MAX_MS)
+            && (last_sent.elapsed() < settle || !label.quiet_for(settle))
+        {
+            thread::sleep(Duration::from_millis(TICK_MS));
+        }
+    }
+
+    /// Payloads of the schedules that are due, counting them as sent. Finished
+    /// schedules are removed.
+    fn take_due(jobs: &Arc<Mutex<Vec<Job>>>, busy: &Arc<AtomicBool>) -> Vec<Vec<Transmission>> {
+        let now = Instant::now();
+        let local_time = Local::now();
+        let mut jobs = jobs.lock().unwrap();
+
+        let mut due = Vec::<Vec<Transmission>>::new();
+        for job in jobs.iter_mut().filter(|job| job.is_due(now, &local_time)) {
+            job.sent += 1;
+            match job.spec.timing {
+                Timing::Every(period) => {
+                    // Skip runs that were missed, instead of sending them all at once
+                    job.next_run = (job.next_run + period).max(now);
+                }
+                Timing::Cron(_) => job.last_minute = Some(local_time.timestamp() / 60),
+            }
+            due.push(job.payload.clone());
+        }
+        jobs.retain(|job| !job.is_done());
+        if !due.is_empty() {
+            busy.store(true, Ordering::SeqCst);
+        }
+        due
+    }
+
+    /// Returns the id to cancel it with. `Every` schedules first run right away.
+    pub fn add(&mut self, spec: ScheduleSpec, payload: Vec<Transmission>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.lock().unwrap().push(Job {
+            id,
+            spec,
+            payload,
+            sent: 0,
+            next_run: Instant::now(),
+            last_minute: None,
+        });
+        id
+    }
+
+    /// One line per active schedule
+    pub fn list(&self) -> Vec<String> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|job| job.id != ONE_OFF_ID)
+            .map(|job| {
+                let sent = match job.spec.count {
+                    Some(count) => format!("{} of {} sent", job.sent, count),
+                    None => format!("{} sent", job.sent),
+                };
+                format!(
+                    "{:>3}  {}, {}: {}",
+                    job.id, job.spec.timing, sent, job.spec.input
+                )
+            })
+            .collect()
+    }
+
+    /// Whether a schedule with that id was active
+    pub fn cancel(&self, id: usize) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        let count = jobs.len();
+        jobs.retain(|job| job.id != id);
+        jobs.len() != count
+    }
+
+    /// Returns how many were active. Lines queued by triggers are still sent.
+    pub fn cancel_all(&self) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let count = jobs.len();
+        jobs.retain(|job| job.id == ONE_OFF_ID);
+        count - jobs.len()
+    }
+
+    /// Send through `serial_port` from now on, e.g. after reopening the port
+    pub fn set_port(&self, serial_port: Box<dyn SerialPort>) {
+        *self.serial_port.lock().unwrap() = serial_port;
+    }
+
+    /// Whether every schedule ran its count, or was cancelled, and nothing is being sent
+    pub fn is_finished(&self) -> bool {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.is_empty() && !self.busy.load(Ordering::SeqCst)
+    }
+
+    /// For sending from other threads
+    pub fn queue(&self) -> SendQueue {
+        SendQueue(Arc::clone(&self.jobs))
+    }
+
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().expect("Scheduler did not join()");
+        }
+    }
+}
+
+/// Sends payloads once, as soon as the scheduler thread gets to them
+#[derive(Clone)]
+pub struct SendQueue(Arc<Mutex<Vec<Job>>>);
+
+impl SendQueue {
+    pub fn send(&self, input: &str, payload: Vec<Transmission>) {
+        self.0.lock().unwrap().push(Job {
+            id: ONE_OFF_ID,
+            spec: ScheduleSpec {
+                input: input.to_string(),
+                timing: Timing::Every(Duration::ZERO),
+                count: Some(1),
+            },
+            payload,
+            sent: 0,
+            next_run: Instant::now(),
+            last_minute: None,
+        });
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_every_command() {
+        assert_eq!(
+            Some(Ok(ScheduleCommand::Every(ScheduleSpec {
+                input: String::from("READ TEMP"),
+                timing: Timing::Every(Duration::from_millis(500)),
+                count: Some(10),
+            }))),
+            ScheduleCommand::parse(":every 500 x10 READ TEMP")
+        );
+        assert_eq!(
+            Some(Ok(ScheduleCommand::Every(ScheduleSpec {
+                input: String::from("xyz 1"),
+                timing: Timing::Every(Duration::from_millis(20)),
+                count: None,
+            }))),
+            ScheduleCommand::parse(":every 20 xyz 1")
+        );
+        assert!(matches!(ScheduleCommand::parse(":every 500"), Some(Err(_))));
+        assert_eq!(
+            Some(Ok(ScheduleCommand::Cancel(Some(2)))),
+            ScheduleCommand::parse(":cancel 2")
+        );
+        assert_eq!(None, ScheduleCommand::parse(":baud 9600"));
+    }
+
+    #[test]
+    fn should_match_cron_schedules() {
+        // A Monday
+        let time = |day: u32, hour: u32, minute: u32| {
+            NaiveDate::from_ymd_opt(2024, 1, day)
+                .unwrap()
+                .and_hms_opt(hour, minute, 0)
+                .unwrap()
+        };
+
+        let cron = Cron::parse("*/15 8-17 * * 1-5").unwrap();
+        assert!(cron.matches(&time(1, 8, 45)));
+        assert!(!cron.matches(&time(1, 8, 46)));
+        assert!(!cron.matches(&time(1, 18, 0)));
+        assert!(!cron.matches(&time(7, 9, 0)));
+
+        // Either day field matching is enough when both are restricted
+        let cron = Cron::parse("0 0 15 * 7").unwrap();
+        assert!(cron.matches(&time(7, 0, 0)));
+        assert!(cron.matches(&time(15, 0, 0)));
+        assert!(!cron.matches(&time(16, 0, 0)));
+
+        // A day field starting with `*` leaves the day to the weekday
+        let cron = Cron::parse("0 0 */2 * 1").unwrap();
+        assert!(cron.matches(&time(8, 0, 0)));
+        assert!(!cron.matches(&time(3, 0, 0)));
+
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("* * *").is_err());
+    }
+}
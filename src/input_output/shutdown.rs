@@ -0,0 +1,60 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit status of a session ended by SIGINT or SIGTERM, as the shell reports Ctrl + C
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+/// How often long waits check whether a signal was received
+const POLL_MS: u64 = 100;
+
+static REQUESTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+fn requested_flag() -> &'static Arc<AtomicBool> {
+    REQUESTED.get_or_init(|| Arc::new(AtomicBool::new(false)))
+}
+
+/// Catch SIGINT and SIGTERM, so a session can stop reading, flush its log and print a
+/// summary before exiting. A second signal ends the process right away, e.g. if the port
+/// hangs.
+pub fn install() {
+    let requested = requested_flag();
+    for signal in &[SIGINT, SIGTERM] {
+        flag::register_conditional_shutdown(*signal, INTERRUPTED_EXIT_CODE, Arc::clone(requested))
+            .expect("Cannot install signal handler");
+        flag::register(*signal, Arc::clone(requested)).expect("Cannot install signal handler");
+    }
+}
+
+/// Whether SIGINT or SIGTERM was received
+pub fn requested() -> bool {
+    requested_flag().load(Ordering::SeqCst)
+}
+
+/// Sleep for `duration`, or until SIGINT or SIGTERM is received
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !requested() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_millis(0) {
+            break;
+        }
+        thread::sleep(remaining.min(Duration::from_millis(POLL_MS)));
+    }
+}
+
+/// `Receiver::recv_timeout()`, which also times out once SIGINT or SIGTERM is received
+pub fn recv_timeout<T>(receiver: &Receiver<T>, timeout: Duration) -> Result<T, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining.min(Duration::from_millis(POLL_MS))) {
+            Err(RecvTimeoutError::Timeout) if !requested() && Instant::now() < deadline => {}
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use serde::Serialize;
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How the statistics are printed when the session ends
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StatsFormat {
+    #[default]
+    Text,
+    Json,
+    None,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "text" => Ok(StatsFormat::Text),
+            "json" => Ok(StatsFormat::Json),
+            "none" => Ok(StatsFormat::None),
+            _ => Err(format!(
+                "Invalid stats format '{}', expected text, json or none",
+                value
+            )),
+        }
+    }
+}
+
+/// Counters of a session, for judging the health of the link
+pub struct SessionStats {
+    start: Instant,
+    rx_bytes: u64,
+    rx_frames: u64,
+    tx_bytes: u64,
+    tx_frames: u64,
+    /// When the last frame was received, for the time between frames
+    last_rx: Option<Instant>,
+    min_delta: Option<Duration>,
+    max_delta: Option<Duration>,
+    total_delta: Duration,
+    deltas: u32,
+    timeouts: u64,
+    decode_errors: u64,
+    reconnects: u64,
+}
+
+/// `SessionStats` as printed with `--stats json`
+#[derive(Serialize)]
+struct StatsRecord {
+    duration_s: f64,
+    rx_bytes: u64,
+    rx_frames: u64,
+    rx_bytes_per_s: f64,
+    tx_bytes: u64,
+    tx_frames: u64,
+    tx_bytes_per_s: f64,
+    min_delta_ms: Option<f64>,
+    avg_delta_ms: Option<f64>,
+    max_delta_ms: Option<f64>,
+    timeouts: u64,
+    decode_errors: u64,
+    reconnects: u64,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        SessionStats::new_at(Instant::now())
+    }
+
+    fn new_at(start: Instant) -> Self {
+        SessionStats {
+            start,
+            rx_bytes: 0,
+            rx_frames: 0,
+            tx_bytes: 0,
+            tx_frames: 0,
+            last_rx: None,
+            min_delta: None,
+            max_delta: None,
+            total_delta: Duration::ZERO,
+            deltas: 0,
+            timeouts: 0,
+            decode_errors: 0,
+            reconnects: 0,
+        }
+    }
+
+    /// Count a frame of `bytes` received now
+    pub fn received(&mut self, bytes: usize) {
+        self.received_at(bytes, Instant::now());
+    }
+
+    fn received_at(&mut self, bytes: usize, now: Instant) {
+        self.rx_bytes += bytes as u64;
+        self.rx_frames += 1;
+        if let Some(last_rx) = self.last_rx {
+            let delta = now.saturating_duration_since(last_rx);
+            self.min_delta = Some(self.min_delta.map_or(delta, |min| min.min(delta)));
+            self.max_delta = Some(self.max_delta.map_or(delta, |max| max.max(delta)));
+            self.total_delta += delta;
+            self.deltas += 1;
+        }
+        self.last_rx = Some(now);
+    }
+
+    /// Count a frame of `bytes` sent
+    pub fn sent(&mut self, bytes: usize) {
+        self.tx_bytes += bytes as u64;
+        self.tx_frames += 1;
+    }
+
+    pub fn timed_out(&mut self) {
+        self.timeouts += 1;
+    }
+
+    /// Count a frame that is not valid UTF-8
+    pub fn decode_error(&mut self) {
+        self.decode_errors += 1;
+    }
+
+    pub fn reconnected(&mut self) {
+        self.reconnects += 1;
+    }
+
+    /// The statistics in `format`, or `None` for `StatsFormat::None`
+    pub fn report(&self, format: StatsFormat) -> Option<String> {
+        self.report_at(format, Instant::now())
+    }
+
+    fn report_at(&self, format: StatsFormat, now: Instant) -> Option<String> {
+        let elapsed = now.saturating_duration_since(self.start);
+        let record = self.record(elapsed);
+        match format {
+            StatsFormat::None => None,
+            StatsFormat::Json => {
+                Some(serde_json::to_string(&record).expect("Cannot serialize statistics"))
+            }
+            StatsFormat::Text => {
+                let deltas = match (
+                    record.min_delta_ms,
+                    record.avg_delta_ms,
+                    record.max_delta_ms,
+                ) {
+                    (Some(min), Some(avg), Some(max)) => {
+                        format!("min {:.1} ms, avg {:.1} ms, max {:.1} ms", min, avg, max)
+                    }
+                    _ => String::from("-"),
+                };
+                Some(
+                    [
+                        format!("Session lasted {}", duration_text(elapsed)),
+                        format!(
+                            "Received: {} frame(s), {} byte(s), {:.1} B/s",
+                            record.rx_frames, record.rx_bytes, record.rx_bytes_per_s
+                        ),
+                        format!(
+                            "Sent: {} frame(s), {} byte(s), {:.1} B/s",
+                            record.tx_frames, record.tx_bytes, record.tx_bytes_per_s
+                        ),
+                        format!("Time between received frames: {}", deltas),
+                        format!(
+                            "Timeouts: {}, decode errors: {}, reconnects: {}",
+                            record.timeouts, record.decode_errors, record.reconnects
+                        ),
+                    ]
+                    .join("\n"),
+                )
+            }
+        }
+    }
+
+    fn record(&self, elapsed: Duration) -> StatsRecord {
+        let seconds = elapsed.as_secs_f64();
+        let per_second = |bytes: u64| match seconds > 0.0 {
+            true => bytes as f64 / seconds,
+            false => 0.0,
+        };
+        let millis = |delta: Duration| delta.as_secs_f64() * 1000.0;
+        StatsRecord {
+            duration_s: seconds,
+            rx_bytes: self.rx_bytes,
+            rx_frames: self.rx_frames,
+            rx_bytes_per_s: per_second(self.rx_bytes),
+            tx_bytes: self.tx_bytes,
+            tx_frames: self.tx_frames,
+            tx_bytes_per_s: per_second(self.tx_bytes),
+            min_delta_ms: self.min_delta.map(millis),
+            avg_delta_ms: match self.deltas {
+                0 => None,
+                deltas => Some(millis(self.total_delta / deltas)),
+            },
+            max_delta_ms: self.max_delta.map(millis),
+            timeouts: self.timeouts,
+            decode_errors: self.decode_errors,
+            reconnects: self.reconnects,
+        }
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        SessionStats::new()
+    }
+}
+
+/// Print the statistics to stderr whenever SIGUSR1 is received, e.g. `kill -USR1 <pid>`.
+/// `StatsFormat::None` prints them as text, as they were asked for.
+pub fn report_on_signal(stats: Arc<Mutex<SessionStats>>, format: StatsFormat) {
+    let format = match format {
+        StatsFormat::None => StatsFormat::Text,
+        format => format,
+    };
+    let mut signals = Signals::new([SIGUSR1]).expect("Cannot install signal handler");
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let report = stats.lock().unwrap().report(format);
+            if let Some(report) = report {
+                eprintln!("{}", report);
+            }
+        }
+    });
+}
+
+/// `5:07`, or `1:02:03` once it reaches an hour
+fn duration_text(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn session() -> (SessionStats, Instant) {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut stats = SessionStats::new_at(start);
+        stats.received_at(10, at(100));
+        stats.received_at(20, at(400));
+        stats.received_at(30, at(500));
+        stats.sent(4);
+        stats.timed_out();
+        stats.reconnected();
+        (stats, at(2000))
+    }
+
+    #[test]
+    fn should_report_session_as_text() {
+        let (stats, end) = session();
+
+        assert_eq!(
+            Some(String::from(
+                "Session lasted 0:02\n\
+                 Received: 3 frame(s), 60 byte(s), 30.0 B/s\n\
+                 Sent: 1 frame(s), 4 byte(s), 2.0 B/s\n\
+                 Time between received frames: min 100.0 ms, avg 200.0 ms, max 300.0 ms\n\
+                 Timeouts: 1, decode errors: 0, reconnects: 1"
+            )),
+            stats.report_at(StatsFormat::Text, end)
+        );
+        assert_eq!(None, stats.report_at(StatsFormat::None, end));
+        assert_eq!("1:02:03", duration_text(Duration::from_secs(3723)));
+    }
+
+    #[test]
+    fn should_report_session_as_json() {
+        let (stats, end) = session();
+        let report = stats.report_at(StatsFormat::Json, end).unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(60, value["rx_bytes"]);
+        assert_eq!(3, value["rx_frames"]);
+        assert_eq!(2.0, value["tx_bytes_per_s"]);
+        assert_eq!(200.0, value["avg_delta_ms"]);
+        assert_eq!(1, value["timeouts"]);
+        assert!("xml".parse::<StatsFormat>().is_err());
+
+        let empty = SessionStats::new_at(end)
+            .report_at(StatsFormat::Json, end)
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&empty).unwrap();
+        assert_eq!(serde_json::Value::Null, value["min_delta_ms"]);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::input_output::shutdown;
+
+use regex::Regex;
+use structopt::StructOpt;
+
+use std::time::{Duration, Instant};
+
+/// Options of `read` that end the session on their own
+#[derive(StructOpt, Clone, Default)]
+pub struct StopArgs {
+    /// Stop after receiving this many lines
+    #[structopt(long = "--max-lines")]
+    pub max_lines: Option<u64>,
+    /// Stop after this long, e.g. `90`, `90s`, `500ms`, `5m` or `1h`
+    #[structopt(long = "--duration", parse(try_from_str = parse_duration))]
+    pub duration: Option<Duration>,
+    /// Stop once a line matches this regex
+    #[structopt(long = "--until")]
+    pub until: Option<Regex>,
+    /// Stop when nothing was received for this long, e.g. `10s`
+    #[structopt(long = "--idle-timeout", parse(try_from_str = parse_duration))]
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Why a `read` session ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadOutcome {
+    /// A line matched `--until`
+    Matched,
+    /// `--max-lines` lines were received
+    MaxLines,
+    /// `--duration` elapsed
+    TimedOut,
+    /// Nothing was received for `--idle-timeout`
+    Idle,
+    /// The port could not be read, e.g. it was unplugged
+    PortError,
+    /// A trigger with `stop` matched
+    Triggered,
+    /// SIGINT or SIGTERM, e.g. Ctrl + C
+    Interrupted,
+}
+
+impl ReadOutcome {
+    /// Exit status of the `read` command
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ReadOutcome::Matched | ReadOutcome::MaxLines | ReadOutcome::Triggered => 0,
+            ReadOutcome::TimedOut => 2,
+            ReadOutcome::Idle => 3,
+            ReadOutcome::PortError => 4,
+            ReadOutcome::Interrupted => shutdown::INTERRUPTED_EXIT_CODE,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            ReadOutcome::Matched => "a line matched --until",
+            ReadOutcome::MaxLines => "--max-lines reached",
+            ReadOutcome::TimedOut => "--duration elapsed",
+            ReadOutcome::Idle => "nothing received for --idle-timeout",
+            ReadOutcome::PortError => "the port could not be read",
+            ReadOutcome::Triggered => "a trigger matched",
+            ReadOutcome::Interrupted => "interrupted",
+        }
+    }
+}
+
+/// Keeps track of the stop conditions during a session
+pub struct StopConditions {
+    args: StopArgs,
+    start: Instant,
+    last_line: Instant,
+    lines: u64,
+}
+
+impl StopConditions {
+    pub fn new(args: StopArgs) -> Self {
+        StopConditions::new_at(args, Instant::now())
+    }
+
+    fn new_at(args: StopArgs, start: Instant) -> Self {
+        StopConditions {
+            args,
+            start,
+            last_line: start,
+            lines: 0,
+        }
+    }
+
+    /// Count a received line, without its line ending
+    pub fn on_line(&mut self, line: &str) -> Option<ReadOutcome> {
+        self.on_line_at(line, Instant::now())
+    }
+
+    fn on_line_at(&mut self, line: &str, now: Instant) -> Option<ReadOutcome> {
+        self.lines += 1;
+        self.last_line = now;
+        if let Some(until) = &self.args.until {
+            if until.is_match(line) {
+                return Some(ReadOutcome::Matched);
+            }
+        }
+        match self.args.max_lines {
+            Some(max_lines) if self.lines >= max_lines => Some(ReadOutcome::MaxLines),
+            _ => None,
+        }
+    }
+
+    /// Whether the session has run out of time
+    pub fn expired(&self) -> Option<ReadOutcome> {
+        self.expired_at(Instant::now())
+    }
+
+    fn expired_at(&self, now: Instant) -> Option<ReadOutcome> {
+        if let Some(duration) = self.args.duration {
+            if now >= self.start + duration {
+                return Some(ReadOutcome::TimedOut);
+            }
+        }
+        match self.args.idle_timeout {
+            Some(idle_timeout) if now >= self.last_line + idle_timeout => Some(ReadOutcome::Idle),
+            _ => None,
+        }
+    }
+
+    /// `deadline`, or earlier if the session runs out of time before it
+    pub fn limit(&self, deadline: Instant) -> Instant {
+        let mut limit = deadline;
+        if let Some(duration) = self.args.duration {
+            limit = limit.min(self.start + duration);
+        }
+        if let Some(idle_timeout) = self.args.idle_timeout {
+            limit = limit.min(self.last_line + idle_timeout);
+        }
+        limit
+    }
+}
+
+/// `90`, `90s`, `500ms`, `5m` or `1h`
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", value))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => {
+            return Err(format!(
+                "Invalid duration '{}', expected ms, s, m or h",
+                value
+            ))
+        }
+    };
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_durations() {
+        assert_eq!(Ok(Duration::from_secs(90)), parse_duration("90"));
+        assert_eq!(Ok(Duration::from_millis(500)), parse_duration("500ms"));
+        assert_eq!(Ok(Duration::from_secs(300)), parse_duration("5m"));
+        assert_eq!(Ok(Duration::from_millis(1500)), parse_duration("1.5s"));
+        assert!(parse_duration("5 days").is_err());
+        assert!(parse_duration("ms").is_err());
+    }
+
+    #[test]
+    fn should_stop_on_first_condition_met() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let args = StopArgs {
+            max_lines: Some(3),
+            duration: Some(Duration::from_secs(60)),
+            until: Some(Regex::new("BOOT COMPLETE").unwrap()),
+            idle_timeout: Some(Duration::from_secs(10)),
+        };
+
+        let mut conditions = StopConditions::new_at(args.clone(), start);
+        assert_eq!(None, conditions.on_line_at("booting", at(5)));
+        assert_eq!(None, conditions.expired_at(at(14)));
+        assert_eq!(Some(ReadOutcome::Idle), conditions.expired_at(at(15)));
+        assert_eq!(at(15), conditions.limit(at(20)));
+        assert_eq!(
+            Some(ReadOutcome::Matched),
+            conditions.on_line_at("BOOT COMPLETE", at(6))
+        );
+        assert_eq!(
+            Some(ReadOutcome::MaxLines),
+            conditions.on_line_at("login:", at(7))
+        );
+
+        let conditions = StopConditions::new_at(
+            StopArgs {
+                idle_timeout: None,
+                ..args
+            },
+            start,
+        );
+        assert_eq!(Some(ReadOutcome::TimedOut), conditions.expired_at(at(60)));
+    }
+}
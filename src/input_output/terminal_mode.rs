@@ -0,0 +1,50 @@
+/// Turns off the bracketed paste the prompt turns on
+#[cfg(unix)]
+const BRACKETED_PASTE_OFF: &str = "\x1b[?2004l";
+
+/// The mode of the terminal on stdin, saved before the prompt switches it to raw mode.
+///
+/// The prompt only restores the mode when `readline` returns, which needs a key press, so
+/// a session ending while the prompt waits restores it from here instead.
+#[derive(Clone, Copy)]
+pub struct TerminalMode {
+    #[cfg(unix)]
+    termios: Option<libc::termios>,
+}
+
+impl TerminalMode {
+    /// Save the current mode, if stdin is a terminal
+    #[cfg(unix)]
+    pub fn save() -> Self {
+        let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: `tcgetattr` only writes to `termios`, which is read only if it succeeded
+        let termios = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) == 0 {
+                Some(termios.assume_init())
+            } else {
+                None
+            }
+        };
+        TerminalMode { termios }
+    }
+
+    #[cfg(not(unix))]
+    pub fn save() -> Self {
+        TerminalMode {}
+    }
+
+    /// Put the terminal back in the saved mode, e.g. with echo and line editing on
+    #[cfg(unix)]
+    pub fn restore(&self) {
+        if let Some(termios) = &self.termios {
+            // SAFETY: `termios` was filled in by `tcgetattr` on the same descriptor
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, termios);
+            }
+            print!("{}", BRACKETED_PASTE_OFF);
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn restore(&self) {}
+}
@@ -0,0 +1,336 @@
+use crate::input_output::output_format::Direction;
+
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimestampStyle {
+    /// `2024-01-31 14:30:00`
+    DateTime,
+    /// `2024-01-31T14:30:00.123+01:00`
+    Iso8601,
+    /// Seconds since the session started
+    Monotonic,
+    /// Seconds since 1970-01-01 UTC
+    Epoch,
+    None,
+}
+
+/// Digits after the seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    Seconds,
+    Millis,
+    Micros,
+    Nanos,
+}
+
+/// What the time between lines is measured from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeltaFrom {
+    /// The previous line, sent or received
+    Previous,
+    /// The first line of the session
+    First,
+    /// The last line sent, or the start of the session
+    Transmit,
+    None,
+}
+
+/// How lines are timestamped, from the `timestamp`, `precision`, `time_zone` and `delta`
+/// keys of [output]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimestampConfig {
+    pub style: TimestampStyle,
+    pub precision: Precision,
+    pub utc: bool,
+    pub delta: DeltaFrom,
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        TimestampConfig {
+            style: TimestampStyle::DateTime,
+            precision: Precision::Seconds,
+            utc: false,
+            delta: DeltaFrom::Previous,
+        }
+    }
+}
+
+impl TimestampConfig {
+    /// Each one is optional. `precision` defaults to seconds for `datetime`, else to
+    /// milliseconds.
+    pub fn parse(
+        style: Option<&str>,
+        precision: Option<&str>,
+        time_zone: Option<&str>,
+        delta: Option<&str>,
+    ) -> Result<TimestampConfig, String> {
+        let style = match style.map(str::to_lowercase).as_deref() {
+            None | Some("datetime") => TimestampStyle::DateTime,
+            Some("iso8601") => TimestampStyle::Iso8601,
+            Some("monotonic") => TimestampStyle::Monotonic,
+            Some("epoch") => TimestampStyle::Epoch,
+            Some("none") => TimestampStyle::None,
+            Some(value) => return Err(format!("Invalid `timestamp` '{}'", value)),
+        };
+        let precision = match precision.map(str::to_lowercase).as_deref() {
+            None if style == TimestampStyle::DateTime => Precision::Seconds,
+            None => Precision::Millis,
+            Some("s") => Precision::Seconds,
+            Some("ms") => Precision::Millis,
+            Some("us") => Precision::Micros,
+            Some("ns") => Precision::Nanos,
+            Some(value) => return Err(format!("Invalid `precision` '{}'", value)),
+        };
+        let utc = match time_zone.map(str::to_lowercase).as_deref() {
+            None | Some("local") => false,
+            Some("utc") => true,
+            Some(value) => return Err(format!("Invalid `time_zone` '{}'", value)),
+        };
+        let delta = match delta.map(str::to_lowercase).as_deref() {
+            None | Some("previous") => DeltaFrom::Previous,
+            Some("first") => DeltaFrom::First,
+            Some("transmit") => DeltaFrom::Transmit,
+            Some("none") => DeltaFrom::None,
+            Some(value) => return Err(format!("Invalid `delta` '{}'", value)),
+        };
+
+        Ok(TimestampConfig {
+            style,
+            precision,
+            utc,
+            delta,
+        })
+    }
+}
+
+impl Precision {
+    fn digits(self) -> usize {
+        match self {
+            Precision::Seconds => 0,
+            Precision::Millis => 3,
+            Precision::Micros => 6,
+            Precision::Nanos => 9,
+        }
+    }
+}
+
+/// When a line was sent or received
+pub struct Stamp {
+    pub timestamp: Option<String>,
+    pub delta: Option<Duration>,
+}
+
+/// Timestamps lines, and measures the time between them
+pub struct Clock {
+    config: TimestampConfig,
+    start: Instant,
+    first: Option<Instant>,
+    previous: Option<Instant>,
+    last_transmit: Option<Instant>,
+}
+
+impl Clock {
+    pub fn new(config: TimestampConfig) -> Self {
+        Clock {
+            config,
+            start: Instant::now(),
+            first: None,
+            previous: None,
+            last_transmit: None,
+        }
+    }
+
+    /// Stamp a line sent or received now
+    pub fn stamp(&mut self, direction: Direction) -> Stamp {
+        self.stamp_at(Utc::now(), Instant::now(), direction)
+    }
+
+    fn stamp_at(&mut self, time: DateTime<Utc>, instant: Instant, direction: Direction) -> Stamp {
+        let since = match self.config.delta {
+            DeltaFrom::Previous => Some(self.previous.unwrap_or(self.start)),
+            DeltaFrom::First => Some(self.first.unwrap_or(instant)),
+            DeltaFrom::Transmit => Some(self.last_transmit.unwrap_or(self.start)),
+            DeltaFrom::None => None,
+        };
+        let delta = since.map(|since| instant.saturating_duration_since(since));
+
+        self.previous = Some(instant);
+        self.first.get_or_insert(instant);
+        if direction == Direction::Tx {
+            self.last_transmit = Some(instant);
+        }
+
+        Stamp {
+            timestamp: self.format_time(&time, instant),
+            delta,
+        }
+    }
+
+    fn format_time(&self, time: &DateTime<Utc>, instant: Instant) -> Option<String> {
+        let precision = self.config.precision;
+        match self.config.style {
+            TimestampStyle::DateTime if self.config.utc => Some(date_time(time, precision)),
+            TimestampStyle::DateTime => Some(date_time(&time.with_timezone(&Local), precision)),
+            TimestampStyle::Iso8601 => {
+                let seconds_format = match precision {
+                    Precision::Seconds => SecondsFormat::Secs,
+                    Precision::Millis => SecondsFormat::Millis,
+                    Precision::Micros => SecondsFormat::Micros,
+                    Precision::Nanos => SecondsFormat::Nanos,
+                };
+                Some(match self.config.utc {
+                    true => time.to_rfc3339_opts(seconds_format, true),
+                    false => time
+                        .with_timezone(&Local)
+                        .to_rfc3339_opts(seconds_format, false),
+                })
+            }
+            TimestampStyle::Monotonic => {
+                let elapsed = instant.saturating_duration_since(self.start);
+                Some(seconds(
+                    elapsed.as_secs() as i64,
+                    elapsed.subsec_nanos(),
+                    precision,
+                ))
+            }
+            TimestampStyle::Epoch => Some(seconds(
+                time.timestamp(),
+                time.timestamp_subsec_nanos(),
+                precision,
+            )),
+            TimestampStyle::None => None,
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.config.precision
+    }
+
+    /// Milliseconds, with as many decimals as the precision calls for beyond them
+    pub fn delta_ms(&self, delta: Duration) -> String {
+        let digits = self.config.precision.digits().saturating_sub(3);
+        let millis = delta.as_secs_f64() * 1000.0;
+        match digits {
+            0 => format!("{:04}", delta.as_millis()),
+            _ => format!(
+                "{:0width$.digits$}",
+                millis,
+                width = digits + 5,
+                digits = digits
+            ),
+        }
+    }
+
+    /// `[2024-01-31 14:30:00 0012ms] `, or just the parts that are enabled
+    pub fn text_prefix(&mut self, direction: Direction) -> String {
+        let stamp = self.stamp(direction);
+        let mut parts = Vec::<String>::new();
+        if let Some(timestamp) = stamp.timestamp {
+            parts.push(timestamp);
+        }
+        if let Some(delta) = stamp.delta {
+            parts.push(format!("{}ms", self.delta_ms(delta)));
+        }
+        match parts.is_empty() {
+            true => String::new(),
+            false => format!("[{}] ", parts.join(" ")),
+        }
+    }
+}
+
+fn date_time<Tz: TimeZone>(time: &DateTime<Tz>, precision: Precision) -> String
+where
+    Tz::Offset: fmt::Display,
+{
+    let format = match precision {
+        Precision::Seconds => "%Y-%m-%d %H:%M:%S",
+        Precision::Millis => "%Y-%m-%d %H:%M:%S%.3f",
+        Precision::Micros => "%Y-%m-%d %H:%M:%S%.6f",
+        Precision::Nanos => "%Y-%m-%d %H:%M:%S%.9f",
+    };
+    time.format(format).to_string()
+}
+
+/// `12.345`, with the digits of `precision`
+fn seconds(whole: i64, nanos: u32, precision: Precision) -> String {
+    let digits = precision.digits();
+    match digits {
+        0 => whole.to_string(),
+        _ => {
+            let fraction = format!("{:09}", nanos);
+            format!("{}.{}", whole, &fraction[..digits])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn time() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 31, 13, 30, 0).unwrap()
+            + chrono::Duration::nanoseconds(123_456_789)
+    }
+
+    fn new_clock(style: &str, precision: Option<&str>, delta: Option<&str>) -> Clock {
+        Clock::new(TimestampConfig::parse(Some(style), precision, Some("utc"), delta).unwrap())
+    }
+
+    #[test]
+    fn should_format_timestamps() {
+        let now = Instant::now();
+        let stamp = |clock: &mut Clock| clock.stamp_at(time(), now, Direction::Rx).timestamp;
+
+        assert_eq!(
+            Some(String::from("2024-01-31 13:30:00")),
+            stamp(&mut new_clock("datetime", None, None))
+        );
+        assert_eq!(
+            Some(String::from("2024-01-31T13:30:00.123456Z")),
+            stamp(&mut new_clock("iso8601", Some("us"), None))
+        );
+        assert_eq!(
+            Some(String::from("1706707800.123456789")),
+            stamp(&mut new_clock("epoch", Some("ns"), None))
+        );
+        assert_eq!(None, stamp(&mut new_clock("none", None, None)));
+        assert!(TimestampConfig::parse(Some("iso"), None, None, None).is_err());
+    }
+
+    #[test]
+    fn should_measure_delta_from_chosen_line() {
+        let mut clock = new_clock("none", Some("ms"), Some("transmit"));
+        let start = clock.start;
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        let delta = |clock: &mut Clock, ms: u64, direction: Direction| {
+            let delta = clock.stamp_at(time(), at(ms), direction).delta.unwrap();
+            clock.delta_ms(delta)
+        };
+        assert_eq!("0100", delta(&mut clock, 100, Direction::Rx));
+        assert_eq!("0250", delta(&mut clock, 250, Direction::Tx));
+        assert_eq!("0050", delta(&mut clock, 300, Direction::Rx));
+        assert_eq!("0150", delta(&mut clock, 400, Direction::Rx));
+
+        let mut clock = Clock::new(
+            TimestampConfig::parse(Some("none"), Some("us"), None, Some("first")).unwrap(),
+        );
+        let start = clock.start;
+        clock.stamp_at(time(), start + Duration::from_millis(10), Direction::Rx);
+        let delta = clock
+            .stamp_at(time(), start + Duration::from_micros(22_500), Direction::Rx)
+            .delta
+            .unwrap();
+        assert_eq!("0012.500", clock.delta_ms(delta));
+        assert_eq!(
+            String::new(),
+            new_clock("none", None, Some("none")).text_prefix(Direction::Rx)
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+use std::process::Command;
+use std::thread;
+
+/// A `[[trigger]]` entry in the config
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TriggerToml {
+    /// Regex matched against every line received
+    pattern: String,
+    /// A line to send, e.g. `y`. `${1}` and `${name}` are replaced with captures, any other
+    /// `$` is sent as it is.
+    send: Option<String>,
+    /// A custom command to run, as typed in the REPL
+    run: Option<String>,
+    /// A program and its arguments, with captures in `TRIGGER_*` environment variables
+    exec: Option<Vec<String>>,
+    /// End the session
+    #[serde(default)]
+    stop: bool,
+    /// Exit status of the process when the session ends
+    exit_code: Option<i32>,
+    /// Only fire the first time the pattern matches
+    #[serde(default)]
+    once: bool,
+}
+
+/// What a trigger does, in this order
+#[derive(Clone, Debug, PartialEq)]
+pub enum TriggerAction {
+    Send(String),
+    Run(String),
+    Exec(Vec<String>),
+    ExitCode(i32),
+    Stop,
+}
+
+#[derive(Clone)]
+struct Trigger {
+    pattern: Regex,
+    actions: Vec<TriggerAction>,
+    once: bool,
+    fired: bool,
+}
+
+/// A trigger that matched a line. `send` and `exec` actions have the captures filled in.
+#[derive(Debug, PartialEq)]
+pub struct Fired {
+    pub pattern: String,
+    pub actions: Vec<TriggerAction>,
+    /// `TRIGGER_LINE`, `TRIGGER_0`, `TRIGGER_1`, ... and `TRIGGER_<NAME>` for named groups
+    pub env: Vec<(String, String)>,
+}
+
+/// The `[[trigger]]` table, checked against every line received
+#[derive(Clone, Default)]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+}
+
+impl Triggers {
+    pub fn from_toml(toml_vals: &[TriggerToml]) -> Result<Triggers, String> {
+        let mut triggers = Vec::<Trigger>::new();
+        for toml_val in toml_vals {
+            let pattern = Regex::new(&toml_val.pattern)
+                .map_err(|err| format!("Invalid pattern '{}': {}", toml_val.pattern, err))?;
+            let mut actions = Vec::<TriggerAction>::new();
+            if let Some(text) = &toml_val.send {
+                actions.push(TriggerAction::Send(text.clone()));
+            }
+            if let Some(command) = &toml_val.run {
+                actions.push(TriggerAction::Run(command.clone()));
+            }
+            match &toml_val.exec {
+                Some(program) if program.is_empty() => {
+                    return Err(format!("'{}' has an empty `exec`", toml_val.pattern));
+                }
+                Some(program) => actions.push(TriggerAction::Exec(program.clone())),
+                None => {}
+            }
+            if let Some(exit_code) = toml_val.exit_code {
+                actions.push(TriggerAction::ExitCode(exit_code));
+            }
+            if toml_val.stop {
+                actions.push(TriggerAction::Stop);
+            }
+            if actions.is_empty() {
+                return Err(format!("'{}' has nothing to do", toml_val.pattern));
+            }
+            triggers.push(Trigger {
+                pattern,
+                actions,
+                once: toml_val.once,
+                fired: false,
+            });
+        }
+        Ok(Triggers { triggers })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triggers.is_empty()
+    }
+
+    /// Custom commands that `run` actions call
+    pub fn runs(&self) -> Vec<String> {
+        self.triggers
+            .iter()
+            .flat_map(|trigger| trigger.actions.iter())
+            .filter_map(|action| match action {
+                TriggerAction::Run(command) => Some(command.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The triggers matching `line`, in the order they are configured
+    pub fn check(&mut self, line: &str) -> Vec<Fired> {
+        let mut fired = Vec::<Fired>::new();
+        for trigger in &mut self.triggers {
+            if trigger.once && trigger.fired {
+                continue;
+            }
+            if let Some(captures) = trigger.pattern.captures(line) {
+                trigger.fired = true;
+                fired.push(Fired {
+                    pattern: trigger.pattern.as_str().to_string(),
+                    actions: trigger
+                        .actions
+                        .iter()
+                        .map(|action| expand(action, &captures))
+                        .collect(),
+                    env: environment(&trigger.pattern, &captures, line),
+                });
+            }
+        }
+        fired
+    }
+}
+
+/// Start `program` without waiting for it to finish
+pub fn exec(program: &[String], env: &[(String, String)]) -> Result<(), String> {
+    let mut child = Command::new(&program[0])
+        .args(&program[1..])
+        .envs(env.iter().cloned())
+        .spawn()
+        .map_err(|err| format!("Cannot run '{}': {}", program[0], err))?;
+    // Reap it once it exits
+    thread::spawn(move || {
+        let _ = child.wait();
+    });
+    Ok(())
+}
+
+fn expand(action: &TriggerAction, captures: &Captures) -> TriggerAction {
+    let expand_text = |text: &str| fill_captures(text, captures);
+    match action {
+        TriggerAction::Send(text) => TriggerAction::Send(expand_text(text)),
+        TriggerAction::Exec(program) => {
+            TriggerAction::Exec(program.iter().map(|arg| expand_text(arg)).collect())
+        }
+        action => action.clone(),
+    }
+}
+
+/// Replace `${1}` and `${name}` with captures, empty if they did not match. Any other `$` is
+/// kept, e.g. in NMEA sentences such as `$PMTK220,1000*1F`.
+fn fill_captures(text: &str, captures: &Captures) -> String {
+    let mut filled = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let group = &rest[start + 2..end];
+        let value = match group.parse::<usize>() {
+            Ok(index) => captures.get(index),
+            Err(_) => captures.name(group),
+        };
+        filled.push_str(&rest[..start]);
+        filled.push_str(value.map_or("", |found| found.as_str()));
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+fn environment(pattern: &Regex, captures: &Captures, line: &str) -> Vec<(String, String)> {
+    let mut env = vec![(String::from("TRIGGER_LINE"), line.to_string())];
+    for (index, name) in pattern.capture_names().enumerate() {
+        let value = captures
+            .get(index)
+            .map_or("", |found| found.as_str())
+            .to_string();
+        if let Some(name) = name {
+            env.push((format!("TRIGGER_{}", name.to_uppercase()), value.clone()));
+        }
+        env.push((format!("TRIGGER_{}", index), value));
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn triggers(toml_str: &str) -> Triggers {
+        #[derive(Deserialize)]
+        struct Config {
+            trigger: Vec<TriggerToml>,
+        }
+        let config: Config = toml::from_str(toml_str).unwrap();
+        Triggers::from_toml(&config.trigger).unwrap()
+    }
+
+    #[test]
+    fn should_fire_with_captures() {
+        let mut triggers = triggers(
+            r#"
+            [[trigger]]
+            pattern = 'PANIC: (?P<reason>\w+) at (\d+)'
+            send = "ACK ${2}"
+            exec = ["notify", "${reason}"]
+            exit_code = 3
+            stop = true
+            "#,
+        );
+
+        assert_eq!(Vec::<Fired>::new(), triggers.check("all good"));
+        let fired = triggers.check("PANIC: oops at 42");
+        assert_eq!(1, fired.len());
+        assert_eq!(
+            vec![
+                TriggerAction::Send(String::from("ACK 42")),
+                TriggerAction::Exec(vec![String::from("notify"), String::from("oops")]),
+                TriggerAction::ExitCode(3),
+                TriggerAction::Stop,
+            ],
+            fired[0].actions
+        );
+        assert!(fired[0]
+            .env
+            .contains(&(String::from("TRIGGER_REASON"), String::from("oops"))));
+        assert!(fired[0]
+            .env
+            .contains(&(String::from("TRIGGER_2"), String::from("42"))));
+    }
+
+    #[test]
+    fn should_only_replace_captures_in_braces() {
+        let mut triggers = triggers(
+            r#"
+            [[trigger]]
+            pattern = 'RATE (\d+)'
+            send = "$PMTK${1},$1abc*1F ${9} ${1"
+            "#,
+        );
+
+        let fired = triggers.check("RATE 220");
+        assert_eq!(
+            vec![TriggerAction::Send(String::from("$PMTK220,$1abc*1F  ${1"))],
+            fired[0].actions
+        );
+    }
+
+    #[test]
+    fn should_fire_once_triggers_once() {
+        let mut triggers = triggers(
+            r#"
+            [[trigger]]
+            pattern = 'Continue\? \[y/n\]'
+            send = "y"
+            once = true
+
+            [[trigger]]
+            pattern = "Continue"
+            run = "LOG CONTINUE"
+            "#,
+        );
+
+        assert_eq!(2, triggers.check("Continue? [y/n]").len());
+        assert_eq!(1, triggers.check("Continue? [y/n]").len());
+        assert_eq!(vec![String::from("LOG CONTINUE")], triggers.runs());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::input_output::escape::{self, EscapeError};
+
+/// Prefix for sending raw bytes, e.g. `:hex 02 41 03`
+const HEX_PREFIX: &str = ":hex";
+
+/// What gets appended to every line we transmit
+#[derive(Debug, PartialEq, Clone)]
+pub enum LineEnding {
+    None,
+    Lf,
+    Cr,
+    CrLf,
+    /// Any other terminator, written with escape sequences in the config, e.g. `\x03`
+    Custom(Vec<u8>),
+}
+
+impl LineEnding {
+    /// Accepts `none`, `LF`, `CR`, `CRLF` (case-insensitive), or anything else as custom bytes.
+    pub fn parse(value: &str) -> Result<LineEnding, EscapeError> {
+        let line_ending = match value.to_lowercase().as_str() {
+            "none" | "" => LineEnding::None,
+            "lf" => LineEnding::Lf,
+            "cr" => LineEnding::Cr,
+            "crlf" => LineEnding::CrLf,
+            _ => LineEnding::Custom(escape::parse_escapes(value)?),
+        };
+        Ok(line_ending)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            LineEnding::None => &[],
+            LineEnding::Lf => b"\n",
+            LineEnding::Cr => b"\r",
+            LineEnding::CrLf => b"\r\n",
+            LineEnding::Custom(bytes) => bytes,
+        }
+    }
+}
+
+/// Turn a line into the bytes to send, the same way everywhere a line is sent.
+///
+/// `:hex 02 41 03` sends exactly those bytes. Anything else has its escape sequences
+/// processed if `escape_sequences` is set, and gets `line_ending` appended.
+pub fn encode(
+    text: &str,
+    line_ending: &LineEnding,
+    escape_sequences: bool,
+) -> Result<Vec<u8>, EscapeError> {
+    if let Some(hex_str) = hex_payload(text) {
+        return escape::parse_hex(hex_str);
+    }
+
+    let mut bytes = if escape_sequences {
+        escape::parse_escapes(text)?
+    } else {
+        text.as_bytes().to_vec()
+    };
+    bytes.extend_from_slice(line_ending.bytes());
+    Ok(bytes)
+}
+
+/// The bytes part of `:hex 02 41 03`, or `None` if the text is not a hex command
+fn hex_payload(text: &str) -> Option<&str> {
+    let rest = text.strip_prefix(HEX_PREFIX)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// How transmitted data is echoed back on the terminal
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EchoFormat {
+    /// What was typed, e.g. `Tx: 'AT\r'`
+    Text,
+    /// The bytes that were sent, escaped, e.g. `Tx: 'AT\r\r\n'`
+    Escaped,
+    /// The bytes that were sent, in hex, e.g. `Tx: [41 54 0D 0A]`
+    Hex,
+    /// Don't echo anything
+    None,
+}
+
+impl EchoFormat {
+    pub fn parse(value: &str) -> Option<EchoFormat> {
+        match value.to_lowercase().as_str() {
+            "text" => Some(EchoFormat::Text),
+            "escaped" => Some(EchoFormat::Escaped),
+            "hex" => Some(EchoFormat::Hex),
+            "none" | "off" => Some(EchoFormat::None),
+            _ => None,
+        }
+    }
+
+    /// The line to print for a line sent with `encode()`, or `None` if nothing should be
+    /// printed. `:hex` lines are always echoed in hex, unless echo is off.
+    pub fn echo(&self, typed: &str, sent: &[u8]) -> Option<String> {
+        match hex_payload(typed) {
+            Some(_) if *self != EchoFormat::None => EchoFormat::Hex.format(typed, sent),
+            _ => self.format(typed, sent),
+        }
+    }
+
+    /// The line to print for a transmit, or `None` if nothing should be printed
+    pub fn format(&self, typed: &str, sent: &[u8]) -> Option<String> {
+        match self {
+            EchoFormat::Text => Some(format!("Tx: '{}'", typed)),
+            EchoFormat::Escaped => Some(format!("Tx: '{}'", escape::escape_bytes(sent))),
+            EchoFormat::Hex => Some(format!("Tx: [{}]", escape::hex_string(sent))),
+            EchoFormat::None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_named_and_custom_line_endings() {
+        assert_eq!(Ok(LineEnding::CrLf), LineEnding::parse("CRLF"));
+        assert_eq!(Ok(LineEnding::None), LineEnding::parse("none"));
+        assert_eq!(
+            Ok(LineEnding::Custom(vec![0x03, b'\r'])),
+            LineEnding::parse("\\x03\\r")
+        );
+    }
+
+    #[test]
+    fn should_format_echo_of_sent_bytes() {
+        let sent = b"AT\r\n";
+        assert_eq!(
+            Some(String::from("Tx: 'AT'")),
+            EchoFormat::Text.format("AT", sent)
+        );
+        assert_eq!(
+            Some(String::from("Tx: 'AT\\r\\n'")),
+            EchoFormat::Escaped.format("AT", sent)
+        );
+        assert_eq!(
+            Some(String::from("Tx: [41 54 0D 0A]")),
+            EchoFormat::Hex.format("AT", sent)
+        );
+        assert_eq!(None, EchoFormat::None.format("AT", sent));
+    }
+
+    #[test]
+    fn should_encode_hex_and_escaped_lines() {
+        let hex = encode(":hex 02 41 03", &LineEnding::CrLf, true).unwrap();
+        assert_eq!(vec![0x02, b'A', 0x03], hex);
+        assert_eq!(
+            Some(String::from("Tx: [02 41 03]")),
+            EchoFormat::Text.echo(":hex 02 41 03", &hex)
+        );
+        assert_eq!(None, EchoFormat::None.echo(":hex 02 41 03", &hex));
+
+        assert_eq!(
+            Ok(b"AT\r\n\r".to_vec()),
+            encode("AT\\r\\n", &LineEnding::Cr, true)
+        );
+        assert_eq!(
+            Ok(b"AT\\r\n".to_vec()),
+            encode("AT\\r", &LineEnding::Lf, false)
+        );
+    }
+}
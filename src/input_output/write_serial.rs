@@ -1,108 +1,771 @@
-use crate::input_output::read_serial::{IReadSerial, ReadError};
+use crate::custom_commands::{CustomCommand, CustomCommands, Step};
+use crate::device_test::runner::{TestRunner, TestTarget};
+use crate::device_test::{SuiteResult, TestSuite};
+use crate::input_output::background_reader::{BackgroundReader, Printer, RxLine};
+use crate::input_output::command_steps::{self, StepRunner, StepTarget, TxSettings};
+use crate::input_output::escape::EscapeError;
+use crate::input_output::history;
+use crate::input_output::latency::{Latency, LatencyStats};
+use crate::input_output::line_filter::LineFilter;
+use crate::input_output::log_file::LogFile;
+use crate::input_output::meta_command::{self, MetaCommand, PortSettings};
+use crate::input_output::output_format::{Direction, OutputFormatter};
+use crate::input_output::repl_helper::ReplHelper;
+use crate::input_output::response::{EndReason, Response, ResponseRule};
+use crate::input_output::scheduler::{
+    ScheduleCommand, ScheduleSpec, Scheduler, SendQueue, Transmission,
+};
+use crate::input_output::shutdown;
+use crate::input_output::stats::{self, SessionStats, StatsFormat};
+use crate::input_output::terminal_mode::TerminalMode;
+use crate::input_output::trigger::{self, TriggerAction, Triggers};
+use crate::input_output::tx_format::{self, LineEnding};
+use crate::parse_commands::ParseCommands;
+use crate::parse_config::{ParseConfig, ParsedTomlValues};
+use crate::script::rhai_script::{RhaiScript, ScriptContext};
 use crate::serial_port::serial_port_open::SerialPortOpen;
 
-use serde::Deserialize;
 use serialport::SerialPort;
 
+use regex::Regex;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::{Config, Editor, ExternalPrinter};
 
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, prelude::*, IsTerminal as _};
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use std::{fs::File, io::prelude::*};
+use std::time::{Duration, Instant};
 
-#[derive(Deserialize)]
-struct ConfigToml {
-    command: Command,
+/// `HELP <name>` shows the documentation of a single custom command. Any other line
+/// starting with it is sent, e.g. `help reset` for a device's own help.
+const HELP_PREFIX: &str = "HELP ";
+/// `:history <text>` lists previous input containing the text
+const HISTORY_PREFIX: &str = ":history";
+/// `:stats` or `:stats json` shows the session statistics
+const STATS_PREFIX: &str = ":stats";
+/// `:latency` shows the response times of each command
+const LATENCY_COMMAND: &str = ":latency";
+/// How long to wait before trying to reopen the port again on `:reconnect`
+const RECONNECT_RETRY_MS: u64 = 1000;
+/// How many times `:reconnect` tries to reopen the port before giving up
+const RECONNECT_ATTEMPTS: u32 = 30;
+/// How often headless mode checks whether all schedules are done
+const SCHEDULE_POLL_MS: u64 = 100;
+/// How often the REPL checks for SIGINT or SIGTERM while waiting at the prompt
+const SHUTDOWN_POLL_MS: u64 = 100;
+
+/// The open serial port, and the threads reading from it and sending schedules
+struct Link {
+    serial_port: Box<dyn SerialPort>,
+    reader: BackgroundReader,
+    /// Started by the first schedule
+    scheduler: Option<Scheduler>,
+    stats: Arc<Mutex<SessionStats>>,
+    /// Levels last set by the config or `:dtr` and `:rts`, restored on `:reconnect`
+    dtr: Option<bool>,
+    rts: Option<bool>,
+}
+
+impl Link {
+    fn scheduler(&mut self) -> &mut Scheduler {
+        let serial_port = &self.serial_port;
+        let reader = &self.reader;
+        let stats = &self.stats;
+        self.scheduler.get_or_insert_with(|| {
+            let scheduler_port = serial_port
+                .try_clone()
+                .expect("Cannot clone serial port for the scheduler");
+            Scheduler::start(scheduler_port, reader.command_label(), Arc::clone(stats))
+        })
+    }
+}
+
+/// Prints a line, e.g. above the prompt
+type PrintLine = Box<dyn FnMut(&str) + Send>;
+
+/// Formats sent and received data as configured in [output], and logs it.
+/// Shared with the thread printing unsolicited output.
+#[derive(Clone)]
+struct Transcript {
+    formatter: Arc<Mutex<OutputFormatter>>,
+    log_file: Option<Arc<Mutex<LogFile>>>,
+    filter: Arc<LineFilter>,
+    /// Text printed to a terminal is highlighted
+    highlight: bool,
+    /// Set once the session is started
+    triggers: Arc<Mutex<Option<TriggerSession>>>,
+    stats: Arc<Mutex<SessionStats>>,
+    stats_format: StatsFormat,
+}
+
+/// Carries out trigger actions in write mode. Lines and custom commands are sent by the
+/// scheduler thread, so the thread that received the line is not held up.
+struct TriggerSession {
+    triggers: Triggers,
+    queue: SendQueue,
+    /// Payloads of the custom commands that `run` actions call
+    runs: HashMap<String, Vec<Transmission>>,
+    tx_line_ending: LineEnding,
+    escape_sequences: bool,
+    exit_code: Option<i32>,
+    stopped: bool,
+}
+
+/// A line of the transcript. Text lines are printed without their timestamp, but logged
+/// with it; records have their own.
+struct Entry {
+    prefix: String,
+    output: String,
+    /// Received lines hidden by [filter] are only logged, if at all
+    shown: bool,
+}
+
+/// Carries out custom command steps on the REPL's port
+struct LinkTarget<'a> {
+    write_serial: &'a WriteSerial,
+    custom_commands: &'a Rc<CustomCommands>,
+    link: &'a mut Link,
+}
+
+/// How sending a line or running a custom command went, from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    /// Every response met its completion condition
+    Completed,
+    /// Something was received, but the response never completed
+    NoMatch,
+    /// Nothing was received in time, or a `wait_for` step timed out
+    Timeout,
+    /// Nothing was sent, e.g. an unknown command or invalid escape sequence
+    Failed,
+}
+
+impl Outcome {
+    /// Exit status of the non-interactive `write` command
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Completed => 0,
+            Outcome::Failed => 1,
+            Outcome::Timeout => 2,
+            Outcome::NoMatch => 3,
+        }
+    }
+
+    /// Exit status of a session that ended this way. An interrupted session exits with
+    /// `shutdown::INTERRUPTED_EXIT_CODE`, else a trigger's `exit_code` takes precedence.
+    pub fn session_exit_code(self, interrupted: bool, trigger_exit_code: Option<i32>) -> i32 {
+        match interrupted {
+            true => shutdown::INTERRUPTED_EXIT_CODE,
+            false => trigger_exit_code.unwrap_or_else(|| self.exit_code()),
+        }
+    }
+}
+
+impl Transcript {
+    /// A received line, labelled with the command sent before it, or as a record
+    fn rx(&self, command: Option<&str>, line: &RxLine) -> Entry {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.received(line.bytes.len());
+            if line.text.contains(char::REPLACEMENT_CHARACTER) {
+                stats.decode_error();
+            }
+        }
+        let mut formatter = self.formatter.lock().unwrap();
+        let mut entry = match formatter.record(Direction::Rx, &line.bytes) {
+            Some(record) => Entry::record(record),
+            None => {
+                let line = line.text.replace("\r", "\\r");
+                let output = match command {
+                    Some(command) => format!("Rx ({}): '{}'", command, line),
+                    None => format!("Rx: '{}'", line),
+                };
+                Entry {
+                    prefix: formatter.text_prefix(Direction::Rx),
+                    output,
+                    shown: true,
+                }
+            }
+        };
+        entry.shown = self.filter.is_shown(&line.text);
+        entry
+    }
+
+    /// Sent bytes as a record, or else the `echo` text, if any
+    fn tx(&self, echo: Option<String>, bytes: &[u8]) -> Option<Entry> {
+        self.stats.lock().unwrap().sent(bytes.len());
+        let mut formatter = self.formatter.lock().unwrap();
+        match formatter.record(Direction::Tx, bytes) {
+            Some(record) => Some(Entry::record(record)),
+            None => {
+                // Stamped even without an echo, for `delta = "transmit"`
+                let prefix = formatter.text_prefix(Direction::Tx);
+                echo.map(|output| Entry {
+                    prefix,
+                    output,
+                    shown: true,
+                })
+            }
+        }
+    }
+
+    /// What to print for `entry`, if anything
+    fn display(&self, entry: &Entry) -> Option<String> {
+        match (entry.shown, self.highlight) {
+            (false, _) => None,
+            (true, true) => Some(self.filter.highlight(&entry.output)),
+            (true, false) => Some(entry.output.clone()),
+        }
+    }
+
+    /// Carry out the actions of the triggers matching a received line
+    fn fire_triggers(&self, line: &str) {
+        if let Some(session) = self.triggers.lock().unwrap().as_mut() {
+            session.fire(line);
+        }
+    }
+
+    /// Whether a trigger stopped the session, or SIGINT or SIGTERM was received
+    fn stopped(&self) -> bool {
+        if shutdown::requested() {
+            return true;
+        }
+        let session = self.triggers.lock().unwrap();
+        session.as_ref().is_some_and(|session| session.stopped)
+    }
+
+    fn trigger_exit_code(&self) -> Option<i32> {
+        let session = self.triggers.lock().unwrap();
+        session.as_ref().and_then(|session| session.exit_code)
+    }
+
+    /// Flush the log and print the statistics, on stderr as stdout may be piped
+    fn end_session(&self) {
+        if let Some(log_file) = &self.log_file {
+            log_file.lock().unwrap().flush();
+        }
+        if let Some(report) = self.stats.lock().unwrap().report(self.stats_format) {
+            eprintln!("{}", report);
+        }
+    }
+
+    fn log(&self, entry: &Entry) {
+        if !entry.shown && !self.filter.log_filtered {
+            return;
+        }
+        if let Some(log_file) = &self.log_file {
+            let mut log_file = log_file.lock().unwrap();
+            log_file.write_line(&format!("{}{}", entry.prefix, entry.output));
+        }
+    }
+}
+
+impl TriggerSession {
+    fn fire(&mut self, line: &str) {
+        for fired in self.triggers.check(line) {
+            for action in &fired.actions {
+                match action {
+                    TriggerAction::Send(text) => {
+                        match tx_format::encode(text, &self.tx_line_ending, self.escape_sequences) {
+                            Ok(bytes) => self.queue.send(
+                                text,
+                                vec![Transmission::Send {
+                                    label: text.clone(),
+                                    bytes,
+                                }],
+                            ),
+                            Err(error) => println!("Trigger '{}': {}", fired.pattern, error),
+                        }
+                    }
+                    TriggerAction::Run(command) => {
+                        if let Some(payload) = self.runs.get(command) {
+                            self.queue.send(command, payload.clone());
+                        }
+                    }
+                    TriggerAction::Exec(program) => {
+                        if let Err(error) = trigger::exec(program, &fired.env) {
+                            println!("Trigger '{}': {}", fired.pattern, error);
+                        }
+                    }
+                    TriggerAction::ExitCode(code) => self.exit_code = Some(*code),
+                    TriggerAction::Stop => {
+                        println!("--- Stopped by trigger '{}' ---", fired.pattern);
+                        self.stopped = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Entry {
+    fn record(output: String) -> Self {
+        Entry {
+            prefix: String::new(),
+            output,
+            shown: true,
+        }
+    }
+}
+
+impl StepTarget for LinkTarget<'_> {
+    fn send_and_read(&mut self, text: &str, tx_settings: &TxSettings) -> Outcome {
+        self.write_serial
+            .send_and_read(text, tx_settings, self.link)
+    }
+
+    fn wait_for(&mut self, pattern: &Regex, timeout: Duration) -> bool {
+        self.write_serial.wait_for(pattern, timeout, self.link)
+    }
+
+    fn run_script(
+        &mut self,
+        path: &Path,
+        args: &[String],
+        running: &[String],
+    ) -> Result<(), Outcome> {
+        self.write_serial
+            .run_script(path, args, running, self.custom_commands, self.link)
+    }
 }
 
-#[derive(Deserialize)]
-struct Command {
-    command_array: Vec<Vec<String>>,
+impl TestTarget for LinkTarget<'_> {
+    fn exchange(&mut self, text: &str, tx_settings: &TxSettings) -> Result<Response, String> {
+        self.write_serial
+            .exchange(text, tx_settings, self.link)
+            .map_err(|error| error.to_string())
+    }
+
+    fn receive(&mut self, rule: &ResponseRule, label: &str) -> Response {
+        self.write_serial.receive(rule, label, self.link)
+    }
 }
 
-pub struct WriteSerial<'a> {
-    config_file_name: &'a str,
-    read_serial: Box<dyn IReadSerial + 'a>,
-    history_path: &'a str,
-    max_history_len: usize,
+pub struct WriteSerial {
+    config: ParsedTomlValues,
     show_all_commands_: HashSet<String>,
+    transcript: Transcript,
+    latency: Mutex<LatencyStats>,
 }
 
-impl<'a> WriteSerial<'a> {
-    pub fn new(config_file_name: &'a str, read_serial: Box<dyn IReadSerial + 'a>) -> Self {
+impl WriteSerial {
+    pub fn new(config_file_name: &str) -> Self {
         let mut show_all_commands_ = HashSet::<String>::new();
         show_all_commands_.insert("SHOW ALL COMMANDS".to_uppercase());
         show_all_commands_.insert("HELP".to_uppercase());
+        let config = ParseConfig::get_config(config_file_name);
+        let log_file = config.log.as_ref().map(|log_config| {
+            let log_file = LogFile::open(
+                log_config,
+                &config.serial_port,
+                config.output.format.header(),
+            )
+            .unwrap_or_else(|err| panic!("Cannot open log file: {}", err));
+            Arc::new(Mutex::new(log_file))
+        });
+        let formatter = OutputFormatter::new(&config.output, &config.serial_port);
+        let transcript = Transcript {
+            highlight: formatter.is_text() && io::stdout().is_terminal(),
+            formatter: Arc::new(Mutex::new(formatter)),
+            log_file,
+            filter: Arc::new(config.filter.clone()),
+            triggers: Arc::new(Mutex::new(None)),
+            stats: Arc::new(Mutex::new(SessionStats::new())),
+            stats_format: StatsFormat::default(),
+        };
         Self {
-            config_file_name,
-            read_serial,
-            history_path: "history.txt",
-            max_history_len: (1 << 7) + ((1 << 7) - 1),
+            config,
             show_all_commands_,
+            transcript,
+            latency: Mutex::new(LatencyStats::default()),
         }
     }
 
-    pub fn execute(&self, custom_command_file_name: Option<String>) {
-        let custom_commands = self.get_custom_commands(custom_command_file_name);
+    /// Filter and highlight with these as well as the [filter] section
+    pub fn with_filter(mut self, filter: LineFilter) -> Self {
+        self.config.filter.extend(filter);
+        self.transcript.filter = Arc::new(self.config.filter.clone());
+        self
+    }
 
-        let mut buffer_arr: [u8; 256] = [0; 256];
-        let serial_port_results = SerialPortOpen::get_serial_port(self.config_file_name);
-        let mut serial_port = serial_port_results.serial_port;
+    /// How the statistics are printed when the session ends
+    pub fn with_stats_format(mut self, stats_format: StatsFormat) -> Self {
+        self.transcript.stats_format = stats_format;
+        self
+    }
 
-        // Initial flush
-        while serial_port.read(&mut buffer_arr).is_ok() {
-            serial_port.flush().expect("Initial flush failed");
-            self.print_buffer(&buffer_arr);
-        }
+    /// Exit status set by a trigger, if any
+    pub fn trigger_exit_code(&self) -> Option<i32> {
+        self.transcript.trigger_exit_code()
+    }
 
-        let _timeout_duration = serial_port_results.timeout_duration;
-        let mut count = 0;
+    /// `[[write.schedule]]` entries from the config
+    pub fn config_schedules(&self) -> Vec<ScheduleSpec> {
+        self.config.write.schedules.clone()
+    }
 
-        let mut rustyline_editor = Editor::<()>::new();
-        rustyline_editor
-            .history_mut()
-            .set_max_len(self.max_history_len);
+    pub fn execute(&self, custom_command_file_name: Option<String>) {
+        let custom_commands = Rc::new(ParseCommands::get_commands(custom_command_file_name));
+
+        let history_config = &self.config.write.history;
+        let editor_config = Config::builder()
+            .max_history_size(history_config.max_len)
+            .history_ignore_dups(history_config.dedup)
+            .build();
+        let mut rustyline_editor =
+            Editor::<ReplHelper>::with_config(editor_config).expect("Cannot create line editor");
+        rustyline_editor.set_helper(Some(ReplHelper::new(
+            &custom_commands,
+            self.config.write.escape_sequences,
+        )));
+        self.load_history(&mut rustyline_editor);
+
+        // Print unsolicited output above the prompt, if the terminal allows it
+        let print_line: PrintLine = match rustyline_editor.create_external_printer() {
+            Ok(mut external_printer) => Box::new(move |line: &str| {
+                let _ = external_printer.print(format!("{}\n", line));
+            }),
+            Err(_) => Box::new(|line: &str| println!("{}", line)),
+        };
+        let mut link = self.open_link(print_line);
+        self.start_triggers(&custom_commands, &mut link);
+        for spec in &self.config.write.schedules {
+            self.add_schedule(&custom_commands, spec.clone(), &mut link);
+        }
+        println!("\n--- Press Ctrl + C to end the session ---");
+        println!("--- Type in `SHOW ALL COMMANDS` for all custom commands ---");
+        let at_prompt = Arc::new(AtomicBool::new(false));
+        self.watch_prompt(Arc::clone(&at_prompt));
 
         loop {
-            if count == 0 {
-                println!("\n--- Press Ctrl + C to end the session ---");
-                println!("--- Type in `SHOW ALL COMMANDS` for all custom commands ---");
+            if self.transcript.stopped() {
+                break;
             }
-            count = (count + 1) % 5;
 
-            let buffer_str = match self.get_input(&mut rustyline_editor) {
+            at_prompt.store(true, Ordering::SeqCst);
+            let input = self.get_input(&mut rustyline_editor);
+            at_prompt.store(false, Ordering::SeqCst);
+            let buffer_str = match input {
                 Ok(line) => line,
                 Err(_) => break,
             };
+            // Stopped while waiting for input
+            if self.transcript.stopped() {
+                break;
+            }
 
             let buffer_upper = buffer_str.to_uppercase();
             if self.show_all_commands_.contains(&buffer_upper) {
                 self.handle_show_all_command(&custom_commands);
-            } else if custom_commands.contains_key(&buffer_upper) {
-                self.handle_custom_commands(&custom_commands, &buffer_upper, &mut serial_port);
+            } else if let Some(custom_command) = buffer_upper
+                .strip_prefix(HELP_PREFIX)
+                .and_then(|name| custom_commands.get(name))
+            {
+                println!("{}", custom_command.help());
+            } else if buffer_str.trim().eq_ignore_ascii_case(LATENCY_COMMAND) {
+                self.handle_latency_command();
+            } else if let Some(format) = stats_format(&buffer_str) {
+                self.handle_stats_command(format);
+            } else if let Some(term) = history_term(&buffer_str) {
+                self.handle_history_command(&rustyline_editor, term);
+            } else if let Some(meta_command) = MetaCommand::parse(&buffer_str) {
+                link = match self.handle_meta_command(meta_command, link) {
+                    Some(link) => link,
+                    // The port could not be reopened
+                    None => return self.end_session(),
+                };
+            } else if let Some(schedule_command) = ScheduleCommand::parse(&buffer_str) {
+                self.handle_schedule_command(&custom_commands, schedule_command, &mut link);
+            } else if let Some((custom_command, args)) = custom_commands.find(&buffer_str) {
+                let _ =
+                    self.handle_custom_commands(&custom_commands, custom_command, &args, &mut link);
             } else {
-                self.write_and_read(&buffer_str, &mut serial_port);
+                self.write_and_read(&buffer_str, &mut link);
+            }
+        }
+
+        link.reader.stop();
+        self.end_session();
+    }
+
+    /// The prompt blocks until a key is pressed, so a signal or `stop` trigger received there
+    /// ends the process from another thread, after taking the terminal out of the prompt's raw
+    /// mode. Elsewhere the REPL loop notices it and ends the session itself.
+    fn watch_prompt(&self, at_prompt: Arc<AtomicBool>) {
+        let transcript = self.transcript.clone();
+        let terminal_mode = TerminalMode::save();
+        thread::spawn(move || loop {
+            if transcript.stopped() && at_prompt.load(Ordering::SeqCst) {
+                terminal_mode.restore();
+                println!();
+                transcript.end_session();
+                process::exit(
+                    Outcome::Completed
+                        .session_exit_code(shutdown::requested(), transcript.trigger_exit_code()),
+                );
+            }
+            thread::sleep(Duration::from_millis(SHUTDOWN_POLL_MS));
+        });
+    }
+
+    /// Send each of `sends`, then run each of `runs` (a custom command name, optionally
+    /// followed by its arguments), without prompting. Stops at the first one that doesn't
+    /// complete, and returns how it went.
+    ///
+    /// Then runs `schedules` until they have all been sent their number of times. Schedules
+    /// without a count run until the process is stopped.
+    pub fn execute_headless(
+        &self,
+        custom_command_file_name: Option<String>,
+        sends: &[String],
+        runs: &[String],
+        schedules: &[ScheduleSpec],
+    ) -> Outcome {
+        let custom_commands = Rc::new(ParseCommands::get_commands(custom_command_file_name));
+        let mut link = self.open_link(Box::new(|line: &str| println!("{}", line)));
+        self.start_triggers(&custom_commands, &mut link);
+
+        let mut outcome = Outcome::Completed;
+        for buffer_str in sends {
+            outcome = self.write_and_read(buffer_str, &mut link);
+            if outcome != Outcome::Completed || self.transcript.stopped() {
+                break;
+            }
+        }
+        if outcome == Outcome::Completed {
+            for run in runs {
+                outcome = match custom_commands.resolve(run) {
+                    Some((custom_command, args)) => {
+                        match self.handle_custom_commands(
+                            &custom_commands,
+                            custom_command,
+                            &args,
+                            &mut link,
+                        ) {
+                            Ok(outcome) | Err(outcome) => outcome,
+                        }
+                    }
+                    None => {
+                        println!("No custom command named '{}'", run);
+                        Outcome::Failed
+                    }
+                };
+                if outcome != Outcome::Completed || self.transcript.stopped() {
+                    break;
+                }
+            }
+        }
+        if outcome == Outcome::Completed && !schedules.is_empty() && !self.transcript.stopped() {
+            outcome = self.run_schedules(&custom_commands, schedules, &mut link);
+        }
+
+        link.reader.stop();
+        self.end_session();
+        outcome
+    }
+
+    /// Send a line or run a custom command `count` times, then print the percentiles and a
+    /// histogram of the response times. Returns the worst outcome.
+    pub fn execute_bench(
+        &self,
+        custom_command_file_name: Option<String>,
+        input: &str,
+        count: usize,
+    ) -> Outcome {
+        let custom_commands = Rc::new(ParseCommands::get_commands(custom_command_file_name));
+        let mut link = self.open_link(Box::new(|line: &str| println!("{}", line)));
+        let custom_command = custom_commands.find(input);
+        let command = match &custom_command {
+            Some((custom_command, _)) => custom_command.name.clone(),
+            None => input.to_string(),
+        };
+
+        let mut outcome = Outcome::Completed;
+        let mut runs = 0;
+        let mut completed = 0;
+        while runs < count && !self.transcript.stopped() {
+            let run_outcome = match &custom_command {
+                Some((custom_command, args)) => match self.handle_custom_commands(
+                    &custom_commands,
+                    custom_command,
+                    args,
+                    &mut link,
+                ) {
+                    Ok(outcome) | Err(outcome) => outcome,
+                },
+                None => self.write_and_read(input, &mut link),
+            };
+            outcome = outcome.max(run_outcome);
+            runs += 1;
+            if run_outcome == Outcome::Completed {
+                completed += 1;
+            }
+        }
+        link.reader.stop();
+
+        let latency = self.latency.lock().unwrap();
+        println!("\n--- Bench: {} run(s), {} complete ---", runs, completed);
+        for line in latency.report() {
+            println!("{}", line);
+        }
+        for line in latency.histogram(&command) {
+            println!("{}", line);
+        }
+        drop(latency);
+        self.transcript.end_session();
+        outcome
+    }
+
+    /// Print the response times and the statistics
+    fn end_session(&self) {
+        let latency = self.latency.lock().unwrap();
+        if self.transcript.stats_format == StatsFormat::Text && !latency.is_empty() {
+            eprintln!("Latency:");
+            for line in latency.report() {
+                eprintln!("  {}", line);
+            }
+        }
+        self.transcript.end_session();
+    }
+
+    fn run_schedules(
+        &self,
+        custom_commands: &CustomCommands,
+        schedules: &[ScheduleSpec],
+        link: &mut Link,
+    ) -> Outcome {
+        for spec in schedules {
+            if !self.add_schedule(custom_commands, spec.clone(), link) {
+                return Outcome::Failed;
+            }
+        }
+        while !link.scheduler().is_finished() {
+            if self.transcript.stopped() {
+                return Outcome::Completed;
+            }
+            thread::sleep(Duration::from_millis(SCHEDULE_POLL_MS));
+        }
+        // Let the response to the last one arrive
+        if let Some(timeout) = self.config.write.response.timeout {
+            thread::sleep(timeout);
+        }
+        Outcome::Completed
+    }
+
+    /// Open the port, discard what it already received, and start reading it in the background
+    fn open_link(&self, mut print_line: PrintLine) -> Link {
+        let transcript = self.transcript.clone();
+        let printer: Printer = Box::new(move |command: Option<&str>, line: &RxLine| {
+            let entry = transcript.rx(command, line);
+            if let Some(output) = transcript.display(&entry) {
+                print_line(&output);
+            }
+            transcript.log(&entry);
+            transcript.fire_triggers(&line.text);
+        });
+        if let Some(header) = self.config.output.format.header() {
+            println!("{}", header);
+        }
+
+        let mut buffer_arr: [u8; 256] = [0; 256];
+        let serial_port_results = SerialPortOpen::open_port(&self.config);
+        let mut serial_port = serial_port_results.serial_port;
+
+        // Initial flush
+        while let Ok(bytes_read) = serial_port.read(&mut buffer_arr) {
+            serial_port.flush().expect("Initial flush failed");
+            self.print_buffer(&buffer_arr[..bytes_read]);
+        }
+
+        let reader_port = serial_port
+            .try_clone()
+            .expect("Cannot clone serial port for background reading");
+        stats::report_on_signal(
+            Arc::clone(&self.transcript.stats),
+            self.transcript.stats_format,
+        );
+        Link {
+            serial_port,
+            reader: BackgroundReader::start(reader_port, printer),
+            scheduler: None,
+            stats: Arc::clone(&self.transcript.stats),
+            dtr: self.config.dtr,
+            rts: self.config.rts,
+        }
+    }
+
+    /// Start carrying out the `[[trigger]]` actions. `run` actions are worked out now, like
+    /// schedules.
+    fn start_triggers(&self, custom_commands: &CustomCommands, link: &mut Link) {
+        let triggers = &self.config.triggers;
+        if triggers.is_empty() {
+            return;
+        }
+        let mut runs = HashMap::<String, Vec<Transmission>>::new();
+        for command in triggers.runs() {
+            let payload = match custom_commands.resolve(&command) {
+                Some((custom_command, args)) => {
+                    self.custom_command_payload(custom_commands, custom_command, &args)
+                }
+                None => Err(String::from("No such custom command")),
+            };
+            match payload {
+                Ok(payload) => {
+                    runs.insert(command, payload);
+                }
+                Err(error) => println!("Triggers cannot run '{}': {}", command, error),
             }
         }
+
+        *self.transcript.triggers.lock().unwrap() = Some(TriggerSession {
+            triggers: triggers.clone(),
+            queue: link.scheduler().queue(),
+            runs,
+            tx_line_ending: self.config.write.tx_line_ending.clone(),
+            escape_sequences: self.config.write.escape_sequences,
+            exit_code: None,
+            stopped: false,
+        });
     }
 
-    pub fn get_input(&self, rustyline_editor: &mut Editor<()>) -> Result<String, ReadlineError> {
-        if rustyline_editor.load_history(self.history_path).is_err() {
+    /// Load the history once. New entries are appended to the file as they are typed.
+    fn load_history(&self, rustyline_editor: &mut Editor<ReplHelper>) {
+        let history_path = &self.config.write.history.path;
+        if rustyline_editor.load_history(history_path).is_err() {
             println!(
                 "No previous history yet. Will create history at: '{}'",
-                self.history_path
+                history_path.display()
             );
+            if let Some(dir) = history_path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
         }
+    }
 
+    pub fn get_input(
+        &self,
+        rustyline_editor: &mut Editor<ReplHelper>,
+    ) -> Result<String, ReadlineError> {
         println!("");
         let readline = rustyline_editor.readline(">>> ");
         match readline {
             Ok(line) => {
-                rustyline_editor.add_history_entry(line.as_str());
-                rustyline_editor.save_history(self.history_path).unwrap();
+                if rustyline_editor.add_history_entry(line.as_str()) {
+                    let history_path = &self.config.write.history.path;
+                    if let Err(err) = rustyline_editor.append_history(history_path) {
+                        println!(
+                            "Cannot save history to '{}': {}",
+                            history_path.display(),
+                            err
+                        );
+                    }
+                }
                 Ok(line)
             }
             Err(ReadlineError::Interrupted) => {
@@ -119,43 +782,82 @@ impl<'a> WriteSerial<'a> {
         }
     }
 
-    pub fn write_and_read(&self, buffer_str: &str, serial_port: &mut Box<dyn SerialPort>) {
-        println!("Tx: '{}'", buffer_str);
-        self.write_str(&buffer_str, serial_port);
-        self.print_read_results(serial_port);
+    fn write_and_read(&self, buffer_str: &str, link: &mut Link) -> Outcome {
+        self.send_and_read(buffer_str, &TxSettings::new(&self.config.write, None), link)
     }
 
-    fn write_str(&self, buffer_str: &str, serial_port: &mut Box<dyn SerialPort>) {
-        let mut buffer_u8 = Vec::<u8>::new();
-        for byte in buffer_str.bytes() {
-            buffer_u8.push(byte);
-        }
-        buffer_u8.push('\n' as u8);
+    fn send_and_read(
+        &self,
+        buffer_str: &str,
+        tx_settings: &TxSettings,
+        link: &mut Link,
+    ) -> Outcome {
+        let response = match self.exchange(buffer_str, tx_settings, link) {
+            Ok(response) => response,
+            Err(error) => {
+                println!("Nothing sent. {}", error);
+                return Outcome::Failed;
+            }
+        };
 
-        let _write_result = serial_port.write(&buffer_u8);
-        serial_port.flush().expect("Flush after write() failed");
-        thread::sleep(Duration::from_millis(200));
+        if response.ended_by == EndReason::Timeout {
+            self.transcript.stats.lock().unwrap().timed_out();
+        }
+        command_steps::response_outcome(&response, &tx_settings.response)
     }
 
-    fn print_read_results(&self, serial_port: &mut Box<dyn SerialPort>) {
-        let lines_read = self.read_serial.read_serial_line(serial_port);
-        match lines_read {
-            Err(error) => {
-                let msg = match error {
-                    ReadError::Timeout => "Response timed out!",
-                    ReadError::NoResponse => "No response!",
-                };
-                println!("{}", msg);
-            }
-            Ok(line) => {
-                let replaced_line = line.replace("\r", "\\r");
-                for cur_line in replaced_line.split("\n") {
-                    if !cur_line.is_empty() {
-                        println!("Rx: '{}'", cur_line)
-                    }
-                }
+    /// Send a line and collect its response, printing both
+    fn exchange(
+        &self,
+        buffer_str: &str,
+        tx_settings: &TxSettings,
+        link: &mut Link,
+    ) -> Result<Response, EscapeError> {
+        let buffer_u8 = tx_format::encode(
+            buffer_str,
+            &tx_settings.line_ending,
+            self.config.write.escape_sequences,
+        )?;
+
+        // Waits for a schedule being sent, so it is echoed right before it goes out
+        let responses = link.reader.begin_response(buffer_str);
+        let echo = self.config.write.local_echo.echo(buffer_str, &buffer_u8);
+        if let Some(entry) = self.transcript.tx(echo, &buffer_u8) {
+            self.print_data(&entry);
+        }
+        let sent_at = Instant::now();
+        self.write_bytes(&buffer_u8, &mut link.serial_port);
+        let response = tx_settings.response.collect(&responses, |line| {
+            self.print_received(line);
+        });
+        let latency = Latency {
+            first_byte: link
+                .reader
+                .end_response()
+                .map(|first_byte| first_byte.saturating_duration_since(sent_at)),
+            complete: response.last_line_at.map_or_else(
+                || sent_at.elapsed(),
+                |at| at.saturating_duration_since(sent_at),
+            ),
+        };
+
+        // A response that timed out says nothing about how fast the device is
+        if !matches!(
+            response.ended_by,
+            EndReason::Timeout | EndReason::Disconnected
+        ) {
+            let command = tx_settings.command.as_deref().unwrap_or(buffer_str);
+            self.latency.lock().unwrap().record(command, latency);
+            if self.config.write.show_latency {
+                println!("--- Latency: {} ---", latency.describe());
             }
         }
+        Ok(response)
+    }
+
+    fn write_bytes(&self, buffer_u8: &[u8], serial_port: &mut Box<dyn SerialPort>) {
+        let _write_result = serial_port.write(buffer_u8);
+        serial_port.flush().expect("Flush after write() failed");
     }
 
     fn print_buffer(&self, buf: &[u8]) {
@@ -164,65 +866,462 @@ impl<'a> WriteSerial<'a> {
             let deref_byte: u8 = *byte;
             buffer_str.push(deref_byte as char);
         }
-        println!("Rx: '{}'", buffer_str);
+        let line = RxLine {
+            text: buffer_str,
+            bytes: buf.to_vec(),
+        };
+        self.print_data(&self.transcript.rx(None, &line));
     }
 
-    fn get_custom_commands(
-        &self,
-        custom_command_file_name: Option<String>,
-    ) -> HashMap<String, Vec<String>> {
-        let mut hashmap = HashMap::<String, Vec<String>>::new();
+    /// Print and log a received line, then fire the triggers it matches
+    fn print_received(&self, line: &RxLine) {
+        self.print_data(&self.transcript.rx(None, line));
+        self.transcript.fire_triggers(&line.text);
+    }
 
-        if let Some(file_name) = custom_command_file_name {
-            let path: PathBuf = PathBuf::from(file_name);
-            let mut file = File::open(&path).expect(&format!("Cannot open: '{}'", path.display()));
-            let mut file_data = String::new();
-            file.read_to_string(&mut file_data).unwrap();
+    /// Print sent or received data, and log it if a log file is configured
+    fn print_data(&self, entry: &Entry) {
+        if let Some(output) = self.transcript.display(entry) {
+            println!("{}", output);
+        }
+        self.transcript.log(entry);
+    }
 
-            let config_toml: ConfigToml =
-                toml::from_str(&file_data).expect("Cannot get values from TOML file");
+    fn handle_show_all_command(&self, custom_commands: &CustomCommands) {
+        if custom_commands.is_empty() {
+            println!("No custom commands. Load some with `--commands <file>`");
+            return;
+        }
 
-            let commands_vec = config_toml.command.command_array;
-            for vec_str in commands_vec {
-                let mut iter = vec_str.iter();
-                if let Some(shortcut_command) = iter.next() {
-                    let shortcut_command = shortcut_command.to_uppercase();
-                    let commands: Vec<String> = iter.map(|str1| String::from(str1)).collect();
-                    hashmap.insert(String::from(shortcut_command), commands);
-                }
+        let mut group = "";
+        for custom_command in custom_commands.sorted() {
+            if custom_command.group != group {
+                group = &custom_command.group;
+                println!("[{}]", group);
+            }
+            match &custom_command.description {
+                Some(description) => println!("  {} - {}", custom_command.usage(), description),
+                None => println!("  {}", custom_command.usage()),
             }
         }
+        println!("Type `HELP <name>` for the steps of a command");
+    }
 
-        hashmap
+    fn handle_latency_command(&self) {
+        let latency = self.latency.lock().unwrap();
+        if latency.is_empty() {
+            println!("No responses yet");
+        }
+        for line in latency.report() {
+            println!("{}", line);
+        }
     }
 
-    fn handle_show_all_command(&self, custom_commands: &HashMap<String, Vec<String>>) {
-        for key in custom_commands.keys() {
-            println!("Command: '{}'", key);
-            let empty_vec = Vec::<String>::new();
-            let iterator = custom_commands.get(key).unwrap_or(&empty_vec).iter();
-            for (index, command) in iterator.enumerate() {
-                println!("  {}. '{}'", index + 1, command);
+    fn handle_stats_command(&self, format: Result<StatsFormat, String>) {
+        let report = format.map(|format| self.transcript.stats.lock().unwrap().report(format));
+        match report {
+            Ok(Some(report)) => println!("{}", report),
+            Ok(None) => {}
+            Err(error) => println!("{}", error),
+        }
+    }
+
+    fn handle_history_command(&self, rustyline_editor: &Editor<ReplHelper>, term: &str) {
+        let found = history::search(rustyline_editor.history(), term.trim());
+        if found.is_empty() {
+            println!("Nothing in history matches '{}'", term.trim());
+        }
+        for (number, entry) in found {
+            println!("{:>5}  {}", number, entry);
+        }
+    }
+
+    /// Change or show the port settings. Returns the link to use from now on, which is a
+    /// new one after `:reconnect`, or `None` if the port could not be reopened.
+    fn handle_meta_command(
+        &self,
+        meta_command: Result<MetaCommand, String>,
+        mut link: Link,
+    ) -> Option<Link> {
+        let meta_command = match meta_command {
+            Ok(meta_command) => meta_command,
+            Err(error) => {
+                println!("{}", error);
+                return Some(link);
+            }
+        };
+
+        match meta_command {
+            MetaCommand::Reconnect => link = self.reconnect(link)?,
+            MetaCommand::Status => {}
+            _ => {
+                if let Err(error) = meta_command.apply(&mut link.serial_port) {
+                    println!("Cannot apply setting: {}", error);
+                    return Some(link);
+                }
+                match meta_command {
+                    MetaCommand::Dtr(level) => link.dtr = Some(level),
+                    MetaCommand::Rts(level) => link.rts = Some(level),
+                    _ => {}
+                }
             }
         }
+        println!("{}", meta_command::port_status(&mut link.serial_port));
+        Some(link)
     }
 
-    fn handle_custom_commands(
+    fn handle_schedule_command(
         &self,
-        custom_commands: &HashMap<String, Vec<String>>,
-        buffer_upper: &str,
-        serial_port: &mut Box<dyn SerialPort>,
+        custom_commands: &CustomCommands,
+        schedule_command: Result<ScheduleCommand, String>,
+        link: &mut Link,
     ) {
-        let empty_vec = Vec::<String>::new();
-        let vec_command = custom_commands.get(buffer_upper).unwrap_or(&empty_vec);
-        for command in vec_command {
-            self.write_and_read(&command, serial_port);
-            let last_elem = command.split(" ").last().unwrap();
-            let time_sleep_millis = match last_elem.parse::<u64>() {
-                Ok(time) => time >> 1,
-                Err(_) => 500,
-            };
-            thread::sleep(Duration::from_millis(time_sleep_millis));
+        match schedule_command {
+            Err(error) => println!("{}", error),
+            Ok(ScheduleCommand::Every(spec)) => {
+                self.add_schedule(custom_commands, spec, link);
+            }
+            Ok(ScheduleCommand::List) => {
+                let schedules = match &link.scheduler {
+                    Some(scheduler) => scheduler.list(),
+                    None => Vec::new(),
+                };
+                if schedules.is_empty() {
+                    println!("No active schedules");
+                }
+                for schedule in schedules {
+                    println!("{}", schedule);
+                }
+            }
+            Ok(ScheduleCommand::Cancel(Some(id))) => {
+                match link
+                    .scheduler
+                    .as_ref()
+                    .map(|scheduler| scheduler.cancel(id))
+                {
+                    Some(true) => println!("Cancelled schedule {}", id),
+                    _ => println!("No active schedule {}", id),
+                }
+            }
+            Ok(ScheduleCommand::Cancel(None)) => {
+                let count = link
+                    .scheduler
+                    .as_ref()
+                    .map_or(0, |scheduler| scheduler.cancel_all());
+                println!("Cancelled {} schedule(s)", count);
+            }
+        }
+    }
+
+    /// Work out what to send and start sending it. Returns false if it cannot be scheduled.
+    fn add_schedule(
+        &self,
+        custom_commands: &CustomCommands,
+        spec: ScheduleSpec,
+        link: &mut Link,
+    ) -> bool {
+        let payload = match custom_commands.find(&spec.input) {
+            Some((custom_command, args)) => {
+                self.custom_command_payload(custom_commands, custom_command, &args)
+            }
+            None => tx_format::encode(
+                &spec.input,
+                &self.config.write.tx_line_ending,
+                self.config.write.escape_sequences,
+            )
+            .map(|bytes| {
+                vec![Transmission::Send {
+                    label: spec.input.clone(),
+                    bytes,
+                }]
+            })
+            .map_err(|error| error.to_string()),
+        };
+        match payload {
+            Ok(payload) => {
+                let description = format!("{}: {}", spec.timing, spec.input);
+                let id = link.scheduler().add(spec, payload);
+                println!("--- Schedule {} started, {} ---", id, description);
+                true
+            }
+            Err(error) => {
+                println!("Cannot schedule '{}': {}", spec.input, error);
+                false
+            }
+        }
+    }
+
+    /// The bytes and pauses of a custom command, for the scheduler. Steps that need to see
+    /// responses cannot be scheduled.
+    fn custom_command_payload(
+        &self,
+        custom_commands: &CustomCommands,
+        custom_command: &CustomCommand,
+        args: &[String],
+    ) -> Result<Vec<Transmission>, String> {
+        let values = custom_command.bind_args(args)?;
+        let line_ending = TxSettings::new(&self.config.write, Some(custom_command)).line_ending;
+        let mut payload = Vec::<Transmission>::new();
+        for step in &custom_command.steps {
+            match step {
+                Step::Send { text, delay } => {
+                    let text = custom_command.expand(text, &values);
+                    let bytes =
+                        tx_format::encode(&text, &line_ending, self.config.write.escape_sequences)
+                            .map_err(|error| error.to_string())?;
+                    payload.push(Transmission::Send { label: text, bytes });
+                    payload.push(Transmission::Pause(
+                        delay.unwrap_or(self.config.write.step_delay),
+                    ));
+                }
+                Step::Sleep(duration) => payload.push(Transmission::Pause(*duration)),
+                Step::Call { name, args } => {
+                    // Checked when the commands were loaded
+                    let callee = custom_commands.get(name).unwrap();
+                    let callee_args: Vec<String> = args
+                        .iter()
+                        .map(|arg| custom_command.expand(arg, &values))
+                        .collect();
+                    payload.extend(self.custom_command_payload(
+                        custom_commands,
+                        callee,
+                        &callee_args,
+                    )?);
+                }
+                Step::WaitFor { .. } | Step::Script { .. } => {
+                    return Err(format!(
+                        "'{}' has `wait_for` or `script` steps, which cannot be scheduled",
+                        custom_command.name
+                    ));
+                }
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Close the port and open it again, keeping the settings changed since it was opened.
+    /// Keeps trying for a while, e.g. until a USB adapter is plugged back in. Returns `None`
+    /// if it gave up, or Ctrl + C was pressed.
+    fn reconnect(&self, link: Link) -> Option<Link> {
+        let Link {
+            serial_port,
+            mut reader,
+            scheduler,
+            stats,
+            dtr,
+            rts,
+        } = link;
+        reader.stop();
+        let settings = PortSettings::read(serial_port.as_ref())
+            .ok()
+            .map(|settings| settings.with_output_lines(dtr, rts));
+        drop(serial_port);
+
+        let mut attempts = 0;
+        let mut serial_port = loop {
+            attempts += 1;
+            match SerialPortOpen::try_open_port(&self.config) {
+                Ok(serial_port_results) => break serial_port_results.serial_port,
+                Err(error) if attempts >= RECONNECT_ATTEMPTS || shutdown::requested() => {
+                    println!(
+                        "Cannot reopen '{}': {}. Giving up after {} attempt(s)",
+                        self.config.serial_port, error, attempts
+                    );
+                    return None;
+                }
+                Err(error) => {
+                    println!(
+                        "Cannot reopen '{}': {}. Retrying, Ctrl + C to give up...",
+                        self.config.serial_port, error
+                    );
+                    thread::sleep(Duration::from_millis(RECONNECT_RETRY_MS));
+                }
+            }
+        };
+        if let Some(settings) = settings {
+            if let Err(error) = settings.apply(&mut serial_port) {
+                println!("Cannot restore settings: {}", error);
+            }
+        }
+
+        let reader_port = serial_port
+            .try_clone()
+            .expect("Cannot clone serial port for background reading");
+        reader.restart(reader_port);
+        if let Some(scheduler) = &scheduler {
+            let scheduler_port = serial_port
+                .try_clone()
+                .expect("Cannot clone serial port for the scheduler");
+            scheduler.set_port(scheduler_port);
         }
+        println!("--- Reconnected ---");
+        stats.lock().unwrap().reconnected();
+        Some(Link {
+            serial_port,
+            reader,
+            scheduler,
+            stats,
+            dtr,
+            rts,
+        })
+    }
+
+    /// Run every step of a custom command. Returns the worst outcome of its sends, or
+    /// `Err` if a step failed and the remaining steps were skipped.
+    fn handle_custom_commands(
+        &self,
+        custom_commands: &Rc<CustomCommands>,
+        custom_command: &CustomCommand,
+        args: &[String],
+        link: &mut Link,
+    ) -> Result<Outcome, Outcome> {
+        let mut target = LinkTarget {
+            write_serial: self,
+            custom_commands,
+            link,
+        };
+        StepRunner::new(custom_commands, &self.config.write, Vec::new()).run(
+            &mut target,
+            custom_command,
+            args,
+        )
+    }
+
+    /// Run a Rhai script against the port. Returns `Err` if the script failed.
+    /// `running` holds the custom commands that led to it.
+    fn run_script(
+        &self,
+        path: &Path,
+        args: &[String],
+        running: &[String],
+        custom_commands: &Rc<CustomCommands>,
+        link: &mut Link,
+    ) -> Result<(), Outcome> {
+        let serial_port = link
+            .serial_port
+            .try_clone()
+            .expect("Cannot clone serial port for the script");
+        let received = link
+            .reader
+            .begin_response(&format!("script {}", path.display()));
+        let context = ScriptContext::new(
+            serial_port,
+            received,
+            self.config.write.clone(),
+            Rc::clone(custom_commands),
+        );
+        context.borrow_mut().set_running(running.to_vec());
+
+        let result = match RhaiScript::load(path, context) {
+            Ok(mut script) => script.run(args).map_err(|error| {
+                if script.timed_out() {
+                    (error, Outcome::Timeout)
+                } else {
+                    (error, Outcome::Failed)
+                }
+            }),
+            Err(error) => Err((error, Outcome::Failed)),
+        };
+        link.reader.end_response();
+        result.map_err(|(error, outcome)| {
+            println!("--- Script failed: {} ---", error);
+            outcome
+        })
+    }
+
+    /// Print received lines until one matches `pattern`. Returns false on timeout.
+    fn wait_for(&self, pattern: &Regex, timeout: Duration, link: &mut Link) -> bool {
+        let rule = ResponseRule {
+            until_regex: Some(pattern.clone()),
+            timeout: Some(timeout),
+            ..ResponseRule::default()
+        };
+        self.receive(&rule, &format!("wait for /{}/", pattern), link)
+            .ended_by
+            == EndReason::Matched
+    }
+
+    /// Print received lines until `rule` says to stop, without sending anything
+    fn receive(&self, rule: &ResponseRule, label: &str, link: &mut Link) -> Response {
+        let responses = link.reader.begin_response(label);
+        let response = rule.collect(&responses, |line| {
+            self.print_received(line);
+        });
+        link.reader.end_response();
+        response
+    }
+
+    /// Run the cases of each suite on the port, then print the statistics
+    pub fn execute_tests(
+        &self,
+        custom_command_file_name: Option<String>,
+        suites: &[TestSuite],
+    ) -> Vec<SuiteResult> {
+        let custom_commands = Rc::new(ParseCommands::get_commands(custom_command_file_name));
+        let mut link = self.open_link(Box::new(|line: &str| println!("{}", line)));
+        let mut target = LinkTarget {
+            write_serial: self,
+            custom_commands: &custom_commands,
+            link: &mut link,
+        };
+        let results =
+            TestRunner::new(&custom_commands, &self.config.write).run(&mut target, suites);
+
+        link.reader.stop();
+        self.end_session();
+        results
+    }
+}
+
+/// The text part of `:history <text>`, or `None` if the input is not a history command
+fn history_term(buffer_str: &str) -> Option<&str> {
+    let rest = buffer_str.strip_prefix(HISTORY_PREFIX)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// The format asked for by `:stats [text|json]`, or `None` if the input is not a stats
+/// command
+fn stats_format(buffer_str: &str) -> Option<Result<StatsFormat, String>> {
+    let rest = buffer_str.trim().strip_prefix(STATS_PREFIX)?;
+    match rest.trim() {
+        "" => Some(Ok(StatsFormat::Text)),
+        format if rest.starts_with(char::is_whitespace) => Some(format.parse()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_exit_with_the_code_of_the_worst_outcome() {
+        let exit_codes: Vec<i32> = [
+            Outcome::Completed,
+            Outcome::Failed,
+            Outcome::Timeout,
+            Outcome::NoMatch,
+        ]
+        .iter()
+        .map(|outcome| outcome.exit_code())
+        .collect();
+        assert_eq!(vec![0, 1, 2, 3], exit_codes);
+
+        let worst = [Outcome::Completed, Outcome::NoMatch, Outcome::Timeout]
+            .iter()
+            .fold(Outcome::Completed, |worst, outcome| worst.max(*outcome));
+        assert_eq!(2, worst.exit_code());
+    }
+
+    #[test]
+    fn should_let_interruptions_and_triggers_override_the_outcome() {
+        assert_eq!(3, Outcome::NoMatch.session_exit_code(false, None));
+        assert_eq!(7, Outcome::NoMatch.session_exit_code(false, Some(7)));
+        assert_eq!(130, Outcome::Completed.session_exit_code(true, Some(7)));
     }
 }
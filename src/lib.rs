@@ -1,13 +1,23 @@
+pub mod custom_commands;
+pub mod device_test;
 pub mod input_output;
+pub mod parse_commands;
 pub mod parse_config;
+pub mod script;
 pub mod serial_port;
 
 pub mod factory;
 
+use device_test::{report, TestSuite};
 use factory::Factory;
+use input_output::line_filter::{FilterArgs, LineFilter};
+use input_output::scheduler::ScheduleSpec;
+use input_output::shutdown;
+use input_output::stats::StatsFormat;
+use input_output::stop_condition::StopArgs;
+use input_output::write_serial::Outcome;
 
-use std::thread;
-use std::time::Duration;
+use std::path::Path;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -29,6 +39,16 @@ enum Cli {
         /// Config file path.
         #[structopt(short = "-c", long = "--config")]
         config: Option<String>,
+        #[structopt(flatten)]
+        filter: FilterArgs,
+        #[structopt(flatten)]
+        stop: StopArgs,
+        /// How the session statistics are printed when it ends: text, json or none
+        #[structopt(long = "--stats", default_value = "text")]
+        stats: StatsFormat,
+        /// Record changes of the CTS, DSR, RI and CD lines among the lines received
+        #[structopt(long = "--watch-lines")]
+        watch_lines: bool,
     },
     /// Write to a serial port, with custom commands
     Write {
@@ -38,39 +58,195 @@ enum Cli {
         /// Custom command file path
         #[structopt(long = "--commands")]
         commands: Option<String>,
+        /// Send this line and wait for its response instead of starting the prompt.
+        /// Can be given more than once.
+        #[structopt(long = "--send")]
+        send: Vec<String>,
+        /// Run this custom command, with any arguments, instead of starting the prompt.
+        /// Can be given more than once. Runs after all `--send` lines.
+        #[structopt(long = "--run")]
+        run: Vec<String>,
+        /// Schedule a line or custom command instead of starting the prompt, like `:every`,
+        /// e.g. `--every "500 x10 READ TEMP"`. Can be given more than once.
+        #[structopt(long = "--every")]
+        every: Vec<String>,
+        /// Run the schedules from the config instead of starting the prompt
+        #[structopt(long = "--schedule")]
+        schedule: bool,
+        #[structopt(flatten)]
+        filter: FilterArgs,
+        /// How the session statistics are printed when it ends: text, json or none
+        #[structopt(long = "--stats", default_value = "text")]
+        stats: StatsFormat,
+    },
+    /// Send a line or run a custom command repeatedly, and report its response times
+    Bench {
+        /// Config file path.
+        #[structopt(short = "-c", long = "--config")]
+        config: Option<String>,
+        /// Custom command file path
+        #[structopt(long = "--commands")]
+        commands: Option<String>,
+        /// How many times to send it
+        #[structopt(short = "-n", long = "--count", default_value = "100")]
+        count: usize,
+        /// How the session statistics are printed when it ends: text, json or none
+        #[structopt(long = "--stats", default_value = "text")]
+        stats: StatsFormat,
+        /// Line to send, or custom command with any arguments
+        input: String,
+    },
+    /// Run a script file against a serial port
+    Run {
+        /// Config file path.
+        #[structopt(short = "-c", long = "--config")]
+        config: Option<String>,
+        /// Custom command file path, for `run_command()` in Rhai scripts
+        #[structopt(long = "--commands")]
+        commands: Option<String>,
+        /// Script file path. `.rhai` files are run as Rhai scripts.
+        script: String,
+    },
+    /// Run test cases against a device, and report which passed
+    Test {
+        /// Config file path.
+        #[structopt(short = "-c", long = "--config")]
+        config: Option<String>,
+        /// Custom command file path, for `run` steps
+        #[structopt(long = "--commands")]
+        commands: Option<String>,
+        /// Write a JUnit XML report to this path
+        #[structopt(long = "--junit")]
+        junit: Option<String>,
+        /// Write a JSON report to this path
+        #[structopt(long = "--json")]
+        json: Option<String>,
+        /// Test case files
+        #[structopt(required = true)]
+        files: Vec<String>,
     },
 }
 
-/// Start our CLI terminal.
-pub fn execute() {
+/// Start our CLI terminal. Returns the exit code of the process.
+pub fn execute() -> i32 {
     let args = Cli::from_args();
     match args {
-        Cli::Read { config } => {
+        Cli::Read {
+            config,
+            filter,
+            stop,
+            stats,
+            watch_lines,
+        } => {
+            let filter = parse_filter_args(&filter);
+            let config_file_path: String = config.unwrap_or(String::from(""));
+            shutdown::install();
+            let read_serial = Factory::create_read_serial(&config_file_path)
+                .with_filter(filter)
+                .with_stop_args(stop)
+                .with_stats_format(stats)
+                .with_watch_lines(watch_lines);
+            read_serial.execute()
+        }
+
+        Cli::Write {
+            config,
+            commands,
+            send,
+            run,
+            every,
+            schedule,
+            filter,
+            stats,
+        } => {
+            let filter = parse_filter_args(&filter);
             let config_file_path: String = config.unwrap_or(String::from(""));
-            let mut handlers = Vec::<thread::JoinHandle<()>>::new();
-            handlers.push(thread::spawn(move || {
-                let read_serial = Factory::create_read_serial(&config_file_path);
-                read_serial.execute();
-            }));
+            let write_serial = Factory::create_write_serial(&config_file_path)
+                .with_filter(filter)
+                .with_stats_format(stats);
 
-            handlers.push(thread::spawn(|| {
-                const TIME_BETWEEN_MSG_SECONDS: u64 = 10;
-                loop {
-                    println!("Press Ctrl + C to end the session");
-                    thread::sleep(Duration::from_secs(TIME_BETWEEN_MSG_SECONDS));
+            let mut schedules = Vec::<ScheduleSpec>::new();
+            for spec in &every {
+                match ScheduleSpec::parse_every(spec) {
+                    Ok(spec) => schedules.push(spec),
+                    Err(error) => {
+                        println!("Invalid --every '{}': {}", spec, error);
+                        return 1;
+                    }
                 }
-            }));
+            }
+            if schedule {
+                schedules.extend(write_serial.config_schedules());
+            }
 
-            for handler in handlers {
-                handler.join().expect("Handler did not join()");
+            shutdown::install();
+            if send.is_empty() && run.is_empty() && every.is_empty() && !schedule {
+                write_serial.execute(commands);
+                Outcome::Completed
+                    .session_exit_code(shutdown::requested(), write_serial.trigger_exit_code())
+            } else {
+                let outcome = write_serial.execute_headless(commands, &send, &run, &schedules);
+                outcome.session_exit_code(shutdown::requested(), write_serial.trigger_exit_code())
             }
         }
 
-        Cli::Write { config, commands } => {
+        Cli::Bench {
+            config,
+            commands,
+            count,
+            stats,
+            input,
+        } => {
+            let config_file_path: String = config.unwrap_or(String::from(""));
+            let write_serial =
+                Factory::create_write_serial(&config_file_path).with_stats_format(stats);
+            shutdown::install();
+            let outcome = write_serial.execute_bench(commands, &input, count);
+            outcome.session_exit_code(shutdown::requested(), None)
+        }
+
+        Cli::Run {
+            config,
+            commands,
+            script,
+        } => {
+            let config_file_path: String = config.unwrap_or(String::from(""));
+            let script_runner = Factory::create_script_runner(&config_file_path);
+            shutdown::install();
+            let outcome = script_runner.execute(&script, commands);
+            outcome.session_exit_code(shutdown::requested(), None)
+        }
+
+        Cli::Test {
+            config,
+            commands,
+            junit,
+            json,
+            files,
+        } => {
+            let suites: Vec<TestSuite> = files
+                .iter()
+                .map(|file| TestSuite::load(Path::new(file)))
+                .collect();
             let config_file_path: String = config.unwrap_or(String::from(""));
             let write_serial = Factory::create_write_serial(&config_file_path);
 
-            write_serial.execute(commands);
+            shutdown::install();
+            let results = write_serial.execute_tests(commands, &suites);
+            println!("\n{}", report::summary(&results));
+            report::write_reports(&results, junit.as_deref(), json.as_deref());
+            let outcome = match report::all_passed(&results) {
+                true => Outcome::Completed,
+                false => Outcome::Failed,
+            };
+            outcome.session_exit_code(shutdown::requested(), None)
         }
     }
 }
+
+/// The rules of `--include`, `--exclude` and `--highlight`. Invalid ones are rejected before
+/// the config is read or the port opened, like an invalid [filter] section.
+fn parse_filter_args(filter_args: &FilterArgs) -> LineFilter {
+    LineFilter::from_args(filter_args)
+        .unwrap_or_else(|err| panic!("Invalid filter arguments: {}", err))
+}
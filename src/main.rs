@@ -1,3 +1,5 @@
+use std::process;
+
 fn main() {
-    serial_port_reader_writer::execute();
+    process::exit(serial_port_reader_writer::execute());
 }
@@ -0,0 +1,344 @@
+use crate::custom_commands::{Arg, CustomCommand, CustomCommands, Step, DEFAULT_GROUP};
+use crate::input_output::response::{ResponseRule, ResponseToml};
+use crate::input_output::tx_format::LineEnding;
+use crate::script::rhai_script::RhaiScript;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long `wait_for` waits when no `timeout_ms` is given
+const DEFAULT_WAIT_FOR_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Deserialize)]
+struct CommandsToml {
+    command: Command,
+}
+
+/// The [command] section of `ExtraCommands.toml`
+#[derive(Deserialize)]
+struct Command {
+    /// Old format: `[Command_name, Command To Run 1, Commands to Run 2, etc.]`
+    #[serde(default)]
+    command_array: Vec<Vec<String>>,
+    /// New format: one `[[command.definitions]]` table per command
+    #[serde(default)]
+    definitions: Vec<Definition>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    name: String,
+    description: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    args: Vec<ArgToml>,
+    steps: Vec<StepToml>,
+    line_ending: Option<String>,
+    response: Option<ResponseToml>,
+}
+
+/// `{ name = "addr" }`, or `{ name = "speed", default = "slow" }` for an optional argument
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArgToml {
+    name: String,
+    default: Option<String>,
+}
+
+/// One entry of `steps`: a plain string to send, or one of the tables
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StepToml {
+    Text(String),
+    Send(SendToml),
+    WaitFor(WaitForToml),
+    Sleep(SleepToml),
+    Call(CallToml),
+    Script(ScriptToml),
+}
+
+/// `{ send = "...", delay_ms = 100 }`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SendToml {
+    send: String,
+    delay_ms: Option<u64>,
+}
+
+/// `{ wait_for = "READY", timeout_ms = 2000 }`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WaitForToml {
+    wait_for: String,
+    timeout_ms: Option<u64>,
+}
+
+/// `{ sleep_ms = 500 }`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SleepToml {
+    sleep_ms: u64,
+}
+
+/// `{ call = "OTHER", args = ["{addr}", "fast"] }`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CallToml {
+    call: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// `{ script = "provision.rhai", args = ["{addr}"] }`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptToml {
+    script: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+pub struct ParseCommands {}
+
+impl ParseCommands {
+    /// Read custom commands. No file means no custom commands.
+    pub fn get_commands(custom_command_file_name: Option<String>) -> CustomCommands {
+        match custom_command_file_name {
+            Some(file_name) => {
+                let path: PathBuf = PathBuf::from(file_name);
+                let mut file = File::open(&path)
+                    .unwrap_or_else(|err| panic!("Cannot open: '{}': {}", path.display(), err));
+                let mut file_data = String::new();
+                file.read_to_string(&mut file_data).unwrap();
+                ParseCommands::parse_commands(&file_data)
+            }
+            None => CustomCommands::default(),
+        }
+    }
+
+    fn parse_commands(file_data: &str) -> CustomCommands {
+        let mut custom_commands = CustomCommands::default();
+        let commands_toml: CommandsToml =
+            toml::from_str(file_data).expect("Cannot get values from TOML file");
+
+        for vec_str in commands_toml.command.command_array {
+            let mut iter = vec_str.into_iter();
+            if let Some(shortcut_command) = iter.next() {
+                let name = shortcut_command.to_uppercase();
+                let custom_command = CustomCommand {
+                    name,
+                    aliases: Vec::<String>::new(),
+                    description: None,
+                    group: DEFAULT_GROUP.to_string(),
+                    args: Vec::<Arg>::new(),
+                    steps: iter.map(Step::send).collect(),
+                    line_ending: None,
+                    response: None,
+                };
+                custom_commands.insert(custom_command);
+            }
+        }
+
+        for definition in commands_toml.command.definitions {
+            let custom_command = ParseCommands::get_custom_command(definition);
+            for alias in &custom_command.aliases {
+                if let Some(existing) = custom_commands.get(alias) {
+                    panic!(
+                        "Alias '{}' of '{}' is already used by '{}'",
+                        alias, custom_command.name, existing.name
+                    );
+                }
+            }
+            custom_commands.insert(custom_command);
+        }
+
+        if let Err(error) = custom_commands.validate_calls() {
+            panic!("Invalid custom commands: {}", error);
+        }
+        custom_commands
+    }
+
+    fn get_custom_command(definition: Definition) -> CustomCommand {
+        let name = definition.name.to_uppercase();
+        let line_ending = definition.line_ending.map(|value| {
+            LineEnding::parse(&value).unwrap_or_else(|err| {
+                panic!("Invalid `line_ending` '{}' for '{}': {}", value, name, err)
+            })
+        });
+        let response = definition.response.map(|toml_val| {
+            ResponseRule::from_toml(&toml_val)
+                .unwrap_or_else(|err| panic!("Invalid `until_regex` for '{}': {}", name, err))
+        });
+
+        let args: Vec<Arg> = definition
+            .args
+            .into_iter()
+            .map(|arg| Arg {
+                name: arg.name,
+                default: arg.default,
+            })
+            .collect();
+        let steps = definition
+            .steps
+            .into_iter()
+            .map(|step| ParseCommands::get_step(step, &name))
+            .collect();
+
+        let custom_command = CustomCommand {
+            name,
+            aliases: definition
+                .aliases
+                .iter()
+                .map(|alias| alias.to_uppercase())
+                .collect(),
+            description: definition.description,
+            group: definition
+                .group
+                .unwrap_or_else(|| DEFAULT_GROUP.to_string()),
+            args,
+            steps,
+            line_ending,
+            response,
+        };
+        if let Err(error) = custom_command.validate_args() {
+            panic!("Invalid custom command: {}", error);
+        }
+        custom_command
+    }
+
+    fn get_step(step: StepToml, name: &str) -> Step {
+        match step {
+            StepToml::Text(text) => Step::send(text),
+            StepToml::Send(toml_val) => Step::Send {
+                text: toml_val.send,
+                delay: toml_val.delay_ms.map(Duration::from_millis),
+            },
+            StepToml::WaitFor(toml_val) => Step::WaitFor {
+                pattern: Regex::new(&toml_val.wait_for)
+                    .unwrap_or_else(|err| panic!("Invalid `wait_for` for '{}': {}", name, err)),
+                timeout: Duration::from_millis(
+                    toml_val.timeout_ms.unwrap_or(DEFAULT_WAIT_FOR_TIMEOUT_MS),
+                ),
+            },
+            StepToml::Sleep(toml_val) => Step::Sleep(Duration::from_millis(toml_val.sleep_ms)),
+            StepToml::Call(toml_val) => Step::Call {
+                name: toml_val.call.to_uppercase(),
+                args: toml_val.args,
+            },
+            StepToml::Script(toml_val) => {
+                let path = PathBuf::from(toml_val.script);
+                if let Err(error) = RhaiScript::check(&path) {
+                    panic!("Invalid `script` for '{}': {}", name, error);
+                }
+                Step::Script {
+                    path,
+                    args: toml_val.args,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_array_and_table_commands() {
+        let commands = ParseCommands::parse_commands(
+            r#"
+            [command]
+            command_array = [["status", "AT+STATUS"]]
+
+            [[command.definitions]]
+            name = "boot"
+            steps = [
+              "AT+RST",
+              { send = "AT+INIT", delay_ms = 100 },
+              { wait_for = "READY", timeout_ms = 2000 },
+              { sleep_ms = 500 },
+            ]
+            "#,
+        );
+
+        let status: Vec<String> = commands
+            .get("status")
+            .unwrap()
+            .steps
+            .iter()
+            .map(Step::to_string)
+            .collect();
+        assert_eq!(vec!["'AT+STATUS'"], status);
+
+        let boot: Vec<String> = commands
+            .get("BOOT")
+            .unwrap()
+            .steps
+            .iter()
+            .map(Step::to_string)
+            .collect();
+        assert_eq!(
+            vec![
+                "'AT+RST'",
+                "'AT+INIT', then wait 100 ms",
+                "wait for /READY/ (up to 2000 ms)",
+                "sleep 500 ms",
+            ],
+            boot
+        );
+    }
+
+    #[test]
+    fn should_find_commands_by_alias() {
+        let commands = ParseCommands::parse_commands(
+            r#"
+            [[command.definitions]]
+            name = "reset"
+            aliases = ["rst"]
+            steps = ["AT+RST"]
+
+            [[command.definitions]]
+            name = "boot"
+            steps = [{ call = "rst" }, "AT+INIT"]
+            "#,
+        );
+        assert_eq!("RESET", commands.get("rst").unwrap().name);
+        assert_eq!("BOOT", commands.find("boot").unwrap().0.name);
+    }
+
+    #[test]
+    #[should_panic(expected = "Commands call each other: A -> B -> A")]
+    fn should_reject_commands_calling_each_other() {
+        ParseCommands::parse_commands(
+            r#"
+            [[command.definitions]]
+            name = "a"
+            steps = [{ call = "b" }]
+
+            [[command.definitions]]
+            name = "b"
+            steps = [{ call = "a" }]
+            "#,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown field `descripton`")]
+    fn should_reject_misspelt_definition_keys() {
+        ParseCommands::parse_commands(
+            r#"
+            [[command.definitions]]
+            name = "RESET"
+            descripton = "Reset the board"
+            steps = ["AT+RST"]
+            "#,
+        );
+    }
+}
@@ -1,14 +1,34 @@
+use crate::input_output::history::{HistoryConfig, HistoryToml};
+use crate::input_output::line_filter::{FilterToml, LineFilter};
+use crate::input_output::log_file::{LogConfig, LogToml};
+use crate::input_output::output_format::{OutputConfig, OutputToml};
+use crate::input_output::response::{ResponseRule, ResponseToml};
+use crate::input_output::scheduler::{ScheduleSpec, ScheduleToml};
+use crate::input_output::trigger::{TriggerToml, Triggers};
+use crate::input_output::tx_format::{EchoFormat, LineEnding};
 use serde::Deserialize;
 use serialport::{DataBits, FlowControl, Parity, StopBits};
 use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::io::Read as _;
 use std::path::PathBuf;
 use std::time::Duration;
 
 #[derive(Deserialize)]
 struct ConfigToml {
     serial: Serial,
+    #[serde(default)]
+    read: Read,
+    #[serde(default)]
+    write: Write,
+    #[serde(default)]
+    log: LogToml,
+    #[serde(default)]
+    output: OutputToml,
+    #[serde(default)]
+    filter: FilterToml,
+    #[serde(default)]
+    trigger: Vec<TriggerToml>,
 }
 
 /// The name of the struct has to match the name of the section,
@@ -24,6 +44,62 @@ struct Serial {
     parity: String,
     stop_bits: u32,
     timeout_in_milliseconds: u64,
+    /// Level of Data Terminal Ready once the port is open
+    dtr: Option<bool>,
+    /// Level of Request To Send once the port is open
+    rts: Option<bool>,
+}
+
+/// Optional [read] section, used by the `read` command.
+#[derive(Deserialize, Default)]
+struct Read {
+    line_hook: Option<String>,
+    watch_lines: Option<bool>,
+}
+
+/// Optional [write] section, used by the `write` command.
+#[derive(Deserialize, Default)]
+struct Write {
+    escape_sequences: Option<bool>,
+    tx_line_ending: Option<String>,
+    local_echo: Option<String>,
+    step_delay_ms: Option<u64>,
+    show_latency: Option<bool>,
+    #[serde(default)]
+    response: ResponseToml,
+    #[serde(default)]
+    history: HistoryToml,
+    #[serde(default)]
+    schedule: Vec<ScheduleToml>,
+}
+
+/// Settings for the `read` command
+pub struct ReadConfig {
+    /// Rhai script whose `on_line(line)` is called for every line received
+    pub line_hook: Option<PathBuf>,
+    /// Record changes of CTS, DSR, RI and CD among the lines received
+    pub watch_lines: bool,
+}
+
+/// Settings for the `write` command
+#[derive(Clone)]
+pub struct WriteConfig {
+    /// Turn `\r`, `\x02`, etc. into the bytes they represent before sending
+    pub escape_sequences: bool,
+    /// Appended to every line sent, unless a custom command overrides it
+    pub tx_line_ending: LineEnding,
+    /// How transmitted data is printed
+    pub local_echo: EchoFormat,
+    /// When a response is complete, unless a custom command overrides it
+    pub response: ResponseRule,
+    /// Pause between the steps of a custom command, unless the step has its own `delay_ms`
+    pub step_delay: Duration,
+    /// Print how long each response took after it
+    pub show_latency: bool,
+    /// Where the REPL history is kept
+    pub history: HistoryConfig,
+    /// Started with the REPL, or with `write --schedule`
+    pub schedules: Vec<ScheduleSpec>,
 }
 
 pub struct ParsedTomlValues {
@@ -34,6 +110,19 @@ pub struct ParsedTomlValues {
     pub parity: Parity,
     pub stop_bits: StopBits,
     pub timeout_in_milliseconds: Duration,
+    /// Set once the port is open, and again when it is reopened
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    pub read: ReadConfig,
+    pub write: WriteConfig,
+    /// Where output is logged as well as printed, in read and write modes
+    pub log: Option<LogConfig>,
+    /// How sent and received data is printed, logged and timestamped
+    pub output: OutputConfig,
+    /// Which received lines are shown, and what is highlighted in them
+    pub filter: LineFilter,
+    /// Actions taken when received lines match, in read and write modes
+    pub triggers: Triggers,
 }
 
 pub struct ParseConfig {}
@@ -62,6 +151,20 @@ impl ParseConfig {
         let parity = ParseConfig::get_parity(&toml_val);
         let stop_bits = ParseConfig::get_stop_bits(&toml_val);
         let timeout_in_milliseconds = Duration::from_millis(toml_val.timeout_in_milliseconds);
+        let read = ReadConfig {
+            line_hook: config_toml.read.line_hook.map(PathBuf::from),
+            watch_lines: config_toml.read.watch_lines.unwrap_or(false),
+        };
+        let write =
+            ParseConfig::get_write_config(&config_toml.write, serial_port, timeout_in_milliseconds);
+        let log = LogConfig::from_toml(&config_toml.log)
+            .unwrap_or_else(|err| panic!("Invalid [log] section: {}", err));
+        let output = OutputConfig::from_toml(&config_toml.output)
+            .unwrap_or_else(|err| panic!("Invalid [output] section: {}", err));
+        let filter = LineFilter::from_toml(&config_toml.filter)
+            .unwrap_or_else(|err| panic!("Invalid [filter] section: {}", err));
+        let triggers = Triggers::from_toml(&config_toml.trigger)
+            .unwrap_or_else(|err| panic!("Invalid [[trigger]]: {}", err));
 
         ParsedTomlValues {
             serial_port: serial_port.to_string(),
@@ -71,44 +174,125 @@ impl ParseConfig {
             parity,
             stop_bits,
             timeout_in_milliseconds,
+            dtr: toml_val.dtr,
+            rts: toml_val.rts,
+            read,
+            write,
+            log,
+            output,
+            filter,
+            triggers,
         }
     }
 
-    fn get_data_bits(toml_val: &Serial) -> DataBits {
-        match toml_val.data_bits {
-            5 => DataBits::Five,
-            6 => DataBits::Six,
-            7 => DataBits::Seven,
-            8 => DataBits::Eight,
-            _ => DataBits::Eight,
+    /// Responses time out after `timeout` unless `[write.response]` says otherwise
+    fn get_write_config(toml_val: &Write, serial_port: &str, timeout: Duration) -> WriteConfig {
+        let tx_line_ending = match &toml_val.tx_line_ending {
+            Some(value) => LineEnding::parse(value)
+                .unwrap_or_else(|err| panic!("Invalid `tx_line_ending` '{}': {}", value, err)),
+            None => LineEnding::Lf,
+        };
+        let local_echo = match &toml_val.local_echo {
+            Some(value) => EchoFormat::parse(value)
+                .unwrap_or_else(|| panic!("Invalid `local_echo` '{}'", value)),
+            None => EchoFormat::Text,
+        };
+        let mut response = ResponseRule::from_toml(&toml_val.response)
+            .unwrap_or_else(|err| panic!("Invalid `until_regex` in [write.response]: {}", err));
+        response.timeout = response.timeout.or(Some(timeout));
+        let schedules = toml_val
+            .schedule
+            .iter()
+            .enumerate()
+            .map(|(index, schedule)| {
+                ScheduleSpec::from_toml(schedule).unwrap_or_else(|err| {
+                    panic!("Invalid [[write.schedule]] number {}: {}", index + 1, err)
+                })
+            })
+            .collect();
+
+        WriteConfig {
+            escape_sequences: toml_val.escape_sequences.unwrap_or(true),
+            tx_line_ending,
+            local_echo,
+            response,
+            step_delay: Duration::from_millis(toml_val.step_delay_ms.unwrap_or(500)),
+            show_latency: toml_val.show_latency.unwrap_or(false),
+            history: HistoryConfig::from_toml(&toml_val.history, serial_port),
+            schedules,
         }
     }
 
+    fn get_data_bits(toml_val: &Serial) -> DataBits {
+        ParseConfig::data_bits_from(toml_val.data_bits).unwrap_or(DataBits::Eight)
+    }
+
     fn get_flow_control(toml_val: &Serial) -> FlowControl {
-        let flow_control: &str = &toml_val.flow_control.to_lowercase();
-        match flow_control {
-            "none" => FlowControl::None,
-            "software" => FlowControl::Software,
-            "hardware" => FlowControl::Hardware,
-            _ => FlowControl::None,
-        }
+        ParseConfig::flow_control_from(&toml_val.flow_control).unwrap_or(FlowControl::None)
     }
 
     fn get_parity(toml_val: &Serial) -> Parity {
-        let parity: &str = &toml_val.parity.to_lowercase();
-        match parity {
-            "none" => Parity::None,
-            "odd" => Parity::Odd,
-            "even" => Parity::Even,
-            _ => Parity::None,
-        }
+        ParseConfig::parity_from(&toml_val.parity).unwrap_or(Parity::None)
     }
 
     fn get_stop_bits(toml_val: &Serial) -> StopBits {
-        match toml_val.stop_bits {
-            1 => StopBits::One,
-            2 => StopBits::Two,
-            _ => StopBits::One,
+        ParseConfig::stop_bits_from(toml_val.stop_bits).unwrap_or(StopBits::One)
+    }
+
+    pub fn data_bits_from(data_bits: u32) -> Option<DataBits> {
+        match data_bits {
+            5 => Some(DataBits::Five),
+            6 => Some(DataBits::Six),
+            7 => Some(DataBits::Seven),
+            8 => Some(DataBits::Eight),
+            _ => None,
+        }
+    }
+
+    pub fn flow_control_from(flow_control: &str) -> Option<FlowControl> {
+        match flow_control.to_lowercase().as_str() {
+            "none" => Some(FlowControl::None),
+            "software" => Some(FlowControl::Software),
+            "hardware" => Some(FlowControl::Hardware),
+            _ => None,
+        }
+    }
+
+    pub fn parity_from(parity: &str) -> Option<Parity> {
+        match parity.to_lowercase().as_str() {
+            "none" => Some(Parity::None),
+            "odd" => Some(Parity::Odd),
+            "even" => Some(Parity::Even),
+            _ => None,
+        }
+    }
+
+    pub fn stop_bits_from(stop_bits: u32) -> Option<StopBits> {
+        match stop_bits {
+            1 => Some(StopBits::One),
+            2 => Some(StopBits::Two),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl WriteConfig {
+    /// Sends lines as they are, with `\n`, and no delay between the steps of a command
+    pub fn with_response(response: ResponseRule) -> Self {
+        WriteConfig {
+            escape_sequences: true,
+            tx_line_ending: LineEnding::Lf,
+            local_echo: EchoFormat::None,
+            response,
+            step_delay: Duration::from_millis(0),
+            show_latency: false,
+            history: HistoryConfig {
+                max_len: 0,
+                dedup: false,
+                path: PathBuf::new(),
+            },
+            schedules: Vec::new(),
         }
     }
 }
@@ -0,0 +1,105 @@
+pub mod parser;
+pub mod rhai_script;
+pub mod runner;
+
+use regex::Regex;
+use std::fmt;
+
+/// A script statement, with the line it starts on for error messages
+pub struct Statement {
+    pub line: usize,
+    pub kind: StatementKind,
+}
+
+pub enum StatementKind {
+    /// `send "AT+GMR"`
+    Send(Expr),
+    /// `expect /v(\d+)/ timeout 2000 as version`
+    Expect {
+        pattern: Regex,
+        timeout_ms: Option<u64>,
+        /// Variables for capture groups 1, 2, ... Named groups are always stored.
+        captures: Vec<String>,
+    },
+    /// `set name = expr`
+    Set { name: String, value: Expr },
+    /// `if` / `elif` / `else` / `end`
+    If {
+        branches: Vec<(Condition, Vec<Statement>)>,
+        otherwise: Vec<Statement>,
+    },
+    /// `repeat 3 as i` / `end`, with `i` counting from 1
+    Repeat {
+        count: Expr,
+        counter: Option<String>,
+        body: Vec<Statement>,
+    },
+    /// `while cond` / `end`
+    While {
+        condition: Condition,
+        body: Vec<Statement>,
+    },
+    /// `sleep 500`, in milliseconds
+    Sleep(Expr),
+    /// `log "text"`
+    Log(Expr),
+    /// `fail "reason"`
+    Fail(Option<Expr>),
+}
+
+/// A value. Everything is a string at runtime; `+` and `-` need integers.
+pub enum Expr {
+    /// Quoted text, with `$name` and `${name}` replaced by variables
+    Text(String),
+    Number(i64),
+    Var(String),
+    Binary(Box<Expr>, ArithOp, Box<Expr>),
+}
+
+#[derive(Clone, Copy)]
+pub enum ArithOp {
+    Add,
+    Sub,
+}
+
+pub enum Condition {
+    Compare(Expr, CompareOp, Expr),
+    /// `$value =~ /regex/`
+    Matches(Expr, Regex),
+}
+
+/// Numbers compare as numbers, anything else as text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A problem parsing or running a script
+#[derive(Debug, PartialEq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+    /// Set when an `expect` timed out, so the exit code can say so
+    pub timed_out: bool,
+}
+
+impl ScriptError {
+    pub fn new(line: usize, message: String) -> Self {
+        ScriptError {
+            line,
+            message,
+            timed_out: false,
+        }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
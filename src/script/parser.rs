@@ -0,0 +1,524 @@
+use crate::script::{ArithOp, CompareOp, Condition, Expr, ScriptError, Statement, StatementKind};
+
+use regex::Regex;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Regex(String),
+    Number(i64),
+    Var(String),
+    Op(&'static str),
+}
+
+/// Longest first, so `==` isn't read as `=` twice
+const OPERATORS: &[&str] = &["==", "!=", "<=", ">=", "=~", "<", ">", "=", "+", "-", ","];
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Str(text) => write!(f, "\"{}\"", text),
+            Token::Regex(pattern) => write!(f, "/{}/", pattern),
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Var(name) => write!(f, "${}", name),
+            Token::Op(op) => write!(f, "'{}'", op),
+        }
+    }
+}
+
+/// How a block of statements ended
+enum BlockEnd {
+    Eof,
+    End,
+    Else,
+    Elif(Condition),
+}
+
+/// The tokens of one line
+struct Tokens {
+    line: usize,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+struct Parser {
+    lines: Vec<Tokens>,
+    pos: usize,
+}
+
+/// Parse a whole script. Errors carry the 1-based line they were found on.
+pub fn parse(source: &str) -> Result<Vec<Statement>, ScriptError> {
+    let mut lines = Vec::<Tokens>::new();
+    for (index, text) in source.lines().enumerate() {
+        let tokens = tokenize(text, index + 1)?;
+        if !tokens.is_empty() {
+            lines.push(Tokens {
+                line: index + 1,
+                tokens,
+                pos: 0,
+            });
+        }
+    }
+
+    let mut parser = Parser { lines, pos: 0 };
+    let (statements, block_end, line) = parser.parse_block()?;
+    match block_end {
+        BlockEnd::Eof => Ok(statements),
+        BlockEnd::End => Err(ScriptError::new(
+            line,
+            String::from("'end' without a block"),
+        )),
+        BlockEnd::Else | BlockEnd::Elif(_) => Err(ScriptError::new(
+            line,
+            String::from("'else' or 'elif' without 'if'"),
+        )),
+    }
+}
+
+impl Parser {
+    /// Statements up to the next `end`, `else` or `elif` at this level, or the end of the script.
+    /// Also returns the line that ended the block.
+    fn parse_block(&mut self) -> Result<(Vec<Statement>, BlockEnd, usize), ScriptError> {
+        let mut statements = Vec::<Statement>::new();
+        let mut last_line = 0;
+
+        while self.pos < self.lines.len() {
+            let mut tokens = Tokens {
+                line: self.lines[self.pos].line,
+                tokens: self.lines[self.pos].tokens.clone(),
+                pos: 0,
+            };
+            self.pos += 1;
+            last_line = tokens.line;
+
+            let keyword = tokens.word()?;
+            match keyword.as_str() {
+                "end" => {
+                    tokens.finish()?;
+                    return Ok((statements, BlockEnd::End, tokens.line));
+                }
+                "else" => {
+                    tokens.finish()?;
+                    return Ok((statements, BlockEnd::Else, tokens.line));
+                }
+                "elif" => {
+                    let condition = tokens.condition()?;
+                    tokens.finish()?;
+                    return Ok((statements, BlockEnd::Elif(condition), tokens.line));
+                }
+                _ => statements.push(self.parse_statement(&keyword, &mut tokens)?),
+            }
+        }
+
+        Ok((statements, BlockEnd::Eof, last_line))
+    }
+
+    fn parse_statement(
+        &mut self,
+        keyword: &str,
+        tokens: &mut Tokens,
+    ) -> Result<Statement, ScriptError> {
+        let line = tokens.line;
+        let kind = match keyword {
+            "send" => StatementKind::Send(tokens.expr()?),
+            "expect" => tokens.expect_statement()?,
+            "set" => {
+                let name = tokens.word()?;
+                tokens.op("=")?;
+                StatementKind::Set {
+                    name,
+                    value: tokens.expr()?,
+                }
+            }
+            "if" => {
+                let mut condition = tokens.condition()?;
+                tokens.finish()?;
+                let mut branches = Vec::<(Condition, Vec<Statement>)>::new();
+                let mut otherwise = Vec::<Statement>::new();
+                loop {
+                    let (body, block_end, end_line) = self.parse_block()?;
+                    branches.push((condition, body));
+                    match block_end {
+                        BlockEnd::End => break,
+                        BlockEnd::Elif(next) => condition = next,
+                        BlockEnd::Else => {
+                            otherwise = self.parse_body("else", end_line)?;
+                            break;
+                        }
+                        BlockEnd::Eof => return Err(missing_end("if", line)),
+                    }
+                }
+                StatementKind::If {
+                    branches,
+                    otherwise,
+                }
+            }
+            "repeat" => {
+                let count = tokens.expr()?;
+                let counter = match tokens.peek() {
+                    Some(Token::Word(word)) if word == "as" => {
+                        tokens.pos += 1;
+                        Some(tokens.word()?)
+                    }
+                    _ => None,
+                };
+                tokens.finish()?;
+                StatementKind::Repeat {
+                    count,
+                    counter,
+                    body: self.parse_body("repeat", line)?,
+                }
+            }
+            "while" => {
+                let condition = tokens.condition()?;
+                tokens.finish()?;
+                StatementKind::While {
+                    condition,
+                    body: self.parse_body("while", line)?,
+                }
+            }
+            "sleep" => StatementKind::Sleep(tokens.expr()?),
+            "log" => StatementKind::Log(tokens.expr()?),
+            "fail" => match tokens.peek() {
+                Some(_) => StatementKind::Fail(Some(tokens.expr()?)),
+                None => StatementKind::Fail(None),
+            },
+            _ => {
+                return Err(ScriptError::new(
+                    line,
+                    format!("unknown statement '{}'", keyword),
+                ))
+            }
+        };
+        tokens.finish()?;
+        Ok(Statement { line, kind })
+    }
+
+    /// The statements of a block that can only be ended by `end`
+    fn parse_body(&mut self, opener: &str, line: usize) -> Result<Vec<Statement>, ScriptError> {
+        let (body, block_end, end_line) = self.parse_block()?;
+        match block_end {
+            BlockEnd::End => Ok(body),
+            BlockEnd::Eof => Err(missing_end(opener, line)),
+            BlockEnd::Else | BlockEnd::Elif(_) => Err(ScriptError::new(
+                end_line,
+                format!("expected 'end' to close '{}' on line {}", opener, line),
+            )),
+        }
+    }
+}
+
+fn missing_end(opener: &str, line: usize) -> ScriptError {
+    ScriptError::new(line, format!("'{}' without 'end'", opener))
+}
+
+impl Tokens {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, ScriptError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| self.error(String::from("unexpected end of line")))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn error(&self, message: String) -> ScriptError {
+        ScriptError::new(self.line, message)
+    }
+
+    fn word(&mut self) -> Result<String, ScriptError> {
+        match self.next()? {
+            Token::Word(word) => Ok(word),
+            token => Err(self.error(format!("expected a name, found {}", token))),
+        }
+    }
+
+    fn op(&mut self, op: &str) -> Result<(), ScriptError> {
+        match self.next()? {
+            Token::Op(found) if found == op => Ok(()),
+            token => Err(self.error(format!("expected '{}', found {}", op, token))),
+        }
+    }
+
+    /// Nothing may follow a complete statement
+    fn finish(&self) -> Result<(), ScriptError> {
+        match self.peek() {
+            Some(token) => Err(self.error(format!("unexpected {}", token))),
+            None => Ok(()),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Expr, ScriptError> {
+        match self.next()? {
+            Token::Str(text) => Ok(Expr::Text(text)),
+            Token::Number(number) => Ok(Expr::Number(number)),
+            Token::Var(name) => Ok(Expr::Var(name)),
+            token => Err(self.error(format!("expected a value, found {}", token))),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ScriptError> {
+        let mut expr = self.operand()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("+")) => ArithOp::Add,
+                Some(Token::Op("-")) => ArithOp::Sub,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.operand()?));
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, ScriptError> {
+        let left = self.expr()?;
+        let op = match self.next()? {
+            Token::Op("=~") => return Ok(Condition::Matches(left, self.regex()?)),
+            Token::Op("==") => CompareOp::Eq,
+            Token::Op("!=") => CompareOp::Ne,
+            Token::Op("<") => CompareOp::Lt,
+            Token::Op("<=") => CompareOp::Le,
+            Token::Op(">") => CompareOp::Gt,
+            Token::Op(">=") => CompareOp::Ge,
+            token => {
+                return Err(self.error(format!("expected a comparison, found {}", token)));
+            }
+        };
+        Ok(Condition::Compare(left, op, self.expr()?))
+    }
+
+    /// `/regex/` or a quoted string, compiled now so mistakes show up before anything is sent
+    fn regex(&mut self) -> Result<Regex, ScriptError> {
+        let pattern = match self.next()? {
+            Token::Regex(pattern) | Token::Str(pattern) => pattern,
+            token => return Err(self.error(format!("expected /regex/, found {}", token))),
+        };
+        Regex::new(&pattern).map_err(|err| self.error(format!("invalid regex: {}", err)))
+    }
+
+    /// `expect /regex/ [timeout <ms>] [as name, name...]`
+    fn expect_statement(&mut self) -> Result<StatementKind, ScriptError> {
+        let pattern = self.regex()?;
+        let mut timeout_ms = None;
+        let mut captures = Vec::<String>::new();
+
+        while let Some(Token::Word(word)) = self.peek().cloned() {
+            self.pos += 1;
+            match word.as_str() {
+                "timeout" => match self.next()? {
+                    Token::Number(number) if number >= 0 => timeout_ms = Some(number as u64),
+                    token => {
+                        return Err(self.error(format!(
+                            "expected a timeout in milliseconds, found {}",
+                            token
+                        )))
+                    }
+                },
+                "as" => {
+                    captures.push(self.word()?);
+                    while let Some(Token::Op(",")) = self.peek() {
+                        self.pos += 1;
+                        captures.push(self.word()?);
+                    }
+                }
+                _ => return Err(self.error(format!("unexpected '{}'", word))),
+            }
+        }
+
+        Ok(StatementKind::Expect {
+            pattern,
+            timeout_ms,
+            captures,
+        })
+    }
+}
+
+/// Split a line into tokens, stopping at a `#` comment
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, ScriptError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::<Token>::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            break;
+        } else if c == '"' || c == '/' {
+            let (content, next) = read_delimited(&chars, i, line)?;
+            tokens.push(match c {
+                '"' => Token::Str(content),
+                _ => Token::Regex(content),
+            });
+            i = next;
+        } else if c == '$' {
+            let (name, next) = read_var_name(&chars, i + 1);
+            if name.is_empty() {
+                return Err(ScriptError::new(line, String::from("'$' without a name")));
+            }
+            tokens.push(Token::Var(name));
+            i = next;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let digits: String = chars[start..i].iter().collect();
+            let number = digits
+                .parse::<i64>()
+                .map_err(|_| ScriptError::new(line, format!("number too big: {}", digits)))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    i += op.len();
+                }
+                None => {
+                    return Err(ScriptError::new(line, format!("unexpected '{}'", c)));
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Text between `chars[start]` and the next unescaped copy of it. Only the escaped delimiter
+/// loses its backslash; other escapes are left for `send` or the regex to handle.
+fn read_delimited(
+    chars: &[char],
+    start: usize,
+    line: usize,
+) -> Result<(String, usize), ScriptError> {
+    let delimiter = chars[start];
+    let mut content = String::new();
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if chars.get(i + 1) == Some(&delimiter) => {
+                content.push(delimiter);
+                i += 2;
+            }
+            '\\' if i + 1 < chars.len() => {
+                content.push('\\');
+                content.push(chars[i + 1]);
+                i += 2;
+            }
+            c if c == delimiter => return Ok((content, i + 1)),
+            c => {
+                content.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    Err(ScriptError::new(
+        line,
+        format!("missing closing {}", delimiter),
+    ))
+}
+
+/// `name` or `{name}` starting at `chars[start]`. Returns the name and the index after it.
+pub fn read_var_name(chars: &[char], start: usize) -> (String, usize) {
+    if chars.get(start) == Some(&'{') {
+        if let Some(length) = chars[start + 1..].iter().position(|c| *c == '}') {
+            let name = chars[start + 1..start + 1 + length].iter().collect();
+            return (name, start + length + 2);
+        }
+        return (String::new(), start);
+    }
+
+    let mut i = start;
+    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+        i += 1;
+    }
+    (chars[start..i].iter().collect(), i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_nested_blocks() {
+        let source = r#"
+# Bring up the board
+send "AT+GMR"
+expect /v(\d+)\.(\d+)/ timeout 2000 as major, minor
+if $major >= 2
+  repeat 3 as i
+    send "PING $i"
+    expect "PONG"
+  end
+elif $major == 1
+  log "old firmware"
+else
+  fail "unknown firmware $major"
+end
+"#;
+        let statements = parse(source).unwrap();
+        assert_eq!(3, statements.len());
+        assert_eq!(
+            vec![3, 4, 5],
+            statements.iter().map(|s| s.line).collect::<Vec<_>>()
+        );
+
+        match &statements[1].kind {
+            StatementKind::Expect {
+                timeout_ms,
+                captures,
+                ..
+            } => {
+                assert_eq!(Some(2000), *timeout_ms);
+                assert_eq!(
+                    vec![String::from("major"), String::from("minor")],
+                    *captures
+                );
+            }
+            _ => panic!("expected an expect statement"),
+        }
+        match &statements[2].kind {
+            StatementKind::If {
+                branches,
+                otherwise,
+            } => {
+                assert_eq!(2, branches.len());
+                assert_eq!(1, otherwise.len());
+            }
+            _ => panic!("expected an if statement"),
+        }
+    }
+
+    #[test]
+    fn should_report_errors_with_line_numbers() {
+        let error = |source: &str| parse(source).err().unwrap().to_string();
+
+        assert_eq!(
+            "line 2: 'repeat' without 'end'",
+            error("log \"a\"\nrepeat 3\n")
+        );
+        assert_eq!("line 1: unknown statement 'sned'", error("sned \"AT\""));
+        assert_eq!("line 3: 'end' without a block", error("sleep 10\n\nend"));
+        assert_eq!("line 1: missing closing \"", error("send \"AT"));
+        assert!(error("expect /(/").starts_with("line 1: invalid regex"));
+    }
+}
@@ -0,0 +1,469 @@
+use crate::custom_commands::CustomCommands;
+use crate::input_output::background_reader::RxLine;
+use crate::input_output::command_steps::{self, StepRunner, StepTarget, TxSettings};
+use crate::input_output::meta_command::{self, MetaCommand};
+use crate::input_output::shutdown;
+use crate::input_output::tx_format::{self, LineEnding};
+use crate::input_output::write_serial::Outcome;
+use crate::parse_config::WriteConfig;
+
+use regex::Regex;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST, INT};
+use serialport::SerialPort;
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long `read_line()` and `expect()` wait when neither the call nor [write.response]
+/// sets a timeout
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+/// Called for each received line in read mode, if the script defines it
+const LINE_HOOK_FN: &str = "on_line";
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// What scripts can reach: the port, the lines received from it, and the custom commands
+pub struct ScriptContext {
+    serial_port: Box<dyn SerialPort>,
+    received: Receiver<RxLine>,
+    write: WriteConfig,
+    custom_commands: Rc<CustomCommands>,
+    /// Custom commands that led to this script, which it must not run again
+    running: Vec<String>,
+    /// Set when `expect()` timed out, so the exit code can say so
+    timed_out: bool,
+}
+
+/// Carries out custom command steps for `run_command()`, on the script's port
+struct ScriptTarget<'a>(&'a Rc<RefCell<ScriptContext>>);
+
+/// A compiled Rhai script, with the functions of `ScriptContext` registered
+pub struct RhaiScript {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    context: Rc<RefCell<ScriptContext>>,
+}
+
+impl ScriptContext {
+    pub fn new(
+        serial_port: Box<dyn SerialPort>,
+        received: Receiver<RxLine>,
+        write: WriteConfig,
+        custom_commands: Rc<CustomCommands>,
+    ) -> Rc<RefCell<ScriptContext>> {
+        Rc::new(RefCell::new(ScriptContext {
+            serial_port,
+            received,
+            write,
+            custom_commands,
+            running: Vec::new(),
+            timed_out: false,
+        }))
+    }
+
+    /// Set when the script is a step of these custom commands
+    pub fn set_running(&mut self, running: Vec<String>) {
+        self.running = running;
+    }
+
+    fn default_timeout(&self) -> Duration {
+        self.write
+            .response
+            .timeout
+            .unwrap_or_else(|| Duration::from_millis(DEFAULT_TIMEOUT_MS))
+    }
+
+    /// Send a line the same way the write REPL does
+    fn send(&mut self, text: &str, line_ending: &LineEnding) -> Result<(), String> {
+        let bytes = tx_format::encode(text, line_ending, self.write.escape_sequences)
+            .map_err(|err| err.to_string())?;
+
+        self.print_received();
+        if let Some(echo) = self.write.local_echo.echo(text, &bytes) {
+            println!("{}", echo);
+        }
+        self.serial_port
+            .write_all(&bytes)
+            .and_then(|_| self.serial_port.flush())
+            .map_err(|err| format!("Cannot send: {}", err))
+    }
+
+    /// The next received line, or `None` if nothing arrived in time
+    fn read_line(&mut self, timeout: Duration) -> Option<String> {
+        shutdown::recv_timeout(&self.received, timeout)
+            .ok()
+            .map(|line| line.text)
+    }
+
+    /// Wait for a line matching `pattern`. Returns the whole match, then each capture group.
+    fn expect(&mut self, pattern: &Regex, timeout: Duration) -> Result<Vec<String>, String> {
+        let start_time = Instant::now();
+        loop {
+            let remaining = timeout
+                .checked_sub(start_time.elapsed())
+                .unwrap_or_default();
+            let line = match shutdown::recv_timeout(&self.received, remaining) {
+                Ok(line) => line.text,
+                Err(RecvTimeoutError::Timeout) if shutdown::requested() => {
+                    return Err(String::from("Interrupted"))
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.timed_out = true;
+                    return Err(format!("Timed out waiting for /{}/", pattern));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(String::from("No lines can be read here"))
+                }
+            };
+            println!("Rx: '{}'", line.replace("\r", "\\r"));
+
+            if let Some(found) = pattern.captures(&line) {
+                return Ok(found
+                    .iter()
+                    .map(|group| group.map_or("", |m| m.as_str()).to_string())
+                    .collect());
+            }
+        }
+    }
+
+    fn set_port(&mut self, setting: &str, value: String) -> Result<(), String> {
+        match MetaCommand::parse(&format!(":{} {}", setting, value)) {
+            Some(Ok(meta_command)) => meta_command
+                .apply(&mut self.serial_port)
+                .map_err(|err| err.to_string()),
+            Some(Err(error)) => Err(error),
+            None => Err(format!("Unknown port setting '{}'", setting)),
+        }
+    }
+
+    /// Print whatever was received and not read yet
+    fn print_received(&self) {
+        for line in self.received.try_iter() {
+            println!("Rx: '{}'", line.text.replace("\r", "\\r"));
+        }
+    }
+}
+
+impl RhaiScript {
+    /// Compile a script without running it, to report syntax errors early
+    pub fn check(path: &Path) -> Result<(), String> {
+        Engine::new()
+            .compile_file(path.to_path_buf())
+            .map(|_| ())
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn load(path: &Path, context: Rc<RefCell<ScriptContext>>) -> Result<RhaiScript, String> {
+        let mut engine = Engine::new();
+        register_api(&mut engine, &context);
+        // Stops the script, e.g. an endless loop, once SIGINT or SIGTERM is received
+        engine.on_progress(|_| shutdown::requested().then(|| Dynamic::from("Interrupted")));
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        Ok(RhaiScript {
+            path: path.to_path_buf(),
+            engine,
+            ast,
+            scope: Scope::new(),
+            context,
+        })
+    }
+
+    /// Run the top level of the script, with `args` holding the given arguments
+    pub fn run(&mut self, args: &[String]) -> Result<(), String> {
+        let args: Array = args.iter().cloned().map(Dynamic::from).collect();
+        self.scope.set_value("args", args);
+        let result = self.engine.run_ast_with_scope(&mut self.scope, &self.ast);
+        self.context.borrow().print_received();
+        result.map_err(|err| format!("{}: {}", self.path.display(), err))
+    }
+
+    /// Pass a received line to `on_line()`, if the script defines it.
+    /// Returns false if the script returned `false` to hide the line.
+    pub fn on_line(&mut self, line: &str) -> Result<bool, String> {
+        if !self.ast.iter_functions().any(|f| f.name == LINE_HOOK_FN) {
+            return Ok(true);
+        }
+        // The top level already ran in `run()`, and must not send anything again
+        let options = CallFnOptions::new().eval_ast(false);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut self.scope,
+            &self.ast,
+            LINE_HOOK_FN,
+            (line.to_string(),),
+        );
+        match result {
+            Ok(shown) => Ok(shown.as_bool().unwrap_or(true)),
+            Err(err) => Err(format!("{}: {}", self.path.display(), err)),
+        }
+    }
+
+    /// Whether the last error came from `expect()` timing out
+    pub fn timed_out(&self) -> bool {
+        self.context.borrow().timed_out
+    }
+
+    /// Stop the script with an error once it ran this many operations, e.g. in an endless
+    /// loop
+    pub fn with_max_operations(mut self, operations: u64) -> Self {
+        self.engine.set_max_operations(operations);
+        self
+    }
+}
+
+impl StepTarget for ScriptTarget<'_> {
+    fn send_and_read(&mut self, text: &str, tx_settings: &TxSettings) -> Outcome {
+        let mut context = self.0.borrow_mut();
+        if let Err(error) = context.send(text, &tx_settings.line_ending) {
+            println!("Nothing sent. {}", error);
+            return Outcome::Failed;
+        }
+        let response = tx_settings.response.collect(&context.received, |line| {
+            println!("Rx: '{}'", line.text.replace("\r", "\\r"));
+        });
+        command_steps::response_outcome(&response, &tx_settings.response)
+    }
+
+    fn wait_for(&mut self, pattern: &Regex, timeout: Duration) -> bool {
+        self.0.borrow_mut().expect(pattern, timeout).is_ok()
+    }
+
+    fn run_script(
+        &mut self,
+        path: &Path,
+        args: &[String],
+        running: &[String],
+    ) -> Result<(), Outcome> {
+        let outer = mem::replace(&mut self.0.borrow_mut().running, running.to_vec());
+        let result =
+            RhaiScript::load(path, Rc::clone(self.0)).and_then(|mut script| script.run(args));
+        let mut context = self.0.borrow_mut();
+        context.running = outer;
+        result.map_err(|error| {
+            println!("--- Script failed: {} ---", error);
+            if context.timed_out {
+                Outcome::Timeout
+            } else {
+                Outcome::Failed
+            }
+        })
+    }
+}
+
+/// Run a custom command from a script, the same way the write REPL does. Returns whether
+/// every response was complete, or an error if a step failed.
+fn run_command(
+    context: &Rc<RefCell<ScriptContext>>,
+    name: &str,
+    args: &[String],
+) -> RhaiResult<bool> {
+    let (custom_commands, write, running) = {
+        let context = context.borrow();
+        (
+            Rc::clone(&context.custom_commands),
+            context.write.clone(),
+            context.running.clone(),
+        )
+    };
+    let custom_command = custom_commands
+        .get(name)
+        .ok_or_else(|| format!("No custom command named '{}'", name))?;
+
+    let mut target = ScriptTarget(context);
+    match StepRunner::new(&custom_commands, &write, running).run(&mut target, custom_command, args)
+    {
+        Ok(outcome) => Ok(outcome == Outcome::Completed),
+        Err(outcome) => {
+            if outcome == Outcome::Timeout {
+                context.borrow_mut().timed_out = true;
+            }
+            Err(format!("'{}' stopped", custom_command.name).into())
+        }
+    }
+}
+
+/// Arguments for a `script` step: its own, or else those of the command running it
+pub fn script_args(step_args: Vec<String>, command_args: &[String]) -> Vec<String> {
+    if step_args.is_empty() {
+        command_args.to_vec()
+    } else {
+        step_args
+    }
+}
+
+fn timeout_from(millis: INT) -> Duration {
+    Duration::from_millis(millis.max(0) as u64)
+}
+
+fn compile(pattern: &str) -> RhaiResult<Regex> {
+    Regex::new(pattern).map_err(|err| format!("Invalid regex: {}", err).into())
+}
+
+fn captures_array(captures: Vec<String>) -> Array {
+    captures.into_iter().map(Dynamic::from).collect()
+}
+
+/// The functions scripts can call
+fn register_api(engine: &mut Engine, context: &Rc<RefCell<ScriptContext>>) {
+    let ctx = Rc::clone(context);
+    engine.register_fn("send", move |text: &str| -> RhaiResult<()> {
+        let mut ctx = ctx.borrow_mut();
+        let line_ending = ctx.write.tx_line_ending.clone();
+        ctx.send(text, &line_ending).map_err(Into::into)
+    });
+
+    let ctx = Rc::clone(context);
+    engine.register_fn("read_line", move || -> Dynamic {
+        let mut ctx = ctx.borrow_mut();
+        let timeout = ctx.default_timeout();
+        ctx.read_line(timeout).map_or(Dynamic::UNIT, Dynamic::from)
+    });
+    let ctx = Rc::clone(context);
+    engine.register_fn("read_line", move |timeout_ms: INT| -> Dynamic {
+        let mut ctx = ctx.borrow_mut();
+        ctx.read_line(timeout_from(timeout_ms))
+            .map_or(Dynamic::UNIT, Dynamic::from)
+    });
+
+    let ctx = Rc::clone(context);
+    engine.register_fn("expect", move |pattern: &str| -> RhaiResult<Array> {
+        let mut ctx = ctx.borrow_mut();
+        let timeout = ctx.default_timeout();
+        let captures = ctx.expect(&compile(pattern)?, timeout)?;
+        Ok(captures_array(captures))
+    });
+    let ctx = Rc::clone(context);
+    engine.register_fn(
+        "expect",
+        move |pattern: &str, timeout_ms: INT| -> RhaiResult<Array> {
+            let mut ctx = ctx.borrow_mut();
+            let captures = ctx.expect(&compile(pattern)?, timeout_from(timeout_ms))?;
+            Ok(captures_array(captures))
+        },
+    );
+
+    engine.register_fn("sleep", |millis: INT| shutdown::sleep(timeout_from(millis)));
+
+    let ctx = Rc::clone(context);
+    engine.register_fn(
+        "set_port",
+        move |setting: &str, value: &str| -> RhaiResult<()> {
+            let value = value.to_string();
+            ctx.borrow_mut()
+                .set_port(setting, value)
+                .map_err(Into::into)
+        },
+    );
+    let ctx = Rc::clone(context);
+    engine.register_fn(
+        "set_port",
+        move |setting: &str, value: INT| -> RhaiResult<()> {
+            let value = value.to_string();
+            ctx.borrow_mut()
+                .set_port(setting, value)
+                .map_err(Into::into)
+        },
+    );
+    let ctx = Rc::clone(context);
+    engine.register_fn(
+        "set_port",
+        move |setting: &str, value: bool| -> RhaiResult<()> {
+            let value = value.to_string();
+            ctx.borrow_mut()
+                .set_port(setting, value)
+                .map_err(Into::into)
+        },
+    );
+    let ctx = Rc::clone(context);
+    engine.register_fn("port_status", move || -> String {
+        meta_command::port_status(&mut ctx.borrow_mut().serial_port)
+    });
+
+    let ctx = Rc::clone(context);
+    engine.register_fn("commands", move || -> Array {
+        let ctx = ctx.borrow();
+        ctx.custom_commands
+            .sorted()
+            .into_iter()
+            .map(|custom_command| {
+                let mut map = Map::new();
+                map.insert("name".into(), custom_command.name.clone().into());
+                map.insert("usage".into(), custom_command.usage().into());
+                map.insert(
+                    "description".into(),
+                    custom_command
+                        .description
+                        .clone()
+                        .unwrap_or_default()
+                        .into(),
+                );
+                map.insert("group".into(), custom_command.group.clone().into());
+                let aliases: Array = custom_command
+                    .aliases
+                    .iter()
+                    .cloned()
+                    .map(Dynamic::from)
+                    .collect();
+                map.insert("aliases".into(), aliases.into());
+                Dynamic::from(map)
+            })
+            .collect()
+    });
+
+    let ctx = Rc::clone(context);
+    engine.register_fn("run_command", move |name: &str| -> RhaiResult<bool> {
+        run_command(&ctx, name, &[])
+    });
+    let ctx = Rc::clone(context);
+    engine.register_fn(
+        "run_command",
+        move |name: &str, args: Array| -> RhaiResult<bool> {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            run_command(&ctx, name, &args)
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn should_check_scripts_before_running_them() {
+        let path = env::temp_dir().join("serial_port_reader_writer_check.rhai");
+        fs::write(
+            &path,
+            "let reply = expect(\"OK\");\nif reply[0] == \"OK\" { print(\"ok\"); }\n",
+        )
+        .unwrap();
+        assert_eq!(Ok(()), RhaiScript::check(&path));
+
+        fs::write(&path, "send(\"AT\");\nlet = 5;\n").unwrap();
+        let error = RhaiScript::check(&path).unwrap_err();
+        assert!(error.contains("line 2"), "{}", error);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_pass_command_args_to_scripts_without_their_own() {
+        let command_args = vec![String::from("7")];
+        assert_eq!(command_args, script_args(Vec::new(), &command_args));
+        assert_eq!(
+            vec![String::from("fast")],
+            script_args(vec![String::from("fast")], &command_args)
+        );
+    }
+}
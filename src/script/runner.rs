@@ -0,0 +1,444 @@
+use crate::custom_commands::CustomCommands;
+use crate::input_output::background_reader::{BackgroundReader, RxLine};
+use crate::input_output::shutdown;
+use crate::input_output::tx_format;
+use crate::input_output::write_serial::Outcome;
+use crate::parse_commands::ParseCommands;
+use crate::parse_config::{ParseConfig, ParsedTomlValues};
+use crate::script::parser::{self, read_var_name};
+use crate::script::rhai_script::{RhaiScript, ScriptContext};
+use crate::script::{ArithOp, CompareOp, Condition, Expr, ScriptError, Statement, StatementKind};
+use crate::serial_port::serial_port_open::SerialPortOpen;
+
+use serialport::SerialPort;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long `expect` waits when neither the statement nor [write.response] sets a timeout
+const DEFAULT_EXPECT_TIMEOUT_MS: u64 = 5000;
+
+/// Runs script files against the configured serial port
+pub struct ScriptRunner {
+    config: ParsedTomlValues,
+}
+
+/// State while a script runs
+struct Session<'c> {
+    config: &'c ParsedTomlValues,
+    serial_port: Box<dyn SerialPort>,
+    /// Every line received, in order
+    received: Receiver<RxLine>,
+    variables: HashMap<String, String>,
+}
+
+impl ScriptRunner {
+    pub fn new(config_file_name: &str) -> Self {
+        Self {
+            config: ParseConfig::get_config(config_file_name),
+        }
+    }
+
+    /// Parse the whole script, then run it. `fail`, errors and timeouts stop the script.
+    /// `.rhai` files are run as Rhai scripts, which can also use the custom commands.
+    pub fn execute(
+        &self,
+        script_file_name: &str,
+        custom_command_file_name: Option<String>,
+    ) -> Outcome {
+        let path = Path::new(script_file_name);
+        if path.extension().and_then(|extension| extension.to_str()) == Some("rhai") {
+            let custom_commands = ParseCommands::get_commands(custom_command_file_name);
+            return self.execute_rhai(path, Rc::new(custom_commands));
+        }
+
+        let source = fs::read_to_string(script_file_name)
+            .unwrap_or_else(|err| panic!("Cannot open: '{}': {}", script_file_name, err));
+        let statements = match parser::parse(&source) {
+            Ok(statements) => statements,
+            Err(error) => {
+                println!("{}: {}", script_file_name, error);
+                return Outcome::Failed;
+            }
+        };
+
+        let serial_port = SerialPortOpen::open_port(&self.config).serial_port;
+        let reader_port = serial_port
+            .try_clone()
+            .expect("Cannot clone serial port for background reading");
+        let mut reader = BackgroundReader::start(reader_port, Box::new(print_unsolicited));
+        let mut session = Session {
+            config: &self.config,
+            serial_port,
+            received: reader.begin_response(script_file_name),
+            variables: HashMap::new(),
+        };
+
+        let result = session.run_block(&statements);
+        session.print_received();
+        reader.stop();
+
+        match result {
+            Ok(()) => {
+                println!("--- Script finished ---");
+                Outcome::Completed
+            }
+            Err(error) => {
+                println!("{}: {}", script_file_name, error);
+                if error.timed_out {
+                    Outcome::Timeout
+                } else {
+                    Outcome::Failed
+                }
+            }
+        }
+    }
+}
+
+impl ScriptRunner {
+    fn execute_rhai(&self, path: &Path, custom_commands: Rc<CustomCommands>) -> Outcome {
+        let serial_port = SerialPortOpen::open_port(&self.config).serial_port;
+        let reader_port = serial_port
+            .try_clone()
+            .expect("Cannot clone serial port for background reading");
+        let mut reader = BackgroundReader::start(reader_port, Box::new(print_unsolicited));
+        let context = ScriptContext::new(
+            serial_port,
+            reader.begin_response(&path.display().to_string()),
+            self.config.write.clone(),
+            custom_commands,
+        );
+
+        let outcome = match RhaiScript::load(path, context) {
+            Ok(mut script) => match script.run(&[]) {
+                Ok(()) => Outcome::Completed,
+                Err(error) => {
+                    println!("{}", error);
+                    if script.timed_out() {
+                        Outcome::Timeout
+                    } else {
+                        Outcome::Failed
+                    }
+                }
+            },
+            Err(error) => {
+                println!("{}", error);
+                Outcome::Failed
+            }
+        };
+        reader.stop();
+        if outcome == Outcome::Completed {
+            println!("--- Script finished ---");
+        }
+        outcome
+    }
+}
+
+impl<'c> Session<'c> {
+    fn run_block(&mut self, statements: &[Statement]) -> Result<(), ScriptError> {
+        for statement in statements {
+            self.run(statement)?;
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, statement: &Statement) -> Result<(), ScriptError> {
+        let line = statement.line;
+        stop_if_interrupted(line)?;
+        match &statement.kind {
+            StatementKind::Send(expr) => {
+                let text = self.eval(expr, line)?;
+                self.send(&text, line)?;
+            }
+            StatementKind::Expect {
+                pattern,
+                timeout_ms,
+                captures,
+            } => {
+                let timeout = match timeout_ms {
+                    Some(timeout_ms) => Duration::from_millis(*timeout_ms),
+                    None => self
+                        .config
+                        .write
+                        .response
+                        .timeout
+                        .unwrap_or_else(|| Duration::from_millis(DEFAULT_EXPECT_TIMEOUT_MS)),
+                };
+                self.expect(pattern, timeout, captures, line)?;
+            }
+            StatementKind::Set { name, value } => {
+                let value = self.eval(value, line)?;
+                self.variables.insert(name.clone(), value);
+            }
+            StatementKind::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    if self.check(condition, line)? {
+                        return self.run_block(body);
+                    }
+                }
+                self.run_block(otherwise)?;
+            }
+            StatementKind::Repeat {
+                count,
+                counter,
+                body,
+            } => {
+                let count = self.eval_number(count, line)?;
+                for index in 1..=count {
+                    if let Some(counter) = counter {
+                        self.variables.insert(counter.clone(), index.to_string());
+                    }
+                    self.run_block(body)?;
+                }
+            }
+            StatementKind::While { condition, body } => {
+                while self.check(condition, line)? {
+                    stop_if_interrupted(line)?;
+                    self.run_block(body)?;
+                }
+            }
+            StatementKind::Sleep(expr) => {
+                let millis = self.eval_number(expr, line)?;
+                shutdown::sleep(Duration::from_millis(millis.max(0) as u64));
+            }
+            StatementKind::Log(expr) => {
+                let text = self.eval(expr, line)?;
+                self.print_received();
+                println!("{}", text);
+            }
+            StatementKind::Fail(expr) => {
+                let message = match expr {
+                    Some(expr) => self.eval(expr, line)?,
+                    None => String::from("failed"),
+                };
+                return Err(ScriptError::new(line, message));
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr, line: usize) -> Result<String, ScriptError> {
+        match expr {
+            Expr::Text(text) => interpolate(text, &self.variables, line),
+            Expr::Number(number) => Ok(number.to_string()),
+            Expr::Var(name) => self
+                .variables
+                .get(name)
+                .cloned()
+                .ok_or_else(|| unknown_variable(name, line)),
+            Expr::Binary(left, op, right) => {
+                let left = self.eval_number(left, line)?;
+                let right = self.eval_number(right, line)?;
+                let result = match op {
+                    ArithOp::Add => left.checked_add(right),
+                    ArithOp::Sub => left.checked_sub(right),
+                };
+                result
+                    .map(|number| number.to_string())
+                    .ok_or_else(|| ScriptError::new(line, String::from("number too big")))
+            }
+        }
+    }
+
+    fn eval_number(&self, expr: &Expr, line: usize) -> Result<i64, ScriptError> {
+        let value = self.eval(expr, line)?;
+        value
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| ScriptError::new(line, format!("'{}' is not a number", value)))
+    }
+
+    fn check(&self, condition: &Condition, line: usize) -> Result<bool, ScriptError> {
+        match condition {
+            Condition::Matches(expr, regex) => Ok(regex.is_match(&self.eval(expr, line)?)),
+            Condition::Compare(left, op, right) => Ok(compare(
+                &self.eval(left, line)?,
+                *op,
+                &self.eval(right, line)?,
+            )),
+        }
+    }
+
+    /// Send a line the same way the write REPL does. Lines received before it are printed
+    /// and can no longer be expected.
+    fn send(&mut self, text: &str, line: usize) -> Result<(), ScriptError> {
+        let write_config = &self.config.write;
+        let bytes = tx_format::encode(
+            text,
+            &write_config.tx_line_ending,
+            write_config.escape_sequences,
+        )
+        .map_err(|err| ScriptError::new(line, err.to_string()))?;
+
+        self.print_received();
+        if let Some(echo) = write_config.local_echo.echo(text, &bytes) {
+            println!("{}", echo);
+        }
+        self.serial_port
+            .write_all(&bytes)
+            .and_then(|_| self.serial_port.flush())
+            .map_err(|err| ScriptError::new(line, format!("cannot send: {}", err)))
+    }
+
+    /// Wait for a received line matching `pattern`, and store its capture groups
+    fn expect(
+        &mut self,
+        pattern: &regex::Regex,
+        timeout: Duration,
+        captures: &[String],
+        line: usize,
+    ) -> Result<(), ScriptError> {
+        let start_time = Instant::now();
+        loop {
+            let remaining = timeout
+                .checked_sub(start_time.elapsed())
+                .unwrap_or_default();
+            let received = match shutdown::recv_timeout(&self.received, remaining) {
+                Ok(received) => received.text,
+                Err(RecvTimeoutError::Timeout) if shutdown::requested() => {
+                    return Err(interrupted(line))
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(ScriptError {
+                        line,
+                        message: format!("timed out waiting for /{}/", pattern),
+                        timed_out: true,
+                    })
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(ScriptError::new(line, String::from("port closed")))
+                }
+            };
+            println!("Rx: '{}'", received.replace("\r", "\\r"));
+
+            if let Some(found) = pattern.captures(&received) {
+                for (index, name) in captures.iter().enumerate() {
+                    let value = found.get(index + 1).map_or("", |m| m.as_str());
+                    self.variables.insert(name.clone(), value.to_string());
+                }
+                for name in pattern.capture_names().flatten() {
+                    let value = found.name(name).map_or("", |m| m.as_str());
+                    self.variables.insert(name.to_string(), value.to_string());
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Print whatever was received since the last `send` or `expect`
+    fn print_received(&self) {
+        for received in self.received.try_iter() {
+            println!("Rx: '{}'", received.text.replace("\r", "\\r"));
+        }
+    }
+}
+
+/// Replace `$name` and `${name}` with variable values. `$$` is a literal `$`.
+fn interpolate(
+    text: &str,
+    variables: &HashMap<String, String>,
+    line: usize,
+) -> Result<String, ScriptError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] != '$' {
+            result.push(chars[i]);
+            i += 1;
+        } else if chars.get(i + 1) == Some(&'$') {
+            result.push('$');
+            i += 2;
+        } else {
+            let (name, next) = read_var_name(&chars, i + 1);
+            if name.is_empty() {
+                result.push('$');
+                i += 1;
+                continue;
+            }
+            let value = variables
+                .get(&name)
+                .ok_or_else(|| unknown_variable(&name, line))?;
+            result.push_str(value);
+            i = next;
+        }
+    }
+
+    Ok(result)
+}
+
+fn unknown_variable(name: &str, line: usize) -> ScriptError {
+    ScriptError::new(line, format!("unknown variable '${}'", name))
+}
+
+fn interrupted(line: usize) -> ScriptError {
+    ScriptError::new(line, String::from("interrupted"))
+}
+
+/// Scripts stop before their next statement once SIGINT or SIGTERM is received
+fn stop_if_interrupted(line: usize) -> Result<(), ScriptError> {
+    match shutdown::requested() {
+        true => Err(interrupted(line)),
+        false => Ok(()),
+    }
+}
+
+fn compare(left: &str, op: CompareOp, right: &str) -> bool {
+    let ordering = match (left.trim().parse::<i64>(), right.trim().parse::<i64>()) {
+        (Ok(left), Ok(right)) => left.cmp(&right),
+        _ => left.cmp(right),
+    };
+    match op {
+        CompareOp::Eq => ordering.is_eq(),
+        CompareOp::Ne => ordering.is_ne(),
+        CompareOp::Lt => ordering.is_lt(),
+        CompareOp::Le => ordering.is_le(),
+        CompareOp::Gt => ordering.is_gt(),
+        CompareOp::Ge => ordering.is_ge(),
+    }
+}
+
+/// Output the script is not waiting for, e.g. before it starts
+fn print_unsolicited(command: Option<&str>, line: &RxLine) {
+    let line = line.text.replace("\r", "\\r");
+    match command {
+        Some(command) => println!("Rx ({}): '{}'", command, line),
+        None => println!("Rx: '{}'", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_interpolate_and_compare_values() {
+        let mut variables = HashMap::<String, String>::new();
+        variables.insert(String::from("addr"), String::from("7"));
+
+        assert_eq!(
+            Ok(String::from("ADDR 7 costs $5")),
+            interpolate("ADDR ${addr} costs $$5", &variables, 1)
+        );
+        assert_eq!(
+            "line 4: unknown variable '$speed'",
+            interpolate("SPEED $speed", &variables, 4)
+                .unwrap_err()
+                .to_string()
+        );
+
+        assert!(compare("10", CompareOp::Gt, "9"));
+        assert!(compare("abc", CompareOp::Lt, "abd"));
+        assert!(compare("OK", CompareOp::Eq, "OK"));
+    }
+}
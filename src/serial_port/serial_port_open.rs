@@ -1,4 +1,4 @@
-use crate::parse_config::ParseConfig;
+use crate::parse_config::{ParseConfig, ParsedTomlValues};
 use serialport::SerialPort;
 use std::time::Duration;
 
@@ -14,6 +14,27 @@ pub struct SerialPortOpen {}
 impl SerialPortOpen {
     pub fn get_serial_port(config_file_name: &str) -> SerialPortResults {
         let parsed_toml_values = ParseConfig::get_config(config_file_name);
+        SerialPortOpen::open_port(&parsed_toml_values)
+    }
+
+    /// Open the serial port described by already parsed config values
+    pub fn open_port(parsed_toml_values: &ParsedTomlValues) -> SerialPortResults {
+        let port = &parsed_toml_values.serial_port;
+        let serial_port_results =
+            SerialPortOpen::try_open_port(parsed_toml_values).unwrap_or_else(|err| {
+                panic!(
+                    "\nSerial Port did not open!\nSerial Port: `{}`\n{}\n",
+                    &port, err
+                )
+            });
+        println!("Opening serial port: '{}'", &port);
+        serial_port_results
+    }
+
+    /// Same as `open_port()`, but returns the error instead of panicking
+    pub fn try_open_port(
+        parsed_toml_values: &ParsedTomlValues,
+    ) -> serialport::Result<SerialPortResults> {
         let port = &parsed_toml_values.serial_port;
         let timeout_duration = parsed_toml_values.timeout_in_milliseconds;
 
@@ -23,16 +44,24 @@ impl SerialPortOpen {
         let serial_port = serial_port.parity(parsed_toml_values.parity);
         let serial_port = serial_port.stop_bits(parsed_toml_values.stop_bits);
         let serial_port = serial_port.timeout(timeout_duration);
+        // Set while opening, as setting it afterwards would pulse it. Linux still raises it
+        // for a moment.
+        let serial_port = match parsed_toml_values.dtr {
+            Some(dtr) => serial_port.dtr_on_open(dtr),
+            None => serial_port,
+        };
 
-        let serial_port = serial_port.open().expect(&format!(
-            "\nSerial Port did not open!\nSerial Port: `{}`\n",
-            &port
-        ));
-        println!("Opening serial port: '{}'", &port);
+        let mut serial_port = serial_port.open()?;
+        // Not every port has control lines, so the port is still used if RTS cannot be set
+        if let Some(rts) = parsed_toml_values.rts {
+            if let Err(error) = serial_port.write_request_to_send(rts) {
+                eprintln!("Cannot set RTS: {}", error);
+            }
+        }
 
-        SerialPortResults {
+        Ok(SerialPortResults {
             serial_port,
             timeout_duration,
-        }
+        })
     }
 }